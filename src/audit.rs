@@ -0,0 +1,317 @@
+//! A client-side audit trail of every change made through a connection.
+//!
+//! With an [`AuditSink`] set on a connection (see
+//! [`Connection::set_audit_sink`](crate::Connection::set_audit_sink)), every mutating call
+//! (see [`crate::method::is_mutating_request`]) is recorded as an [`AuditEntry`] once its
+//! reply is in: who made it, what was sent, and what came back. Calls in dry-run mode are not
+//! recorded, since nothing is sent.
+//!
+//! [`AuditJournal`] is a sink that appends entries as JSON lines. Each line carries the
+//! SHA-256 hash of the previous line, so [`verify`](AuditJournal::verify) detects lines that
+//! were edited, removed or reordered afterwards. Lines cut off the end leave a valid chain;
+//! to catch that, keep the [`head`](AuditJournal::head) somewhere else, such as a remote
+//! log, and check against it with [`verify_head`](AuditJournal::verify_head).
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! use std::sync::Arc;
+//! use unrealircd_rpc::audit::{AuditJournal, AuditQuery};
+//!
+//! # async fn example(conn: Connection) -> unrealircd_rpc::error::Result<()> {
+//! let journal = Arc::new(AuditJournal::open("/var/log/unrealircd-rpc/audit.jsonl")?);
+//! conn.set_audit_sink(Some(journal.clone()));
+//!
+//! // Who removed that G-line?
+//! let query = AuditQuery::default().method("server_ban.del").param("name", "*@203.0.113.*");
+//! for record in journal.query(&query)? {
+//!     println!("{} {:?} {}", record.entry.timestamp, record.entry.issuer, record.entry.params);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::ban_mask::wildcard_match;
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The `prev_hash` of the first record of a journal.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// One mutating call.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// When the reply arrived, in RFC 3339 format.
+    pub timestamp: String,
+    /// The RPC user the connection logged in as.
+    pub rpc_user: String,
+    /// The issuer in effect for the call, see [`Connection::as_issuer`](crate::Connection::as_issuer).
+    pub issuer: Option<String>,
+    /// The endpoint the call was sent to.
+    pub endpoint: Option<String>,
+    pub method: String,
+    pub params: Value,
+    pub success: bool,
+    /// The server's reply, for a successful call.
+    pub reply: Option<Value>,
+    /// The error, for a failed call.
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub(crate) fn new(
+        rpc_user: &str,
+        issuer: Option<String>,
+        endpoint: Option<String>,
+        method: &str,
+        params: Value,
+        result: &Result<Value>,
+    ) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            rpc_user: rpc_user.to_string(),
+            issuer,
+            endpoint,
+            method: method.to_string(),
+            params,
+            success: result.is_ok(),
+            reply: result.as_ref().ok().cloned(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+}
+
+/// Where audit entries go.
+///
+/// A failing sink doesn't fail the call, which has already been made; sinks that must
+/// not lose entries have to deal with errors themselves.
+#[async_trait]
+pub trait AuditSink: Send + Sync {
+    async fn record(&self, entry: &AuditEntry) -> Result<()>;
+}
+
+/// A line of an [`AuditJournal`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalRecord {
+    /// Position in the journal, starting at 1.
+    pub seq: u64,
+    /// `hash` of the previous record, or [`GENESIS_HASH`].
+    pub prev_hash: String,
+    #[serde(flatten)]
+    pub entry: AuditEntry,
+    /// Hex SHA-256 of `prev_hash`, `seq` and the entry.
+    pub hash: String,
+}
+
+impl JournalRecord {
+    fn compute_hash(seq: u64, prev_hash: &str, entry: &AuditEntry) -> Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(prev_hash.as_bytes());
+        hasher.update(format!("\n{}\n", seq).as_bytes());
+        hasher.update(serde_json::to_string(entry)?.as_bytes());
+        Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+/// Criteria for [`AuditJournal::query`]. Criteria that are left out match everything.
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    /// Glob patterns for the method, such as `server_ban.*`.
+    pub methods: Vec<String>,
+    /// Glob pattern for the issuer.
+    pub issuer: Option<String>,
+    /// Glob patterns on string parameters by name. All must match.
+    pub params: BTreeMap<String, String>,
+    /// Only entries at or after this RFC 3339 time.
+    pub since: Option<String>,
+    /// Only entries before this RFC 3339 time.
+    pub until: Option<String>,
+    pub success: Option<bool>,
+}
+
+impl AuditQuery {
+    /// Add a method pattern.
+    pub fn method(mut self, pattern: &str) -> Self {
+        self.methods.push(pattern.to_string());
+        self
+    }
+
+    /// Add a parameter pattern.
+    pub fn param(mut self, name: &str, pattern: &str) -> Self {
+        self.params.insert(name.to_string(), pattern.to_string());
+        self
+    }
+
+    /// Whether an entry matches.
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        if !self.methods.is_empty() && !self.methods.iter().any(|p| wildcard_match(p, &entry.method)) {
+            return false;
+        }
+        if let Some(pattern) = &self.issuer {
+            if !entry.issuer.as_deref().is_some_and(|issuer| wildcard_match(pattern, issuer)) {
+                return false;
+            }
+        }
+        for (name, pattern) in &self.params {
+            if !entry.params.get(name).and_then(Value::as_str).is_some_and(|value| wildcard_match(pattern, value)) {
+                return false;
+            }
+        }
+        let time = |t: &str| chrono::DateTime::parse_from_rfc3339(t).ok();
+        let at = time(&entry.timestamp);
+        if self.since.as_deref().and_then(time).is_some_and(|since| at.is_none_or(|at| at < since)) {
+            return false;
+        }
+        if self.until.as_deref().and_then(time).is_some_and(|until| at.is_none_or(|at| at >= until)) {
+            return false;
+        }
+        self.success.is_none_or(|success| success == entry.success)
+    }
+}
+
+struct Tail {
+    seq: u64,
+    hash: String,
+}
+
+/// A hash-chained JSON-lines audit journal.
+pub struct AuditJournal {
+    path: PathBuf,
+    tail: Mutex<Tail>,
+    /// Held while a record is written, so records are appended in chain order.
+    writing: tokio::sync::Mutex<()>,
+    failures: AtomicU64,
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> Error {
+    Error::Other(format!("{}: {}", path.display(), e))
+}
+
+impl AuditJournal {
+    /// Open a journal, creating it if needed. New records continue the chain of the last
+    /// record in the file; the file is not verified as a whole, see [`verify`](Self::verify).
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let tail = match Self::read(&path)?.pop() {
+            Some(last) => Tail { seq: last.seq, hash: last.hash },
+            None => Tail { seq: 0, hash: GENESIS_HASH.to_string() },
+        };
+        Ok(Self { path, tail: Mutex::new(tail), writing: tokio::sync::Mutex::new(()), failures: AtomicU64::new(0) })
+    }
+
+    /// The journal file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The `seq` and `hash` of the last record written, or `0` and [`GENESIS_HASH`] for an
+    /// empty journal.
+    pub fn head(&self) -> (u64, String) {
+        let tail = self.tail.lock().unwrap();
+        (tail.seq, tail.hash.clone())
+    }
+
+    /// Entries that could not be written.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::SeqCst)
+    }
+
+    fn read(path: &Path) -> Result<Vec<JournalRecord>> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(io_error(path, e)),
+        };
+        let mut records = Vec::new();
+        for (n, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| io_error(path, e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = serde_json::from_str(&line)
+                .map_err(|e| Error::Parse(format!("{} line {}: {}", path.display(), n + 1, e)))?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// All records, oldest first.
+    pub fn records(&self) -> Result<Vec<JournalRecord>> {
+        Self::read(&self.path)
+    }
+
+    /// The records matching `query`, oldest first.
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<JournalRecord>> {
+        Ok(self.records()?.into_iter().filter(|r| query.matches(&r.entry)).collect())
+    }
+
+    /// Check the hash chain of the whole file. Returns the number of records, or an error
+    /// naming the first record that doesn't fit.
+    pub fn verify(&self) -> Result<u64> {
+        let mut prev_hash = GENESIS_HASH.to_string();
+        let mut seq = 0;
+        for record in self.records()? {
+            seq += 1;
+            if record.seq != seq || record.prev_hash != prev_hash {
+                return Err(Error::Parse(format!("audit record {}: chain broken before it", record.seq)));
+            }
+            if JournalRecord::compute_hash(record.seq, &record.prev_hash, &record.entry)? != record.hash {
+                return Err(Error::Parse(format!("audit record {}: contents were modified", record.seq)));
+            }
+            prev_hash = record.hash;
+        }
+        Ok(seq)
+    }
+
+    /// Like [`verify`](Self::verify), and check that the journal still contains the record
+    /// `seq` with `hash`, as returned by [`head`](Self::head) earlier.
+    pub fn verify_head(&self, seq: u64, hash: &str) -> Result<u64> {
+        let count = self.verify()?;
+        if seq == 0 {
+            return Ok(count);
+        }
+        match self.records()?.into_iter().find(|r| r.seq == seq) {
+            Some(record) if record.hash == hash => Ok(count),
+            Some(_) => Err(Error::Parse(format!("audit record {}: does not match the head", seq))),
+            None => Err(Error::Parse(format!("audit record {}: missing, the journal was truncated", seq))),
+        }
+    }
+
+    async fn append(&self, entry: &AuditEntry) -> Result<()> {
+        let _writing = self.writing.lock().await;
+        let (seq, prev_hash) = self.head();
+        let seq = seq + 1;
+        let hash = JournalRecord::compute_hash(seq, &prev_hash, entry)?;
+        let record = JournalRecord { seq, prev_hash, entry: entry.clone(), hash };
+        let line = serde_json::to_string(&record)?;
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut file =
+                std::fs::OpenOptions::new().create(true).append(true).open(&path).map_err(|e| io_error(&path, e))?;
+            writeln!(file, "{}", line).map_err(|e| io_error(&path, e))?;
+            file.sync_data().map_err(|e| io_error(&path, e))
+        })
+        .await
+        .map_err(|e| Error::Other(format!("{}: {}", self.path.display(), e)))??;
+        *self.tail.lock().unwrap() = Tail { seq, hash: record.hash };
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AuditSink for AuditJournal {
+    async fn record(&self, entry: &AuditEntry) -> Result<()> {
+        let result = self.append(entry).await;
+        if result.is_err() {
+            self.failures.fetch_add(1, Ordering::SeqCst);
+        }
+        result
+    }
+}
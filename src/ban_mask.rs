@@ -0,0 +1,273 @@
+//! Local parsing and matching of server ban masks.
+//!
+//! Server bans take a free-form `name`. This module validates such a mask before it is
+//! sent to the server and tests it against [`Client`] objects, so you can see which
+//! connected users a ban would hit before calling [`ServerBan::add`](crate::server_ban::ServerBan::add).
+
+use crate::client::Client;
+use crate::error::{Error, Result};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A parsed server ban mask.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BanMask {
+    /// A `user@host` mask. A mask without `@` is treated as `*@host`.
+    UserHost { user: String, host: HostMask },
+    /// An extended server ban such as `~account:name`.
+    Extended { kind: ExtendedBan, value: String },
+}
+
+/// The host part of a `user@host` mask.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostMask {
+    /// A hostname or IP address, possibly containing `*` and `?` wildcards.
+    Wildcard(String),
+    /// A CIDR range such as `192.0.2.0/24`.
+    Cidr { network: IpAddr, prefix: u8 },
+}
+
+/// The type of an extended server ban.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExtendedBan {
+    Account,
+    Realname,
+    Certfp,
+    Country,
+    SecurityGroup,
+}
+
+impl ExtendedBan {
+    /// The name used in the mask, e.g. `account` for `~account:`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExtendedBan::Account => "account",
+            ExtendedBan::Realname => "realname",
+            ExtendedBan::Certfp => "certfp",
+            ExtendedBan::Country => "country",
+            ExtendedBan::SecurityGroup => "security-group",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "account" | "a" => Some(ExtendedBan::Account),
+            "realname" | "r" => Some(ExtendedBan::Realname),
+            "certfp" | "S" => Some(ExtendedBan::Certfp),
+            "country" => Some(ExtendedBan::Country),
+            "security-group" | "G" => Some(ExtendedBan::SecurityGroup),
+            _ => None,
+        }
+    }
+}
+
+impl BanMask {
+    /// Parse and validate a ban mask.
+    pub fn parse(mask: &str) -> Result<Self> {
+        let mask = mask.trim();
+        if mask.is_empty() {
+            return Err(invalid(mask, "mask is empty"));
+        }
+        if mask.contains(char::is_whitespace) {
+            return Err(invalid(mask, "mask contains whitespace"));
+        }
+
+        if let Some(rest) = mask.strip_prefix('~') {
+            return Self::parse_extended(mask, rest);
+        }
+
+        let (user, host) = match mask.split_once('@') {
+            Some((user, host)) => (user, host),
+            None => ("*", mask),
+        };
+        if user.is_empty() {
+            return Err(invalid(mask, "user part is empty"));
+        }
+        if host.is_empty() {
+            return Err(invalid(mask, "host part is empty"));
+        }
+        if host.contains('@') || user.contains('!') {
+            return Err(invalid(mask, "expected a single user@host"));
+        }
+
+        let host = match host.split_once('/') {
+            Some((addr, prefix)) => parse_cidr(mask, addr, prefix)?,
+            None => HostMask::Wildcard(host.to_string()),
+        };
+
+        Ok(BanMask::UserHost { user: user.to_string(), host })
+    }
+
+    fn parse_extended(mask: &str, rest: &str) -> Result<Self> {
+        let (name, value) = rest
+            .split_once(':')
+            .ok_or_else(|| invalid(mask, "extended ban is missing ':'"))?;
+        let kind = ExtendedBan::from_name(name)
+            .ok_or_else(|| invalid(mask, &format!("unsupported extended server ban '~{}'", name)))?;
+        if value.is_empty() {
+            return Err(invalid(mask, "extended ban value is empty"));
+        }
+
+        match kind {
+            ExtendedBan::Certfp if value.len() != 64 || !value.chars().all(|c| c.is_ascii_hexdigit()) => {
+                return Err(invalid(mask, "certfp must be a 64 character SHA256 hex fingerprint"));
+            }
+            ExtendedBan::Country if !value.chars().all(|c| c.is_ascii_alphabetic() || c == '*' || c == '?') => {
+                return Err(invalid(mask, "country must be a country code such as 'NL'"));
+            }
+            _ => {}
+        }
+
+        Ok(BanMask::Extended { kind, value: value.to_string() })
+    }
+
+    /// Whether this mask matches the given client.
+    pub fn matches(&self, client: &Client) -> bool {
+        match self {
+            BanMask::UserHost { user, host } => {
+                let username = client.username().unwrap_or("");
+                if !wildcard_match(user, username) {
+                    return false;
+                }
+                match host {
+                    HostMask::Wildcard(pattern) => {
+                        client.hostname.as_deref().is_some_and(|h| wildcard_match(pattern, h))
+                            || client.ip.as_deref().is_some_and(|ip| wildcard_match(pattern, ip))
+                    }
+                    HostMask::Cidr { network, prefix } => client
+                        .ip
+                        .as_deref()
+                        .and_then(|ip| ip.parse::<IpAddr>().ok())
+                        .is_some_and(|ip| cidr_contains(*network, *prefix, ip)),
+                }
+            }
+            BanMask::Extended { kind, value } => match kind {
+                ExtendedBan::Account => match client.account() {
+                    // ~account:0 matches users who are not logged in
+                    None => value == "0",
+                    Some(account) => value != "0" && wildcard_match(value, account),
+                },
+                ExtendedBan::Realname => client.realname().is_some_and(|r| wildcard_match(value, r)),
+                ExtendedBan::Certfp => client.certfp().is_some_and(|fp| fp.eq_ignore_ascii_case(value)),
+                ExtendedBan::Country => client.country_code().is_some_and(|cc| wildcard_match(value, cc)),
+                ExtendedBan::SecurityGroup => client.security_groups().iter().any(|g| g == value),
+            },
+        }
+    }
+
+    /// Whether this mask matches the IP address of the given client, the way a (g)zline
+    /// does: only the host part is used, and extended bans never match.
+    pub fn matches_ip(&self, client: &Client) -> bool {
+        let (BanMask::UserHost { host, .. }, Some(ip)) = (self, client.ip.as_deref()) else {
+            return false;
+        };
+        match host {
+            HostMask::Wildcard(pattern) => wildcard_match(pattern, ip),
+            HostMask::Cidr { network, prefix } => ip.parse().is_ok_and(|ip| cidr_contains(*network, *prefix, ip)),
+        }
+    }
+
+    /// Return the clients from `clients` that this mask matches.
+    pub fn affected<'a>(&self, clients: &'a [Client]) -> Vec<&'a Client> {
+        clients.iter().filter(|c| self.matches(c)).collect()
+    }
+}
+
+impl FromStr for BanMask {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        BanMask::parse(s)
+    }
+}
+
+impl fmt::Display for BanMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanMask::UserHost { user, host } => write!(f, "{}@{}", user, host),
+            BanMask::Extended { kind, value } => write!(f, "~{}:{}", kind.name(), value),
+        }
+    }
+}
+
+impl fmt::Display for HostMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostMask::Wildcard(host) => write!(f, "{}", host),
+            HostMask::Cidr { network, prefix } => write!(f, "{}/{}", network, prefix),
+        }
+    }
+}
+
+fn invalid(mask: &str, reason: &str) -> Error {
+    Error::InvalidBanMask(format!("{}: {}", mask, reason))
+}
+
+fn parse_cidr(mask: &str, addr: &str, prefix: &str) -> Result<HostMask> {
+    let network: IpAddr = addr
+        .parse()
+        .map_err(|_| invalid(mask, &format!("'{}' is not an IP address", addr)))?;
+    let prefix: u8 = prefix
+        .parse()
+        .map_err(|_| invalid(mask, &format!("'{}' is not a valid prefix length", prefix)))?;
+    let max = if network.is_ipv4() { 32 } else { 128 };
+    if prefix > max {
+        return Err(invalid(mask, &format!("prefix length /{} is larger than /{}", prefix, max)));
+    }
+
+    // Host bits set usually means a typo, e.g. 198.51.100.7/8 where /28 was meant.
+    let masked = mask_ip(network, prefix);
+    if masked != network {
+        return Err(invalid(
+            mask,
+            &format!("address has host bits set for /{}; did you mean {}/{}?", prefix, masked, prefix),
+        ));
+    }
+
+    Ok(HostMask::Cidr { network, prefix })
+}
+
+fn mask_ip(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4);
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+            IpAddr::V4((bits & mask).into())
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6);
+            let mask = if prefix == 0 { 0 } else { u128::MAX << (128 - prefix) };
+            IpAddr::V6((bits & mask).into())
+        }
+    }
+}
+
+fn cidr_contains(network: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    network.is_ipv4() == ip.is_ipv4() && mask_ip(ip, prefix) == network
+}
+
+/// Case-insensitive IRC style wildcard match supporting `*` and `?`.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().flat_map(char::to_lowercase).collect();
+    let t: Vec<char> = text.chars().flat_map(char::to_lowercase).collect();
+
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
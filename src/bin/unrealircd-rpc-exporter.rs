@@ -0,0 +1,73 @@
+//! Prometheus exporter for UnrealIRCd.
+
+use clap::Parser;
+use std::path::PathBuf;
+use std::time::Duration;
+use unrealircd_rpc::exporter::{Exporter, ExporterConfig};
+use unrealircd_rpc::profile::Profile;
+use unrealircd_rpc::Error;
+
+#[derive(Parser)]
+#[command(name = "unrealircd-rpc-exporter", version, about = "Export UnrealIRCd statistics to Prometheus")]
+struct Cli {
+    /// Profile to use from the profile file
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Profile file (default: ~/.config/unrealircd-rpc/profiles.toml)
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Address to serve /metrics on
+    #[arg(long, default_value = "127.0.0.1:9808")]
+    listen: String,
+
+    /// Seconds between queries to the server
+    #[arg(long, default_value_t = 30)]
+    interval: u64,
+
+    /// object_detail_level for stats.get
+    #[arg(long, default_value_t = 1)]
+    stats_detail: i32,
+
+    /// object_detail_level for channel.list
+    #[arg(long, default_value_t = 1)]
+    channel_detail: i32,
+
+    /// Do not query channels
+    #[arg(long)]
+    no_channels: bool,
+
+    /// Only export user counts of channels with at least this many users
+    #[arg(long, default_value_t = 0)]
+    channel_min_users: u64,
+
+    /// Do not fetch the ban, exception, name ban and spamfilter lists
+    #[arg(long)]
+    no_tkls: bool,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Error> {
+    let profile = Profile::resolve(cli.config.as_deref(), cli.profile.as_deref())?;
+    let mut conn = profile.connection();
+    conn.connect().await?;
+
+    let config = ExporterConfig {
+        interval: Duration::from_secs(cli.interval.max(1)),
+        stats_detail: cli.stats_detail,
+        channel_detail: (!cli.no_channels).then_some(cli.channel_detail),
+        channel_min_users: cli.channel_min_users,
+        tkls: !cli.no_tkls,
+    };
+    eprintln!("serving metrics on http://{}/metrics", cli.listen);
+    Exporter::new(conn, config).run(cli.listen.as_str()).await
+}
@@ -0,0 +1,376 @@
+//! Command-line tool for the UnrealIRCd JSON-RPC interface.
+
+mod output;
+
+use clap::{Args, Parser, Subcommand};
+use output::{print_list, print_object, OutputFormat};
+use serde_json::Value;
+use std::path::PathBuf;
+use unrealircd_rpc::profile::Profile;
+use unrealircd_rpc::{Connection, Error};
+
+#[derive(Parser)]
+#[command(name = "unrealircd-rpc", version, about = "Control UnrealIRCd through its JSON-RPC interface")]
+struct Cli {
+    /// Profile to use from the profile file
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Profile file (default: ~/.config/unrealircd-rpc/profiles.toml)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Output format
+    #[arg(long, short, global = true, value_enum, default_value = "table")]
+    output: OutputFormat,
+
+    /// Show the changes that would be made instead of making them
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Users
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Channels
+    #[command(subcommand)]
+    Channel(ChannelCommand),
+    /// Server bans (K/G/Z-lines, shuns)
+    #[command(subcommand)]
+    Ban(BanCommand),
+    /// Spamfilters
+    #[command(subcommand)]
+    Spamfilter(SpamfilterCommand),
+    /// Name bans (Q-lines)
+    #[command(subcommand)]
+    NameBan(NameBanCommand),
+    /// Server ban exceptions
+    #[command(subcommand)]
+    Except(ExceptCommand),
+    /// Servers
+    #[command(subcommand)]
+    Server(ServerCommand),
+    /// Network statistics
+    Stats(DetailArgs),
+    /// Log events
+    #[command(subcommand)]
+    Log(LogCommand),
+}
+
+#[derive(Args)]
+struct DetailArgs {
+    /// Object detail level
+    #[arg(long, default_value_t = 1)]
+    detail: i32,
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// List all users
+    List(DetailArgs),
+    /// Show a user
+    Get {
+        nick: String,
+        #[command(flatten)]
+        detail: DetailArgs,
+    },
+    /// Kill a user
+    Kill { nick: String, reason: String },
+    /// Set a virtual host on a user
+    SetVhost { nick: String, vhost: String },
+}
+
+#[derive(Subcommand)]
+enum ChannelCommand {
+    /// List all channels
+    List(DetailArgs),
+    /// Show a channel
+    Get {
+        channel: String,
+        #[command(flatten)]
+        detail: DetailArgs,
+    },
+    /// Kick a user from a channel
+    Kick { channel: String, nick: String, reason: String },
+    /// Set channel modes, e.g. `mode #chan +b '*!*@host'`
+    Mode {
+        channel: String,
+        modes: String,
+        parameters: Vec<String>,
+    },
+    /// Set the channel topic
+    Topic { channel: String, topic: String },
+}
+
+#[derive(Subcommand)]
+enum BanCommand {
+    /// Add a server ban
+    Add {
+        mask: String,
+        #[arg(long = "type", default_value = "gline")]
+        ban_type: String,
+        /// Duration such as 1d or 3600; 0 is permanent
+        #[arg(long, default_value = "0")]
+        duration: String,
+        #[arg(long)]
+        reason: String,
+    },
+    /// Delete a server ban
+    Del {
+        mask: String,
+        #[arg(long = "type", default_value = "gline")]
+        ban_type: String,
+    },
+    /// List all server bans
+    List,
+    /// Show which connected users a ban would affect
+    Preview {
+        mask: String,
+        #[arg(long = "type", default_value = "gline")]
+        ban_type: String,
+    },
+}
+
+#[derive(Args)]
+struct SpamfilterKey {
+    /// The text or regex to match
+    name: String,
+    #[arg(long, default_value = "simple")]
+    match_type: String,
+    /// Target letters, e.g. cpnN
+    #[arg(long)]
+    targets: String,
+    #[arg(long)]
+    action: String,
+}
+
+#[derive(Subcommand)]
+enum SpamfilterCommand {
+    /// Add a spamfilter
+    Add {
+        #[command(flatten)]
+        key: SpamfilterKey,
+        #[arg(long, default_value = "0")]
+        duration: String,
+        #[arg(long)]
+        reason: String,
+    },
+    /// Delete a spamfilter
+    Del {
+        #[command(flatten)]
+        key: SpamfilterKey,
+    },
+    /// List all spamfilters
+    List,
+}
+
+#[derive(Subcommand)]
+enum NameBanCommand {
+    /// Add a name ban
+    Add {
+        mask: String,
+        #[arg(long)]
+        reason: String,
+        #[arg(long)]
+        duration: Option<String>,
+    },
+    /// Delete a name ban
+    Del { mask: String },
+    /// List all name bans
+    List,
+}
+
+#[derive(Subcommand)]
+enum ExceptCommand {
+    /// Add a server ban exception
+    Add {
+        mask: String,
+        /// Exception type letters, e.g. kGzZ
+        #[arg(long)]
+        types: String,
+        #[arg(long)]
+        reason: String,
+        #[arg(long)]
+        duration: Option<String>,
+    },
+    /// Delete a server ban exception
+    Del { mask: String },
+    /// List all server ban exceptions
+    List,
+}
+
+#[derive(Subcommand)]
+enum ServerCommand {
+    /// List all servers
+    List,
+    /// Show a server (default: the one we are connected to)
+    Get { server: Option<String> },
+    /// Rehash a server (default: the one we are connected to)
+    Rehash { server: Option<String> },
+}
+
+#[derive(Subcommand)]
+enum LogCommand {
+    /// Show recent log events
+    Tail {
+        /// Log sources, e.g. `all` or `!debug`
+        #[arg(long, value_delimiter = ',')]
+        sources: Option<Vec<String>>,
+        /// Number of events to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: usize,
+    },
+}
+
+const USER_COLUMNS: &[&str] = &["name", "user.username", "hostname", "ip", "user.account", "user.servername"];
+const CHANNEL_COLUMNS: &[&str] = &["name", "num_users", "modes", "topic"];
+const BAN_COLUMNS: &[&str] = &["type", "name", "set_by", "expire_at_string", "reason"];
+const SPAMFILTER_COLUMNS: &[&str] = &["match_type", "name", "spamfilter_targets", "ban_action", "hits", "reason"];
+const NAME_BAN_COLUMNS: &[&str] = &["name", "set_by", "expire_at_string", "reason"];
+const EXCEPT_COLUMNS: &[&str] = &["name", "exception_types", "set_by", "expire_at_string", "reason"];
+const SERVER_COLUMNS: &[&str] = &["name", "server.num_users", "server.features.software", "info"];
+const LOG_COLUMNS: &[&str] = &["timestamp", "level", "subsystem", "event_id", "msg"];
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Error> {
+    let profile = Profile::resolve(cli.config.as_deref(), cli.profile.as_deref())?;
+    let mut conn = profile.connection();
+    conn.connect().await?;
+    conn.set_dry_run(cli.dry_run);
+
+    let format = cli.output;
+    execute(&conn, cli.command, format).await?;
+
+    if cli.dry_run {
+        let planned = conn.context().take_planned();
+        if format == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&planned)?);
+        } else {
+            for request in planned {
+                println!("would send {} {}", request.method, request.params);
+            }
+        }
+    }
+
+    conn.close().await
+}
+
+/// Print the outcome of a mutating call. Nothing is printed in dry-run mode.
+fn print_done(result: Value, format: OutputFormat) {
+    match result {
+        Value::Object(ref obj) if obj.contains_key("dry_run") => {}
+        Value::Bool(true) if format != OutputFormat::Json => println!("OK"),
+        other => print_object(&other, format),
+    }
+}
+
+fn print_tkl(tkl: Option<Value>, format: OutputFormat) {
+    if let Some(tkl) = tkl {
+        print_object(&tkl, format);
+    }
+}
+
+async fn execute(conn: &Connection, command: Command, format: OutputFormat) -> Result<(), Error> {
+    match command {
+        Command::User(cmd) => match cmd {
+            UserCommand::List(d) => print_list(&conn.user().get_all(d.detail).await?, USER_COLUMNS, format),
+            UserCommand::Get { nick, detail } => {
+                let user = conn.user().get(&nick, detail.detail).await?;
+                print_object(&user.ok_or_else(|| Error::Other(format!("{}: no such user", nick)))?, format);
+            }
+            UserCommand::Kill { nick, reason } => print_done(conn.user().kill(&nick, &reason).await?, format),
+            UserCommand::SetVhost { nick, vhost } => print_done(conn.user().set_vhost(&nick, &vhost).await?, format),
+        },
+        Command::Channel(cmd) => match cmd {
+            ChannelCommand::List(d) => print_list(&conn.channel().get_all(d.detail).await?, CHANNEL_COLUMNS, format),
+            ChannelCommand::Get { channel, detail } => {
+                let ch = conn.channel().get(&channel, detail.detail).await?;
+                print_object(&ch.ok_or_else(|| Error::Other(format!("{}: no such channel", channel)))?, format);
+            }
+            ChannelCommand::Kick { channel, nick, reason } => {
+                print_done(conn.channel().kick(&channel, &nick, &reason).await?, format)
+            }
+            ChannelCommand::Mode { channel, modes, parameters } => {
+                print_done(conn.channel().set_mode(&channel, &modes, &parameters.join(" ")).await?, format)
+            }
+            ChannelCommand::Topic { channel, topic } => {
+                print_done(conn.channel().set_topic(&channel, &topic, None, None).await?, format)
+            }
+        },
+        Command::Ban(cmd) => match cmd {
+            BanCommand::Add { mask, ban_type, duration, reason } => {
+                print_tkl(conn.server_ban().add(&mask, &ban_type, &duration, &reason).await?, format)
+            }
+            BanCommand::Del { mask, ban_type } => print_tkl(conn.server_ban().delete(&mask, &ban_type).await?, format),
+            BanCommand::List => print_list(&conn.server_ban().get_all().await?, BAN_COLUMNS, format),
+            BanCommand::Preview { mask, ban_type } => {
+                let preview = conn.server_ban().preview(&mask, &ban_type).await?;
+                let affected = serde_json::to_value(&preview.affected)?;
+                print_list(&affected, USER_COLUMNS, format);
+                eprintln!("{} matching, {} would be banned", preview.affected.len(), preview.effective_count());
+                for warning in &preview.warnings {
+                    eprintln!("warning: {:?}", warning);
+                }
+            }
+        },
+        Command::Spamfilter(cmd) => match cmd {
+            SpamfilterCommand::Add { key, duration, reason } => print_tkl(
+                conn.spamfilter()
+                    .add(&key.name, &key.match_type, &key.targets, &key.action, &duration, &reason)
+                    .await?,
+                format,
+            ),
+            SpamfilterCommand::Del { key } => print_tkl(
+                conn.spamfilter().delete(&key.name, &key.match_type, &key.targets, &key.action).await?,
+                format,
+            ),
+            SpamfilterCommand::List => print_list(&conn.spamfilter().get_all().await?, SPAMFILTER_COLUMNS, format),
+        },
+        Command::NameBan(cmd) => match cmd {
+            NameBanCommand::Add { mask, reason, duration } => {
+                print_tkl(conn.name_ban().add(&mask, &reason, duration.as_deref(), None).await?, format)
+            }
+            NameBanCommand::Del { mask } => print_tkl(conn.name_ban().delete(&mask).await?, format),
+            NameBanCommand::List => print_list(&conn.name_ban().get_all().await?, NAME_BAN_COLUMNS, format),
+        },
+        Command::Except(cmd) => match cmd {
+            ExceptCommand::Add { mask, types, reason, duration } => print_tkl(
+                conn.server_ban_exception()
+                    .add(&mask, &types, &reason, None, duration.as_deref())
+                    .await?,
+                format,
+            ),
+            ExceptCommand::Del { mask } => print_tkl(conn.server_ban_exception().delete(&mask).await?, format),
+            ExceptCommand::List => print_list(&conn.server_ban_exception().get_all().await?, EXCEPT_COLUMNS, format),
+        },
+        Command::Server(cmd) => match cmd {
+            ServerCommand::List => print_list(&conn.server().get_all().await?, SERVER_COLUMNS, format),
+            ServerCommand::Get { server } => {
+                let srv = conn.server().get(server.as_deref()).await?;
+                print_object(&srv.ok_or_else(|| Error::Other("no such server".to_string()))?, format);
+            }
+            ServerCommand::Rehash { server } => print_done(conn.server().rehash(server.as_deref()).await?, format),
+        },
+        Command::Stats(d) => print_object(&conn.stats().get(d.detail).await?, format),
+        Command::Log(LogCommand::Tail { sources, lines }) => {
+            let events = conn.log().get_all(sources).await?.unwrap_or(Value::Array(Vec::new()));
+            let events = events.as_array().cloned().unwrap_or_default();
+            let recent = Value::Array(events[events.len().saturating_sub(lines)..].to_vec());
+            print_list(&recent, LOG_COLUMNS, format);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! Rendering of results as tables, JSON or CSV.
+
+use clap::ValueEnum;
+use serde_json::Value;
+
+/// How results are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+/// Print a list of objects, showing `columns` (dotted paths such as `user.servername`).
+pub fn print_list(items: &Value, columns: &[&str], format: OutputFormat) {
+    let empty = Vec::new();
+    let items = items.as_array().unwrap_or(&empty);
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(items).unwrap_or_default()),
+        OutputFormat::Csv => {
+            let rows: Vec<Vec<String>> = items.iter().map(|i| columns.iter().map(|c| lookup(i, c)).collect()).collect();
+            print_csv(columns, &rows);
+        }
+        OutputFormat::Table => {
+            let rows: Vec<Vec<String>> = items.iter().map(|i| columns.iter().map(|c| lookup(i, c)).collect()).collect();
+            print_table(columns, &rows);
+        }
+    }
+}
+
+/// Print a single object. Tables and CSV show it flattened into key/value pairs.
+pub fn print_object(item: &Value, format: OutputFormat) {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(item).unwrap_or_default()),
+        OutputFormat::Csv | OutputFormat::Table => {
+            let mut pairs = Vec::new();
+            flatten(item, "", &mut pairs);
+            let rows: Vec<Vec<String>> = pairs.into_iter().map(|(k, v)| vec![k, v]).collect();
+            if format == OutputFormat::Csv {
+                print_csv(&["key", "value"], &rows);
+            } else {
+                print_table(&["key", "value"], &rows);
+            }
+        }
+    }
+}
+
+/// Look up a dotted path in an object and render it as text.
+pub fn lookup(item: &Value, path: &str) -> String {
+    let mut value = item;
+    for part in path.split('.') {
+        value = &value[part];
+    }
+    scalar(value)
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items.iter().map(scalar).collect::<Vec<_>>().join(","),
+        other => other.to_string(),
+    }
+}
+
+fn flatten(value: &Value, prefix: &str, out: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(map) => {
+            for (key, v) in map {
+                let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten(v, &path, out);
+            }
+        }
+        other => out.push((prefix.to_string(), scalar(other))),
+    }
+}
+
+fn print_csv(columns: &[&str], rows: &[Vec<String>]) {
+    let mut writer = csv::Writer::from_writer(std::io::stdout());
+    let _ = writer.write_record(columns);
+    for row in rows {
+        let _ = writer.write_record(row);
+    }
+    let _ = writer.flush();
+}
+
+fn print_table(columns: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = columns.iter().map(|c| c.len()).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<String>| {
+        let padded: Vec<String> = cells.iter().zip(&widths).map(|(c, w)| format!("{:<width$}", c, width = w)).collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(columns.iter().map(|c| c.to_uppercase()).collect());
+    for row in rows {
+        line(row.clone());
+    }
+}
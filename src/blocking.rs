@@ -0,0 +1,390 @@
+//! A blocking (synchronous) client.
+//!
+//! The types in this module mirror [`Connection`](crate::Connection) and the handlers, but
+//! block the calling thread until the result is available, like `reqwest::blocking`. Every
+//! call is forwarded to the async implementation on an internal single-threaded runtime,
+//! so requests are built exactly as by the async client.
+//!
+//! This module is available with the `blocking` cargo feature. Do not use it from within
+//! an async runtime; use the async client there instead.
+//!
+//! ```rust,no_run
+//! use unrealircd_rpc::blocking::Connection;
+//!
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let mut conn = Connection::new(
+//!         "wss://127.0.0.1:8600/".to_string(),
+//!         "username:password".to_string(),
+//!         None,
+//!     );
+//!     conn.connect()?;
+//!
+//!     let stats = conn.stats().get(1)?;
+//!     println!("{} users online", stats["user"]["total"]);
+//!     Ok(())
+//! }
+//! ```
+
+use crate::client::Client;
+use crate::connection::{Options, RequestContext};
+use crate::error::Result;
+use crate::log_event::LogEvent;
+use crate::record::Recorder;
+use crate::server_ban::BanPreview;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// Blocking connection to the UnrealIRCd RPC server.
+#[derive(Clone)]
+pub struct Connection {
+    inner: crate::Connection,
+    runtime: Arc<Runtime>,
+}
+
+impl Connection {
+    /// Create a new connection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the internal runtime cannot be created.
+    pub fn new(uri: String, api_login: String, options: Option<Options>) -> Self {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to create runtime for blocking connection");
+        Self {
+            inner: crate::Connection::new(uri, api_login, options),
+            runtime: Arc::new(runtime),
+        }
+    }
+
+    /// The underlying async connection.
+    pub fn inner(&self) -> &crate::Connection {
+        &self.inner
+    }
+
+    /// Establish the WebSocket connection.
+    pub fn connect(&mut self) -> Result<()> {
+        self.runtime.block_on(self.inner.connect())
+    }
+
+    /// Send a JSON-RPC request and wait for response.
+    pub fn query(&self, method: &str, params: serde_json::Value, no_wait: bool) -> Result<serde_json::Value> {
+        self.runtime.block_on(self.inner.query(method, params, no_wait))
+    }
+
+    /// Get the last error code.
+    pub fn errno(&self) -> i64 {
+        self.runtime.block_on(self.inner.errno())
+    }
+
+    /// Get the last error message.
+    pub fn error(&self) -> Option<String> {
+        self.runtime.block_on(self.inner.error())
+    }
+
+    /// Close the connection.
+    pub fn close(&mut self) -> Result<()> {
+        self.runtime.block_on(self.inner.close())
+    }
+
+    /// The request context shared by this connection and its handlers.
+    pub fn context(&self) -> &RequestContext {
+        self.inner.context()
+    }
+
+    /// Enable or disable dry-run mode.
+    pub fn set_dry_run(&self, dry_run: bool) {
+        self.inner.set_dry_run(dry_run);
+    }
+
+    /// Record all further exchanges, or stop recording with `None`.
+    pub fn set_recorder(&self, recorder: Option<Recorder>) {
+        self.inner.set_recorder(recorder);
+    }
+
+    // Handler accessors
+    pub fn rpc(&self) -> Rpc {
+        Rpc { inner: self.inner.rpc(), runtime: self.runtime.clone() }
+    }
+
+    pub fn server(&self) -> Server {
+        Server { inner: self.inner.server(), runtime: self.runtime.clone() }
+    }
+
+    pub fn user(&self) -> User {
+        User { inner: self.inner.user(), runtime: self.runtime.clone() }
+    }
+
+    pub fn channel(&self) -> Channel {
+        Channel { inner: self.inner.channel(), runtime: self.runtime.clone() }
+    }
+
+    pub fn server_ban(&self) -> ServerBan {
+        ServerBan { inner: self.inner.server_ban(), runtime: self.runtime.clone() }
+    }
+
+    pub fn spamfilter(&self) -> Spamfilter {
+        Spamfilter { inner: self.inner.spamfilter(), runtime: self.runtime.clone() }
+    }
+
+    pub fn name_ban(&self) -> NameBan {
+        NameBan { inner: self.inner.name_ban(), runtime: self.runtime.clone() }
+    }
+
+    pub fn log(&self) -> Log {
+        Log { inner: self.inner.log(), runtime: self.runtime.clone() }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats { inner: self.inner.stats(), runtime: self.runtime.clone() }
+    }
+
+    pub fn server_ban_exception(&self) -> ServerBanException {
+        ServerBanException { inner: self.inner.server_ban_exception(), runtime: self.runtime.clone() }
+    }
+}
+
+/// Define a blocking handler that forwards each method to the async handler.
+macro_rules! blocking_handler {
+    (
+        $(#[$meta:meta])*
+        $name:ident => $inner:ty {
+            $(
+                $(#[$fn_meta:meta])*
+                fn $method:ident(&self $(, $arg:ident: $ty:ty)*) -> $ret:ty;
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone)]
+        pub struct $name {
+            inner: $inner,
+            runtime: Arc<Runtime>,
+        }
+
+        impl $name {
+            $(
+                $(#[$fn_meta])*
+                pub fn $method(&self $(, $arg: $ty)*) -> $ret {
+                    self.runtime.block_on(self.inner.$method($($arg),*))
+                }
+            )*
+        }
+    };
+}
+
+blocking_handler! {
+    /// Blocking RPC handler for meta operations.
+    Rpc => crate::rpc::Rpc {
+        /// Get information on all RPC modules loaded.
+        fn info(&self) -> Result<serde_json::Value>;
+        /// Set the name of the issuer (requires UnrealIRCd 6.0.8+).
+        fn set_issuer(&self, name: &str) -> Result<serde_json::Value>;
+        /// Add a timer (requires UnrealIRCd 6.1.0+).
+        fn add_timer(
+            &self,
+            timer_id: &str,
+            every_msec: i64,
+            method: &str,
+            params: serde_json::Value,
+            id: Option<i64>
+        ) -> Result<serde_json::Value>;
+        /// Delete a timer (requires UnrealIRCd 6.1.0+).
+        fn del_timer(&self, timer_id: &str) -> Result<serde_json::Value>;
+    }
+}
+
+blocking_handler! {
+    /// Blocking server handler.
+    Server => crate::server::Server {
+        /// Get a list of all servers.
+        fn get_all(&self) -> Result<serde_json::Value>;
+        /// Get a server object.
+        fn get(&self, server: Option<&str>) -> Result<Option<serde_json::Value>>;
+        /// Rehash a server (reload its configuration).
+        fn rehash(&self, server: Option<&str>) -> Result<serde_json::Value>;
+    }
+}
+
+blocking_handler! {
+    /// Blocking user handler.
+    User => crate::user::User {
+        /// Get a list of all users.
+        fn get_all(&self, object_detail_level: i32) -> Result<serde_json::Value>;
+        /// Get a list of all users as typed client objects.
+        fn get_all_clients(&self, object_detail_level: i32) -> Result<Vec<Client>>;
+        /// Get a user object.
+        fn get(&self, nick: &str, object_detail_level: i32) -> Result<Option<serde_json::Value>>;
+        /// Set the nickname of a user (changes the nick).
+        fn set_nick(&self, nick: &str, newnick: &str) -> Result<serde_json::Value>;
+        /// Set the username/ident of a user.
+        fn set_username(&self, nick: &str, username: &str) -> Result<serde_json::Value>;
+        /// Set the realname/gecos of a user.
+        fn set_realname(&self, nick: &str, realname: &str) -> Result<serde_json::Value>;
+        /// Set a virtual host (vhost) on the user.
+        fn set_vhost(&self, nick: &str, vhost: &str) -> Result<serde_json::Value>;
+        /// Change the user modes of a user.
+        fn set_mode(&self, nick: &str, mode: &str, hidden: bool) -> Result<serde_json::Value>;
+        /// Change the snomask of a user (oper).
+        fn set_snomask(&self, nick: &str, snomask: &str, hidden: bool) -> Result<serde_json::Value>;
+        /// Make user an IRC Operator (oper).
+        #[allow(clippy::too_many_arguments)]
+        fn set_oper(
+            &self,
+            nick: &str,
+            oper_account: &str,
+            oper_class: &str,
+            class: Option<&str>,
+            modes: Option<&str>,
+            snomask: Option<&str>,
+            vhost: Option<&str>
+        ) -> Result<serde_json::Value>;
+        /// Join a user to a channel.
+        fn join(&self, nick: &str, channel: &str, key: Option<&str>, force: bool) -> Result<serde_json::Value>;
+        /// Part a user from a channel.
+        fn part(&self, nick: &str, channel: &str, force: bool) -> Result<serde_json::Value>;
+        /// Quit a user from IRC. Pretends it is a normal QUIT.
+        fn quit(&self, nick: &str, reason: &str) -> Result<serde_json::Value>;
+        /// Kill a user from IRC. Shows that the user is forcefully removed.
+        fn kill(&self, nick: &str, reason: &str) -> Result<serde_json::Value>;
+    }
+}
+
+blocking_handler! {
+    /// Blocking channel handler.
+    Channel => crate::channel::Channel {
+        /// Get a list of channels users.
+        fn get_all(&self, object_detail_level: i32) -> Result<serde_json::Value>;
+        /// Get a channel object.
+        fn get(&self, channel: &str, object_detail_level: i32) -> Result<Option<serde_json::Value>>;
+        /// Set and unset modes on a channel.
+        fn set_mode(&self, channel: &str, modes: &str, parameters: &str) -> Result<serde_json::Value>;
+        /// Set the channel topic.
+        fn set_topic(
+            &self,
+            channel: &str,
+            topic: &str,
+            set_by: Option<&str>,
+            set_at: Option<&str>
+        ) -> Result<serde_json::Value>;
+        /// Kick a user from the channel.
+        fn kick(&self, channel: &str, nick: &str, reason: &str) -> Result<serde_json::Value>;
+    }
+}
+
+blocking_handler! {
+    /// Blocking server ban handler.
+    ServerBan => crate::server_ban::ServerBan {
+        /// Add a ban.
+        fn add(&self, name: &str, ban_type: &str, duration: &str, reason: &str) -> Result<Option<serde_json::Value>>;
+        /// Delete a ban.
+        fn delete(&self, name: &str, ban_type: &str) -> Result<Option<serde_json::Value>>;
+        /// Preview which connected users a ban would affect, without adding it.
+        fn preview(&self, mask: &str, ban_type: &str) -> Result<BanPreview>;
+        /// Get a list of all bans.
+        fn get_all(&self) -> Result<serde_json::Value>;
+        /// Get a specific ban.
+        fn get(&self, name: &str, ban_type: &str) -> Result<Option<serde_json::Value>>;
+    }
+}
+
+blocking_handler! {
+    /// Blocking spamfilter handler.
+    Spamfilter => crate::spamfilter::Spamfilter {
+        /// Add a spamfilter.
+        fn add(
+            &self,
+            name: &str,
+            match_type: &str,
+            spamfilter_targets: &str,
+            ban_action: &str,
+            ban_duration: &str,
+            reason: &str
+        ) -> Result<Option<serde_json::Value>>;
+        /// Delete a spamfilter.
+        fn delete(
+            &self,
+            name: &str,
+            match_type: &str,
+            spamfilter_targets: &str,
+            ban_action: &str
+        ) -> Result<Option<serde_json::Value>>;
+        /// Get a list of all spamfilters.
+        fn get_all(&self) -> Result<serde_json::Value>;
+        /// Get a specific spamfilter.
+        fn get(
+            &self,
+            name: &str,
+            match_type: &str,
+            spamfilter_targets: &str,
+            ban_action: &str
+        ) -> Result<Option<serde_json::Value>>;
+    }
+}
+
+blocking_handler! {
+    /// Blocking name ban (QLine) handler.
+    NameBan => crate::name_ban::NameBan {
+        /// Add a name ban (QLine).
+        fn add(
+            &self,
+            name: &str,
+            reason: &str,
+            duration: Option<&str>,
+            set_by: Option<&str>
+        ) -> Result<Option<serde_json::Value>>;
+        /// Delete a ban.
+        fn delete(&self, name: &str) -> Result<Option<serde_json::Value>>;
+        /// Get a list of all bans.
+        fn get_all(&self) -> Result<serde_json::Value>;
+        /// Get a specific ban.
+        fn get(&self, name: &str) -> Result<Option<serde_json::Value>>;
+    }
+}
+
+blocking_handler! {
+    /// Blocking log handler.
+    Log => crate::log::Log {
+        /// Subscribe to log events. Any previous subscriptions are overwritten.
+        fn subscribe(&self, sources: Vec<String>) -> Result<serde_json::Value>;
+        /// Unsubscribe from all log events.
+        fn unsubscribe(&self) -> Result<serde_json::Value>;
+        /// Get past log events.
+        fn get_all(&self, sources: Option<Vec<String>>) -> Result<Option<serde_json::Value>>;
+        /// Get past log events as typed [`LogEvent`]s.
+        fn get_all_events(&self, sources: Option<Vec<String>>) -> Result<Vec<LogEvent>>;
+        /// Wait up to `timeout` for the next event after `subscribe`.
+        fn next_event(&self, timeout: Duration) -> Result<Option<LogEvent>>;
+    }
+}
+
+blocking_handler! {
+    /// Blocking stats handler.
+    Stats => crate::stats::Stats {
+        /// Get basic statistical information: user counts, channel counts, etc.
+        fn get(&self, object_detail_level: i32) -> Result<serde_json::Value>;
+    }
+}
+
+blocking_handler! {
+    /// Blocking server ban exception handler.
+    ServerBanException => crate::server_ban_exception::ServerBanException {
+        /// Add a ban exception.
+        fn add(
+            &self,
+            name: &str,
+            exception_types: &str,
+            reason: &str,
+            set_by: Option<&str>,
+            duration: Option<&str>
+        ) -> Result<Option<serde_json::Value>>;
+        /// Delete a ban exception.
+        fn delete(&self, name: &str) -> Result<Option<serde_json::Value>>;
+        /// Get a list of all exceptions.
+        fn get_all(&self) -> Result<serde_json::Value>;
+        /// Get a specific ban exception.
+        fn get(&self, name: &str) -> Result<Option<serde_json::Value>>;
+    }
+}
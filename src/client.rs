@@ -0,0 +1,135 @@
+//! Typed client objects as returned by `user.list` and `user.get`.
+
+use serde::{Deserialize, Serialize};
+
+/// A client (user) object.
+///
+/// Which fields are filled in depends on the `object_detail_level` used for the query.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Client {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub details: Option<String>,
+    #[serde(default)]
+    pub connected_since: Option<String>,
+    #[serde(default)]
+    pub idle_since: Option<String>,
+    #[serde(default)]
+    pub user: Option<ClientUser>,
+    #[serde(default)]
+    pub tls: Option<ClientTls>,
+    #[serde(default)]
+    pub geoip: Option<ClientGeoIp>,
+}
+
+/// The `user` section of a client object.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientUser {
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub realname: Option<String>,
+    #[serde(default)]
+    pub vhost: Option<String>,
+    #[serde(default)]
+    pub cloakedhost: Option<String>,
+    #[serde(default)]
+    pub servername: Option<String>,
+    #[serde(default)]
+    pub account: Option<String>,
+    #[serde(default)]
+    pub reputation: Option<i64>,
+    #[serde(default, rename = "security-groups")]
+    pub security_groups: Vec<String>,
+    #[serde(default)]
+    pub modes: Option<String>,
+    #[serde(default)]
+    pub operlogin: Option<String>,
+    #[serde(default)]
+    pub operclass: Option<String>,
+    #[serde(default)]
+    pub channels: Vec<serde_json::Value>,
+}
+
+/// The `tls` section of a client object.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientTls {
+    #[serde(default)]
+    pub certfp: Option<String>,
+    #[serde(default)]
+    pub cipher: Option<String>,
+}
+
+/// The `geoip` section of a client object.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientGeoIp {
+    #[serde(default)]
+    pub country_code: Option<String>,
+    #[serde(default)]
+    pub asn: Option<i64>,
+    #[serde(default)]
+    pub asname: Option<String>,
+}
+
+impl Client {
+    /// The username (ident) of the client, if known.
+    pub fn username(&self) -> Option<&str> {
+        self.user.as_ref().and_then(|u| u.username.as_deref())
+    }
+
+    /// The realname (gecos) of the client, if known.
+    pub fn realname(&self) -> Option<&str> {
+        self.user.as_ref().and_then(|u| u.realname.as_deref())
+    }
+
+    /// The services account the client is logged in to, if any.
+    pub fn account(&self) -> Option<&str> {
+        self.user.as_ref().and_then(|u| u.account.as_deref())
+    }
+
+    /// The server the client is connected to, if known.
+    pub fn servername(&self) -> Option<&str> {
+        self.user.as_ref().and_then(|u| u.servername.as_deref())
+    }
+
+    /// The TLS certificate fingerprint of the client, if any.
+    pub fn certfp(&self) -> Option<&str> {
+        self.tls.as_ref().and_then(|t| t.certfp.as_deref())
+    }
+
+    /// The GeoIP country code of the client, if known.
+    pub fn country_code(&self) -> Option<&str> {
+        self.geoip.as_ref().and_then(|g| g.country_code.as_deref())
+    }
+
+    /// The security groups the client is a member of.
+    pub fn security_groups(&self) -> &[String] {
+        self.user.as_ref().map(|u| u.security_groups.as_slice()).unwrap_or(&[])
+    }
+
+    /// The user modes of the client, without the leading `+`.
+    pub fn modes(&self) -> &str {
+        self.user
+            .as_ref()
+            .and_then(|u| u.modes.as_deref())
+            .unwrap_or("")
+            .trim_start_matches('+')
+    }
+
+    /// Whether the client is an IRC Operator.
+    pub fn is_oper(&self) -> bool {
+        self.user.as_ref().is_some_and(|u| u.operlogin.is_some()) || self.modes().contains('o')
+    }
+
+    /// Whether the client is a network service (user mode `+S`).
+    pub fn is_service(&self) -> bool {
+        self.modes().contains('S')
+    }
+}
@@ -0,0 +1,574 @@
+//! Clone and botnet detection over the user list.
+//!
+//! [`CloneDetector::analyze`] groups clients (as returned by
+//! [`User::get_all_clients`](crate::user::User::get_all_clients), detail level 2 or higher)
+//! by what bots in a flood wave tend to share: IP address, /24 or /64 network, ident,
+//! realname pattern, TLS certificate, nick pattern or similarity, and connect time.
+//! Each [`Cluster`] gets a score; higher means more suspicious.
+//!
+//! [`CloneDetector::propose`] suggests a server ban or spamfilter for a cluster, together
+//! with the users outside the cluster it would also hit. Nothing is sent to the server
+//! until the proposals are passed to [`apply`] with a confirmation callback.
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! use unrealircd_rpc::clones::{apply, CloneDetector, DetectorConfig, ProposeOptions};
+//!
+//! # async fn example(conn: Connection) -> unrealircd_rpc::error::Result<()> {
+//! let clients = conn.user().get_all_clients(2).await?;
+//! let detector = CloneDetector::new(DetectorConfig::default());
+//! let clusters = detector.analyze(&clients);
+//! let proposals: Vec<_> = clusters
+//!     .iter()
+//!     .filter(|c| c.score >= 10.0)
+//!     .filter_map(|c| detector.propose(c, &clients, &ProposeOptions::default()))
+//!     .collect();
+//! for result in apply(&conn, &proposals, |p| p.collateral.is_empty()).await {
+//!     println!("{}: {}", result.action, result.result.is_ok());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::ban_mask::BanMask;
+use crate::client::Client;
+use crate::connection::Connection;
+use crate::error::Result;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// What the members of a cluster have in common.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClusterKind {
+    /// The same IP address.
+    Ip,
+    /// The same IPv4 /24 or IPv6 /64 network.
+    Subnet,
+    /// The same ident (username), ignoring a leading `~`.
+    Ident,
+    /// The same realname, with digit runs ignored.
+    Realname,
+    /// The same TLS certificate fingerprint.
+    Certfp,
+    /// The same nick with digit runs ignored, e.g. `guest#`.
+    NickPattern,
+    /// Nicks within a small edit distance of each other.
+    NickSimilar,
+    /// Nicks matching one of [`DetectorConfig::nick_patterns`].
+    NickRegex,
+    /// Connected within [`DetectorConfig::burst_window`] of each other.
+    ConnectBurst,
+}
+
+impl ClusterKind {
+    fn weight(&self) -> f64 {
+        match self {
+            ClusterKind::Certfp => 3.0,
+            ClusterKind::Ip => 2.0,
+            ClusterKind::Subnet => 1.2,
+            ClusterKind::NickRegex => 1.0,
+            ClusterKind::Ident | ClusterKind::Realname => 1.0,
+            ClusterKind::NickPattern | ClusterKind::NickSimilar => 0.8,
+            ClusterKind::ConnectBurst => 0.6,
+        }
+    }
+}
+
+impl fmt::Display for ClusterKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ClusterKind::Ip => "ip",
+            ClusterKind::Subnet => "subnet",
+            ClusterKind::Ident => "ident",
+            ClusterKind::Realname => "realname",
+            ClusterKind::Certfp => "certfp",
+            ClusterKind::NickPattern => "nick-pattern",
+            ClusterKind::NickSimilar => "nick-similar",
+            ClusterKind::NickRegex => "nick-regex",
+            ClusterKind::ConnectBurst => "connect-burst",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A group of users that share something.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cluster {
+    pub kind: ClusterKind,
+    /// The shared value, e.g. the IP address, `198.51.100.0/24` or `guest#`.
+    pub key: String,
+    /// Nicks of the members, sorted.
+    pub members: Vec<String>,
+    /// `weight × size × (1 + overlap)`, where the weight depends on the kind and the
+    /// overlap (0 to 1) measures how much the members also share their ident, realname
+    /// pattern, nick pattern and network.
+    pub score: f64,
+}
+
+/// Tuning of the detector.
+#[derive(Debug, Clone)]
+pub struct DetectorConfig {
+    /// Smallest group reported.
+    pub min_size: usize,
+    /// Connects within this window form a burst.
+    pub burst_window: Duration,
+    /// Smallest connect burst reported.
+    pub burst_min_size: usize,
+    /// Maximum edit distance for [`ClusterKind::NickSimilar`]; 0 disables it.
+    pub nick_distance: usize,
+    /// Skip nick similarity above this many clients, as it is quadratic.
+    pub max_similarity_clients: usize,
+    /// Extra nick patterns; every pattern with enough matches forms a cluster.
+    pub nick_patterns: Vec<Regex>,
+    /// Also consider IRC operators and services.
+    pub include_opers: bool,
+    /// Clients matching any of these masks are ignored, e.g. a web chat gateway.
+    pub exempt: Vec<BanMask>,
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 3,
+            burst_window: Duration::from_secs(10),
+            burst_min_size: 10,
+            nick_distance: 2,
+            max_similarity_clients: 5000,
+            nick_patterns: Vec::new(),
+            include_opers: false,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+/// Finds clusters of related users.
+#[derive(Debug, Clone, Default)]
+pub struct CloneDetector {
+    config: DetectorConfig,
+}
+
+/// Lowercase, with every run of digits replaced by `#`.
+fn skeleton(s: &str) -> String {
+    let mut out = String::new();
+    let mut in_digits = false;
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            if !in_digits {
+                out.push('#');
+            }
+            in_digits = true;
+        } else {
+            out.extend(c.to_lowercase());
+            in_digits = false;
+        }
+    }
+    out
+}
+
+fn ident(client: &Client) -> Option<String> {
+    client.username().map(|u| u.trim_start_matches('~').to_ascii_lowercase()).filter(|u| !u.is_empty())
+}
+
+/// The /24 or /64 network of the client, e.g. `198.51.100.0/24`.
+fn subnet(client: &Client) -> Option<String> {
+    match client.ip.as_deref()?.parse::<IpAddr>().ok()? {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Some(format!("{}.{}.{}.0/24", a, b, c))
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            let network = std::net::Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0);
+            Some(format!("{}/64", network))
+        }
+    }
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { prev } else { prev + 1 };
+            prev = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(prev + 1);
+        }
+    }
+    row[b.len()]
+}
+
+fn find(parent: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    let mut i = i;
+    while parent[i] != root {
+        let next = parent[i];
+        parent[i] = root;
+        i = next;
+    }
+    root
+}
+
+/// The share (0 to 1) of the largest group of equal values.
+fn share(values: impl Iterator<Item = Option<String>>, size: usize) -> f64 {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for value in values.flatten() {
+        *counts.entry(value).or_insert(0) += 1;
+    }
+    counts.values().max().copied().unwrap_or(0) as f64 / size as f64
+}
+
+impl CloneDetector {
+    /// Create a detector.
+    pub fn new(config: DetectorConfig) -> Self {
+        Self { config }
+    }
+
+    fn considered<'a>(&self, clients: &'a [Client]) -> Vec<&'a Client> {
+        clients
+            .iter()
+            .filter(|c| self.config.include_opers || !(c.is_oper() || c.is_service()))
+            .filter(|c| !self.config.exempt.iter().any(|m| m.matches(c)))
+            .collect()
+    }
+
+    /// Find all clusters, most suspicious first. A cluster with the same members as a
+    /// higher-scoring one is left out.
+    pub fn analyze(&self, clients: &[Client]) -> Vec<Cluster> {
+        let clients = self.considered(clients);
+        let mut groups: Vec<(ClusterKind, String, Vec<&Client>)> = Vec::new();
+
+        let mut group_by = |kind: ClusterKind, key: &dyn Fn(&Client) -> Option<String>| {
+            let mut map: BTreeMap<String, Vec<&Client>> = BTreeMap::new();
+            for client in &clients {
+                if let Some(k) = key(client) {
+                    map.entry(k).or_default().push(client);
+                }
+            }
+            for (k, members) in map {
+                groups.push((kind.clone(), k, members));
+            }
+        };
+        group_by(ClusterKind::Ip, &|c| c.ip.clone());
+        group_by(ClusterKind::Subnet, &subnet);
+        group_by(ClusterKind::Ident, &ident);
+        group_by(ClusterKind::Realname, &|c| c.realname().map(skeleton).filter(|r| !r.is_empty()));
+        group_by(ClusterKind::Certfp, &|c| c.certfp().map(str::to_ascii_lowercase));
+        // Without digits, the skeleton is just the nick
+        group_by(ClusterKind::NickPattern, &|c| Some(skeleton(&c.name)).filter(|s| s.contains('#')));
+
+        for pattern in &self.config.nick_patterns {
+            let members: Vec<&Client> = clients.iter().copied().filter(|c| pattern.is_match(&c.name)).collect();
+            groups.push((ClusterKind::NickRegex, pattern.as_str().to_string(), members));
+        }
+        groups.extend(self.similar_nicks(&clients));
+        groups.extend(self.bursts(&clients));
+
+        let mut clusters: Vec<Cluster> = groups
+            .into_iter()
+            .filter(|(kind, _, members)| {
+                let min = if *kind == ClusterKind::ConnectBurst { self.config.burst_min_size } else { self.config.min_size };
+                members.len() >= min.max(2)
+            })
+            .map(|(kind, key, members)| {
+                let size = members.len();
+                let overlap = [
+                    share(members.iter().map(|c| ident(c)), size),
+                    share(members.iter().map(|c| c.realname().map(skeleton)), size),
+                    share(members.iter().map(|c| Some(skeleton(&c.name))), size),
+                    share(members.iter().map(|c| subnet(c)), size),
+                ]
+                .iter()
+                .sum::<f64>()
+                    / 4.0;
+                let mut names: Vec<String> = members.iter().map(|c| c.name.clone()).collect();
+                names.sort();
+                let score = (kind.weight() * size as f64 * (1.0 + overlap) * 10.0).round() / 10.0;
+                Cluster { kind, key, members: names, score }
+            })
+            .collect();
+
+        clusters.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.key.cmp(&b.key)));
+        let mut seen: BTreeSet<Vec<String>> = BTreeSet::new();
+        clusters.retain(|c| seen.insert(c.members.clone()));
+        clusters
+    }
+
+    /// Single-linkage clusters of nicks within [`DetectorConfig::nick_distance`].
+    fn similar_nicks<'a>(&self, clients: &[&'a Client]) -> Vec<(ClusterKind, String, Vec<&'a Client>)> {
+        let max = self.config.nick_distance;
+        if max == 0 || clients.len() > self.config.max_similarity_clients {
+            return Vec::new();
+        }
+        let nicks: Vec<String> = clients.iter().map(|c| c.name.to_ascii_lowercase()).collect();
+        let mut parent: Vec<usize> = (0..clients.len()).collect();
+        for i in 0..nicks.len() {
+            // Short nicks are all within a few edits of each other
+            if nicks[i].len() < 4 + max {
+                continue;
+            }
+            for j in i + 1..nicks.len() {
+                if nicks[j].len() < 4 + max || nicks[i].len().abs_diff(nicks[j].len()) > max {
+                    continue;
+                }
+                if levenshtein(&nicks[i], &nicks[j]) <= max {
+                    let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+                    parent[a] = b;
+                }
+            }
+        }
+        let mut sets: BTreeMap<usize, Vec<&Client>> = BTreeMap::new();
+        for (i, client) in clients.iter().enumerate() {
+            let root = find(&mut parent, i);
+            sets.entry(root).or_default().push(client);
+        }
+        sets.into_values()
+            .filter(|members| members.len() > 1)
+            .map(|members| {
+                let mut names: Vec<&str> = members.iter().map(|c| c.name.as_str()).collect();
+                names.sort();
+                (ClusterKind::NickSimilar, names[0].to_string(), members)
+            })
+            .collect()
+    }
+
+    /// Groups of clients that connected within [`DetectorConfig::burst_window`].
+    fn bursts<'a>(&self, clients: &[&'a Client]) -> Vec<(ClusterKind, String, Vec<&'a Client>)> {
+        let mut timed: Vec<(chrono::DateTime<chrono::FixedOffset>, &Client)> = clients
+            .iter()
+            .filter_map(|c| {
+                let since = c.connected_since.as_deref()?;
+                Some((chrono::DateTime::parse_from_rfc3339(since).ok()?, *c))
+            })
+            .collect();
+        timed.sort_by_key(|(t, _)| *t);
+
+        let window = chrono::Duration::from_std(self.config.burst_window).unwrap_or(chrono::Duration::MAX);
+        let mut bursts = Vec::new();
+        let mut i = 0;
+        while i < timed.len() {
+            let mut j = i;
+            while j < timed.len() && timed[j].0 - timed[i].0 <= window {
+                j += 1;
+            }
+            if j - i >= self.config.burst_min_size {
+                let key = timed[i].0.to_rfc3339();
+                bursts.push((ClusterKind::ConnectBurst, key, timed[i..j].iter().map(|(_, c)| *c).collect()));
+                i = j;
+            } else {
+                i += 1;
+            }
+        }
+        bursts
+    }
+
+    /// Suggest an action against a cluster, or `None` if there is no sensible one
+    /// (e.g. for a connect burst or a cluster of similar nicks without a common prefix).
+    pub fn propose(&self, cluster: &Cluster, clients: &[Client], options: &ProposeOptions) -> Option<Proposal> {
+        let ban = |mask: String| ProposedAction::ServerBan {
+            mask,
+            ban_type: options.ban_type.clone(),
+            duration: options.duration.clone(),
+            reason: options.reason.clone(),
+        };
+        let nick_filter = |regex: String| ProposedAction::Spamfilter {
+            name: regex,
+            match_type: "regex".to_string(),
+            targets: "u".to_string(),
+            action: options.spamfilter_action.clone(),
+            duration: options.duration.clone(),
+            reason: options.reason.clone(),
+        };
+        let members: Vec<&Client> = clients.iter().filter(|c| cluster.members.contains(&c.name)).collect();
+
+        let action = match cluster.kind {
+            ClusterKind::Ip => ban(format!("*@{}", cluster.key)),
+            ClusterKind::Subnet => ban(format!("*@{}", cluster.key)),
+            ClusterKind::Certfp => ban(format!("~certfp:{}", cluster.key)),
+            ClusterKind::Realname => {
+                // Digit runs become `*`; `?` stands in for spaces, which masks can't contain
+                let pattern: String = cluster.key.chars().map(|c| if c == '#' { '*' } else if c == ' ' { '?' } else { c }).collect();
+                ban(format!("~realname:{}", pattern))
+            }
+            ClusterKind::Ident => {
+                // An ident alone is too broad; only ban it within a shared network
+                let networks: BTreeSet<Option<String>> = members.iter().map(|c| subnet(c)).collect();
+                match networks.into_iter().next() {
+                    Some(Some(network)) if members.iter().all(|c| subnet(c).as_deref() == Some(&network)) => {
+                        // The `~` of unidentified users is part of the username, but a mask
+                        // starting with `~` is an extended ban, so match it with `?`
+                        let usernames: BTreeSet<&str> = members.iter().filter_map(|c| c.username()).collect();
+                        let user = match usernames.into_iter().collect::<Vec<_>>().as_slice() {
+                            [single] => match single.strip_prefix('~') {
+                                Some(rest) => format!("?{}", rest),
+                                None => single.to_string(),
+                            },
+                            _ => format!("*{}", cluster.key),
+                        };
+                        ban(format!("{}@{}", user, network))
+                    }
+                    _ => return None,
+                }
+            }
+            ClusterKind::NickPattern => {
+                let regex = cluster.key.split('#').map(regex::escape).collect::<Vec<_>>().join("[0-9]+");
+                nick_filter(format!("^(?i){}!", regex))
+            }
+            ClusterKind::NickRegex => {
+                nick_filter(format!("^(?i)(?:{})!", cluster.key.trim_start_matches('^').trim_end_matches('$')))
+            }
+            ClusterKind::NickSimilar => {
+                let prefix = common_prefix(&cluster.members);
+                if prefix.chars().count() < 3 {
+                    return None;
+                }
+                nick_filter(format!("^(?i){}", regex::escape(&prefix)))
+            }
+            ClusterKind::ConnectBurst => return None,
+        };
+
+        let hits = action.hits(clients);
+        let covers = hits.iter().filter(|c| cluster.members.contains(&c.name)).map(|c| c.name.clone()).collect();
+        let collateral = hits.iter().filter(|c| !cluster.members.contains(&c.name)).map(|c| c.name.clone()).collect();
+        Some(Proposal { kind: cluster.kind.clone(), key: cluster.key.clone(), action, covers, collateral })
+    }
+}
+
+fn common_prefix(names: &[String]) -> String {
+    let lower: Vec<String> = names.iter().map(|n| n.to_ascii_lowercase()).collect();
+    let Some(first) = lower.first() else { return String::new() };
+    let mut prefix: String = first.clone();
+    for name in &lower[1..] {
+        while !name.starts_with(&prefix) {
+            prefix.pop();
+        }
+    }
+    prefix
+}
+
+/// Settings for proposed actions.
+#[derive(Debug, Clone)]
+pub struct ProposeOptions {
+    /// Server ban type, e.g. `gline` or `zline`.
+    pub ban_type: String,
+    /// Ban duration, e.g. `1d`.
+    pub duration: String,
+    pub reason: String,
+    /// Spamfilter action, e.g. `gline` or `block`.
+    pub spamfilter_action: String,
+}
+
+impl Default for ProposeOptions {
+    fn default() -> Self {
+        Self {
+            ban_type: "gline".to_string(),
+            duration: "1d".to_string(),
+            reason: "Clones / botnet".to_string(),
+            spamfilter_action: "gline".to_string(),
+        }
+    }
+}
+
+/// A server ban or spamfilter to add.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProposedAction {
+    ServerBan { mask: String, ban_type: String, duration: String, reason: String },
+    Spamfilter { name: String, match_type: String, targets: String, action: String, duration: String, reason: String },
+}
+
+impl ProposedAction {
+    /// Whether the action would hit a client. Spamfilters are tested against
+    /// `nick!user@host:realname`, as the `u` target does.
+    pub fn matches(&self, client: &Client) -> bool {
+        !self.hits(std::slice::from_ref(client)).is_empty()
+    }
+
+    /// The clients the action would hit, parsing the mask or regex once for all of them.
+    fn hits<'a>(&self, clients: &'a [Client]) -> Vec<&'a Client> {
+        match self {
+            ProposedAction::ServerBan { mask, .. } => match BanMask::parse(mask) {
+                Ok(mask) => clients.iter().filter(|c| mask.matches(c)).collect(),
+                Err(_) => Vec::new(),
+            },
+            ProposedAction::Spamfilter { name, .. } => match Regex::new(name) {
+                Ok(re) => clients
+                    .iter()
+                    .filter(|c| {
+                        let target = format!(
+                            "{}!{}@{}:{}",
+                            c.name,
+                            c.username().unwrap_or(""),
+                            c.hostname.as_deref().unwrap_or(""),
+                            c.realname().unwrap_or("")
+                        );
+                        re.is_match(&target)
+                    })
+                    .collect(),
+                Err(_) => Vec::new(),
+            },
+        }
+    }
+
+    /// Send the action to the server.
+    pub async fn apply(&self, connection: &Connection) -> Result<Option<serde_json::Value>> {
+        match self {
+            ProposedAction::ServerBan { mask, ban_type, duration, reason } => {
+                connection.server_ban().add(mask, ban_type, duration, reason).await
+            }
+            ProposedAction::Spamfilter { name, match_type, targets, action, duration, reason } => {
+                connection.spamfilter().add(name, match_type, targets, action, duration, reason).await
+            }
+        }
+    }
+}
+
+impl fmt::Display for ProposedAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProposedAction::ServerBan { mask, ban_type, duration, reason } => {
+                write!(f, "{} {} {} :{}", ban_type, mask, duration, reason)
+            }
+            ProposedAction::Spamfilter { name, targets, action, duration, reason, .. } => {
+                write!(f, "spamfilter {} {} {} /{}/ :{}", targets, action, duration, name, reason)
+            }
+        }
+    }
+}
+
+/// A suggested action against a cluster.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proposal {
+    pub kind: ClusterKind,
+    pub key: String,
+    pub action: ProposedAction,
+    /// Cluster members the action hits.
+    pub covers: Vec<String>,
+    /// Users outside the cluster the action would also hit.
+    pub collateral: Vec<String>,
+}
+
+/// The outcome of applying one proposal.
+#[derive(Debug)]
+pub struct ActionResult {
+    pub action: ProposedAction,
+    pub result: Result<Option<serde_json::Value>>,
+}
+
+/// Apply the proposals that `confirm` accepts, in order.
+pub async fn apply(connection: &Connection, proposals: &[Proposal], mut confirm: impl FnMut(&Proposal) -> bool) -> Vec<ActionResult> {
+    let mut results = Vec::new();
+    for proposal in proposals {
+        if confirm(proposal) {
+            let result = proposal.action.apply(connection).await;
+            results.push(ActionResult { action: proposal.action.clone(), result });
+        }
+    }
+    results
+}
@@ -0,0 +1,269 @@
+//! Talking to several servers of one network at once.
+//!
+//! Much of what the JSON-RPC interface returns is local to the server it runs on: the log,
+//! `stats.get` counts of local users, `server.rehash`. A [`Cluster`] holds one named
+//! [`Connection`] per server and either fans a call out to all of them concurrently
+//! ([`Routing::All`]) or sends it to the first one that is reachable
+//! ([`Routing::FirstHealthy`]). Results come back in a [`FanOut`], keyed by member name,
+//! with the members that failed listed separately.
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! use unrealircd_rpc::cluster::{Cluster, Routing};
+//!
+//! # async fn example(hub: Connection, leaf: Connection) -> unrealircd_rpc::error::Result<()> {
+//! let mut cluster = Cluster::new();
+//! cluster.add("hub", hub).add("leaf1", leaf);
+//!
+//! let stats = cluster.stats(1).await;
+//! for (server, stats) in &stats.results {
+//!     println!("{}: {} users", server, stats["user"]["total"]);
+//! }
+//! for (server, error) in &stats.errors {
+//!     eprintln!("{}: {}", server, error);
+//! }
+//!
+//! let users = cluster.call(Routing::FirstHealthy, |conn| async move { conn.user().get_all(1).await }).await;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+use crate::log_event::LogEvent;
+use crate::profile::Profile;
+use futures_util::future::join_all;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Which members a call goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routing {
+    /// Every member, concurrently.
+    All,
+    /// Members in order until one answers. Members that failed last time are tried last,
+    /// after reconnecting.
+    FirstHealthy,
+}
+
+/// Per-member results of a call.
+#[derive(Debug)]
+pub struct FanOut<T> {
+    /// Answers by member name.
+    pub results: BTreeMap<String, T>,
+    /// Members that failed, by name.
+    pub errors: BTreeMap<String, Error>,
+    /// Members that [`Routing::FirstHealthy`] passed over because they couldn't be reached,
+    /// before another member answered.
+    pub skipped: BTreeMap<String, Error>,
+}
+
+impl<T> Default for FanOut<T> {
+    fn default() -> Self {
+        Self { results: BTreeMap::new(), errors: BTreeMap::new(), skipped: BTreeMap::new() }
+    }
+}
+
+impl<T> FanOut<T> {
+    /// Whether every member that was asked succeeded.
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// The results, or an error naming every member that failed.
+    pub fn into_result(self) -> Result<BTreeMap<String, T>> {
+        if self.errors.is_empty() {
+            return Ok(self.results);
+        }
+        let failed: Vec<String> = self.errors.iter().map(|(name, e)| format!("{}: {}", name, e)).collect();
+        Err(Error::Other(failed.join("; ")))
+    }
+}
+
+impl FanOut<Vec<LogEvent>> {
+    /// The events of all members in one list, ordered by timestamp.
+    pub fn merged(&self) -> Vec<LogEvent> {
+        let mut events: Vec<LogEvent> = self.results.values().flatten().cloned().collect();
+        events.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        events
+    }
+}
+
+struct Member {
+    name: String,
+    connection: Connection,
+    healthy: AtomicBool,
+}
+
+/// Named connections to several servers of a network.
+#[derive(Default)]
+pub struct Cluster {
+    members: Vec<Member>,
+}
+
+/// Whether an error means the server couldn't be reached, rather than that it refused.
+fn is_unreachable(error: &Error) -> bool {
+    matches!(error, Error::ConnectionClosed | Error::WebSocket(_) | Error::Timeout)
+}
+
+impl Cluster {
+    /// Create an empty cluster.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a cluster with one (not yet connected) member per profile.
+    pub fn from_profiles(profiles: &BTreeMap<String, Profile>) -> Self {
+        let mut cluster = Self::new();
+        for (name, profile) in profiles {
+            cluster.add(name, profile.connection());
+        }
+        cluster
+    }
+
+    /// Add a member. [`Routing::FirstHealthy`] tries members in the order they were added.
+    pub fn add(&mut self, name: &str, connection: Connection) -> &mut Self {
+        self.members.push(Member {
+            name: name.to_string(),
+            connection,
+            healthy: AtomicBool::new(true),
+        });
+        self
+    }
+
+    /// The member names, in order.
+    pub fn names(&self) -> Vec<&str> {
+        self.members.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    /// The connection of a member.
+    pub fn connection(&self, name: &str) -> Option<&Connection> {
+        self.members.iter().find(|m| m.name == name).map(|m| &m.connection)
+    }
+
+    /// Connect all members concurrently.
+    pub async fn connect_all(&self) -> FanOut<()> {
+        self.fan_out(|mut conn| async move { conn.connect().await }, false).await
+    }
+
+    /// Run `call` on the members selected by `routing`.
+    pub async fn call<T, F, Fut>(&self, routing: Routing, call: F) -> FanOut<T>
+    where
+        F: Fn(Connection) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        match routing {
+            Routing::All => self.all(call).await,
+            Routing::FirstHealthy => self.first_healthy(call).await,
+        }
+    }
+
+    /// Send a raw JSON-RPC request to the members selected by `routing`.
+    pub async fn query(&self, routing: Routing, method: &str, params: Value) -> FanOut<Value> {
+        self.call(routing, |conn| {
+            let params = params.clone();
+            async move { conn.query(method, params, false).await }
+        })
+        .await
+    }
+
+    /// `stats.get` on every member.
+    pub async fn stats(&self, object_detail_level: i32) -> FanOut<Value> {
+        self.all(|conn| async move { conn.stats().get(object_detail_level).await }).await
+    }
+
+    /// `log.list` on every member; see [`FanOut::merged`] for a single timeline.
+    pub async fn log_events(&self, sources: Option<Vec<String>>) -> FanOut<Vec<LogEvent>> {
+        self.all(|conn| {
+            let sources = sources.clone();
+            async move { conn.log().get_all_events(sources).await }
+        })
+        .await
+    }
+
+    /// Rehash every member.
+    pub async fn rehash(&self) -> FanOut<Value> {
+        self.all(|conn| async move { conn.server().rehash(None).await }).await
+    }
+
+    /// Run `call` on every member, reconnecting members that failed last time first.
+    async fn all<T, F, Fut>(&self, call: F) -> FanOut<T>
+    where
+        F: Fn(Connection) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.fan_out(call, true).await
+    }
+
+    async fn fan_out<T, F, Fut>(&self, call: F, reconnect: bool) -> FanOut<T>
+    where
+        F: Fn(Connection) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let call = &call;
+        let calls = self.members.iter().map(|member| async move {
+            if reconnect && !member.healthy.load(Ordering::SeqCst) {
+                if let Err(e) = member.connection.clone().connect().await {
+                    return (member, Err(e));
+                }
+            }
+            (member, call(member.connection.clone()).await)
+        });
+        let mut out = FanOut::default();
+        for (member, result) in join_all(calls).await {
+            match result {
+                Ok(value) => {
+                    member.healthy.store(true, Ordering::SeqCst);
+                    out.results.insert(member.name.clone(), value);
+                }
+                Err(e) => {
+                    if is_unreachable(&e) {
+                        member.healthy.store(false, Ordering::SeqCst);
+                    }
+                    out.errors.insert(member.name.clone(), e);
+                }
+            }
+        }
+        out
+    }
+
+    async fn first_healthy<T, F, Fut>(&self, call: F) -> FanOut<T>
+    where
+        F: Fn(Connection) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut order: Vec<&Member> = self.members.iter().filter(|m| m.healthy.load(Ordering::SeqCst)).collect();
+        order.extend(self.members.iter().filter(|m| !m.healthy.load(Ordering::SeqCst)));
+
+        let mut out = FanOut::default();
+        for member in order {
+            if !member.healthy.load(Ordering::SeqCst) {
+                if let Err(e) = member.connection.clone().connect().await {
+                    out.skipped.insert(member.name.clone(), e);
+                    continue;
+                }
+            }
+            match call(member.connection.clone()).await {
+                Ok(value) => {
+                    member.healthy.store(true, Ordering::SeqCst);
+                    out.results.insert(member.name.clone(), value);
+                    return out;
+                }
+                Err(e) if is_unreachable(&e) => {
+                    member.healthy.store(false, Ordering::SeqCst);
+                    out.skipped.insert(member.name.clone(), e);
+                }
+                // The server answered, so it is healthy; the error is the answer
+                Err(e) => {
+                    out.errors.insert(member.name.clone(), e);
+                    return out;
+                }
+            }
+        }
+        // Nobody answered
+        out.errors.append(&mut out.skipped);
+        out
+    }
+}
@@ -1,14 +1,28 @@
 //! Connection module for UnrealIRCd RPC.
 
+use crate::audit::{AuditEntry, AuditSink};
+use crate::interceptor::{Interceptor, Request};
+use crate::retry::RetryPolicy;
+use crate::throttle::{RateLimiter, RateLimits};
+use crate::trace::event;
+use crate::undo::UndoLog;
 use crate::error::{Error, Result};
+use crate::metrics::RpcMetrics;
+use crate::record::Recorder;
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::sync::atomic::{AtomicI64, Ordering};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 use url::Url;
 
+/// How many unread notifications are kept; older ones are dropped.
+const MAX_PENDING_NOTIFICATIONS: usize = 10_000;
+
 /// Options for connecting to the RPC server.
 #[derive(Debug, Clone)]
 pub struct Options {
@@ -25,6 +39,133 @@ impl Default for Options {
     }
 }
 
+/// One of the RPC endpoints of a [`Connection`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub uri: String,
+    /// Preference: endpoints with a higher weight are tried first.
+    pub weight: u32,
+}
+
+impl Endpoint {
+    /// An endpoint with weight 1.
+    pub fn new(uri: &str) -> Self {
+        Self { uri: uri.to_string(), weight: 1 }
+    }
+
+    /// An endpoint with the given weight.
+    pub fn weighted(uri: &str, weight: u32) -> Self {
+        Self { uri: uri.to_string(), weight }
+    }
+}
+
+type EndpointHandler = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// The endpoints of a connection, in order of preference, and which one is in use.
+struct Endpoints {
+    list: Vec<Endpoint>,
+    active: std::sync::Mutex<Option<usize>>,
+    on_change: std::sync::Mutex<Option<EndpointHandler>>,
+    /// Held while switching endpoints, so concurrent failures switch only once.
+    switching: Mutex<()>,
+    /// Incremented for every new socket, so concurrent failures reconnect only once.
+    generation: AtomicU64,
+}
+
+impl Endpoints {
+    fn new(mut list: Vec<Endpoint>) -> Self {
+        // Stable, so endpoints of equal weight keep their order
+        list.sort_by_key(|e| std::cmp::Reverse(e.weight));
+        Self {
+            list,
+            active: std::sync::Mutex::new(None),
+            on_change: std::sync::Mutex::new(None),
+            switching: Mutex::new(()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    fn active(&self) -> Option<usize> {
+        *self.active.lock().unwrap()
+    }
+
+    fn set_active(&self, index: Option<usize>) {
+        let previous = std::mem::replace(&mut *self.active.lock().unwrap(), index);
+        if let (Some(index), true) = (index, previous != index) {
+            // Called without the lock, so the handler may replace itself
+            let handler = self.on_change.lock().unwrap().clone();
+            if let Some(handler) = handler {
+                handler(&self.list[index].uri);
+            }
+        }
+    }
+}
+
+/// The JSON-RPC error code UnrealIRCd uses for an object that doesn't exist.
+const RPC_NOT_FOUND: i64 = -1000;
+
+/// Whether an error means the endpoint couldn't be reached, rather than that it refused.
+fn is_unreachable(error: &Error) -> bool {
+    matches!(error, Error::ConnectionClosed | Error::WebSocket(_) | Error::Timeout)
+}
+
+type WebSocket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// The issuer of a socket: the one to use without an override, and the one in effect.
+#[derive(Debug, Default)]
+struct Issuers {
+    default: Option<String>,
+    current: Option<String>,
+}
+
+/// A request that would have been sent while in dry-run mode.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedRequest {
+    pub method: String,
+    pub params: serde_json::Value,
+}
+
+/// Request settings shared by a connection, its clones and all handlers created from it.
+#[derive(Debug, Default)]
+pub struct RequestContext {
+    dry_run: AtomicBool,
+    planned: std::sync::Mutex<Vec<PlannedRequest>>,
+}
+
+impl RequestContext {
+    /// Enable or disable dry-run mode.
+    ///
+    /// In dry-run mode, mutating requests (see [`crate::method::is_mutating_request`]) are not sent.
+    /// Instead, `query` records them and returns `{"dry_run": true, "method": ..., "params": ...}`.
+    /// Handler methods that extract a `tkl` from the reply return `None`.
+    pub fn set_dry_run(&self, dry_run: bool) {
+        self.dry_run.store(dry_run, Ordering::SeqCst);
+    }
+
+    /// Whether dry-run mode is enabled.
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run.load(Ordering::SeqCst)
+    }
+
+    /// Take the requests recorded in dry-run mode so far.
+    pub fn take_planned(&self) -> Vec<PlannedRequest> {
+        std::mem::take(&mut *self.planned.lock().unwrap())
+    }
+
+    fn plan(&self, method: &str, params: serde_json::Value) -> serde_json::Value {
+        let reply = serde_json::json!({
+            "dry_run": true,
+            "method": method,
+            "params": params.clone()
+        });
+        self.planned.lock().unwrap().push(PlannedRequest {
+            method: method.to_string(),
+            params,
+        });
+        reply
+    }
+}
+
 /// JSON-RPC request structure.
 #[derive(Debug, Serialize)]
 struct JsonRpcRequest {
@@ -36,6 +177,7 @@ struct JsonRpcRequest {
 
 /// JSON-RPC response structure.
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 struct JsonRpcResponse {
     jsonrpc: Option<String>,
     result: Option<serde_json::Value>,
@@ -53,35 +195,95 @@ struct JsonRpcError {
 /// Main connection to the UnrealIRCd RPC server.
 #[derive(Clone)]
 pub struct Connection {
-    uri: String,
+    endpoints: Arc<Endpoints>,
     auth_header: String,
-    options: Option<Options>,
-    websocket: Arc<Mutex<Option<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>>>,
+    websocket: Arc<Mutex<Option<WebSocket>>>,
     next_id: Arc<AtomicI64>,
     errno: Arc<Mutex<i64>>,
     error: Arc<Mutex<Option<String>>>,
+    context: Arc<RequestContext>,
+    recorder: Arc<std::sync::Mutex<Option<Recorder>>>,
+    notifications: Arc<std::sync::Mutex<VecDeque<serde_json::Value>>>,
+    metrics: Arc<RpcMetrics>,
+    audit: Arc<std::sync::Mutex<Option<Arc<dyn AuditSink>>>>,
+    undo: Arc<std::sync::Mutex<Option<Arc<UndoLog>>>>,
+    interceptors: Arc<std::sync::Mutex<Vec<Arc<dyn Interceptor>>>>,
+    /// Whether requests through this clone run the interceptors; off for the clone the
+    /// interceptors themselves get.
+    intercept: bool,
+    limiter: Arc<std::sync::Mutex<Option<Arc<RateLimiter>>>>,
+    retry: Arc<std::sync::Mutex<Option<RetryPolicy>>>,
+    /// Set for the connection of a [`Pool`](crate::pool::Pool): requests go to pooled connections.
+    pool: Option<Arc<crate::pool::PoolInner>>,
+    issuers: Arc<std::sync::Mutex<Issuers>>,
+    /// Issuer for requests made through this clone, see [`as_issuer`](Self::as_issuer).
+    issuer: Option<String>,
+    /// The RPC user name, restored as issuer after an override when there is no default.
+    api_user: String,
 }
 
 impl Connection {
     /// Create a new connection.
     pub fn new(uri: String, api_login: String, options: Option<Options>) -> Self {
+        Self::with_endpoints(vec![Endpoint::new(&uri)], api_login, options)
+    }
+
+    /// Create a connection that fails over between several endpoints, such as the hubs of
+    /// a network that all have RPC enabled.
+    ///
+    /// [`connect`](Self::connect) uses the first endpoint that accepts the connection, trying
+    /// higher weights first and endpoints of equal weight in the given order. When a request
+    /// fails because the endpoint is unreachable or times out, the connection moves on to the
+    /// next endpoint. Read-only requests are then retried there once; mutating requests are
+    /// not, since the first attempt may have been applied. Log subscriptions are not carried
+    /// over to the new endpoint.
+    ///
+    /// Use [`check_endpoints`](Self::check_endpoints) or
+    /// [`run_health_checks`](Self::run_health_checks) to move back to a preferred endpoint
+    /// once it is reachable again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `endpoints` is empty.
+    pub fn with_endpoints(endpoints: Vec<Endpoint>, api_login: String, options: Option<Options>) -> Self {
+        assert!(!endpoints.is_empty(), "a connection needs at least one endpoint");
         let auth_header = format!("Basic {}", base64::Engine::encode(&base64::engine::general_purpose::STANDARD, api_login.as_bytes()));
+        let api_user = api_login.split(':').next().unwrap_or_default().to_string();
+        let issuers = Issuers { default: options.and_then(|o| o.issuer), current: None };
 
         Self {
-            uri,
+            endpoints: Arc::new(Endpoints::new(endpoints)),
             auth_header,
-            options,
             websocket: Arc::new(Mutex::new(None)),
             next_id: Arc::new(AtomicI64::new(1)),
             errno: Arc::new(Mutex::new(0)),
             error: Arc::new(Mutex::new(None)),
+            context: Arc::new(RequestContext::default()),
+            recorder: Arc::new(std::sync::Mutex::new(None)),
+            notifications: Arc::new(std::sync::Mutex::new(VecDeque::new())),
+            metrics: Arc::new(RpcMetrics::default()),
+            audit: Arc::new(std::sync::Mutex::new(None)),
+            undo: Arc::new(std::sync::Mutex::new(None)),
+            interceptors: Arc::new(std::sync::Mutex::new(Vec::new())),
+            intercept: true,
+            limiter: Arc::new(std::sync::Mutex::new(None)),
+            retry: Arc::new(std::sync::Mutex::new(None)),
+            pool: None,
+            issuers: Arc::new(std::sync::Mutex::new(issuers)),
+            issuer: None,
+            api_user,
         }
     }
 
+    pub(crate) fn into_pooled(mut self, pool: Arc<crate::pool::PoolInner>) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
     /// Get the URI (for testing purposes).
     #[cfg(test)]
     pub fn uri(&self) -> &str {
-        &self.uri
+        &self.endpoints.list[self.endpoints.active().unwrap_or(0)].uri
     }
 
     /// Get the auth header (for testing purposes).
@@ -90,25 +292,242 @@ impl Connection {
         &self.auth_header
     }
 
-    /// Establish the WebSocket connection.
+    /// The request context shared by this connection and its handlers.
+    pub fn context(&self) -> &RequestContext {
+        &self.context
+    }
+
+    /// Enable or disable dry-run mode. See [`RequestContext::set_dry_run`].
+    pub fn set_dry_run(&self, dry_run: bool) {
+        self.context.set_dry_run(dry_run);
+    }
+
+    /// Record all further exchanges on this connection and its clones, or stop recording with `None`.
+    pub fn set_recorder(&self, recorder: Option<Recorder>) {
+        *self.recorder.lock().unwrap() = recorder;
+    }
+
+    fn recorder(&self) -> Option<Recorder> {
+        self.recorder.lock().unwrap().clone()
+    }
+
+    /// Record every mutating call on this connection and its clones, or stop with `None`.
+    /// See the [`audit`](crate::audit) module.
+    pub fn set_audit_sink(&self, sink: Option<Arc<dyn AuditSink>>) {
+        *self.audit.lock().unwrap() = sink;
+    }
+
+    fn audit_sink(&self) -> Option<Arc<dyn AuditSink>> {
+        self.audit.lock().unwrap().clone()
+    }
+
+    /// Record every reversible call on this connection and its clones so it can be undone,
+    /// or stop with `None`. See the [`undo`](crate::undo) module.
+    pub fn set_undo_log(&self, log: Option<Arc<UndoLog>>) {
+        *self.undo.lock().unwrap() = log;
+    }
+
+    fn undo_log(&self) -> Option<Arc<UndoLog>> {
+        self.undo.lock().unwrap().clone()
+    }
+
+    /// Run `interceptor` around every request on this connection and its clones, after the
+    /// interceptors added before it. See the [`interceptor`](crate::interceptor) module.
+    pub fn add_interceptor(&self, interceptor: Arc<dyn Interceptor>) {
+        self.interceptors.lock().unwrap().push(interceptor);
+    }
+
+    /// Remove all interceptors.
+    pub fn clear_interceptors(&self) {
+        self.interceptors.lock().unwrap().clear();
+    }
+
+    /// Limit the rate of requests on this connection and its clones, or stop limiting with
+    /// `None`. Setting limits starts with full buckets. See the [`throttle`](crate::throttle) module.
+    pub fn set_rate_limits(&self, limits: Option<RateLimits>) {
+        *self.limiter.lock().unwrap() = limits.map(|limits| Arc::new(RateLimiter::new(limits)));
+    }
+
+    /// The rate limiter in use, for its [`stats`](RateLimiter::stats).
+    pub fn rate_limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.limiter.lock().unwrap().clone()
+    }
+
+    /// Retry requests that fail for transient reasons on this connection and its clones, or
+    /// stop with `None`. See the [`retry`](crate::retry) module.
+    pub fn set_retry_policy(&self, policy: Option<RetryPolicy>) {
+        *self.retry.lock().unwrap() = policy;
+    }
+
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.retry.lock().unwrap().clone()
+    }
+
+    /// RPC latency and error metrics of this connection and its clones.
+    pub fn metrics(&self) -> &RpcMetrics {
+        &self.metrics
+    }
+
+    /// A clone of this connection whose requests are made on behalf of `issuer`, for
+    /// proxies that forward the actions of several operators over one connection:
+    ///
+    /// ```rust,no_run
+    /// # async fn example(conn: unrealircd_rpc::Connection) -> unrealircd_rpc::error::Result<()> {
+    /// conn.as_issuer("alice").server_ban().add("*@192.0.2.1", "gline", "1d", "Drones").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Before each request the connection switches the issuer with `rpc.set_issuer` if
+    /// needed, while holding the socket, so no other request can slip in between. Requests
+    /// without an override switch back to the issuer of the [`Options`], or of the last
+    /// explicit `rpc.set_issuer`; without either, to the RPC user name. An explicit
+    /// `rpc.set_issuer` on the returned connection fails, as it would change the issuer
+    /// of every other clone.
+    pub fn as_issuer(&self, issuer: &str) -> Connection {
+        let mut conn = self.clone();
+        conn.issuer = Some(issuer.to_string());
+        conn
+    }
+
+    /// The issuer set with [`as_issuer`](Self::as_issuer), if any.
+    pub fn issuer(&self) -> Option<&str> {
+        self.issuer.as_deref()
+    }
+
+    /// The issuer requests through this clone are made on behalf of: the one set with
+    /// [`as_issuer`](Self::as_issuer), or else the default one.
+    fn effective_issuer(&self) -> Option<String> {
+        self.issuer.clone().or_else(|| self.issuers.lock().unwrap().default.clone())
+    }
+
+    /// The endpoints, in the order they are tried.
+    pub fn endpoints(&self) -> &[Endpoint] {
+        &self.endpoints.list
+    }
+
+    /// The URI of the endpoint in use, or `None` before connecting.
+    pub fn active_endpoint(&self) -> Option<&str> {
+        self.endpoints.active().map(|i| self.endpoints.list[i].uri.as_str())
+    }
+
+    /// Called with the URI of the new endpoint whenever the connection switches endpoints,
+    /// including the first [`connect`](Self::connect).
+    pub fn on_endpoint_change(&self, handler: impl Fn(&str) + Send + Sync + 'static) {
+        *self.endpoints.on_change.lock().unwrap() = Some(Arc::new(handler));
+    }
+
+    /// Establish the WebSocket connection, to the first endpoint that accepts it.
+    ///
+    /// For the connection of a [`Pool`](crate::pool::Pool), this checks out a pooled
+    /// connection to make sure the server can be reached.
     pub async fn connect(&mut self) -> Result<()> {
-        let url = Url::parse(&self.uri)?;
-        let request = http::Request::builder()
-            .uri(url.as_str())
-            .header("Authorization", &self.auth_header)
-            .body(())?;
+        if let Some(pool) = &self.pool {
+            return pool.checkout().await.map(|_| ());
+        }
+        let _switching = self.endpoints.switching.lock().await;
+        self.connect_from(0).await
+    }
+
+    /// Connect to the endpoints in order starting at `start`, wrapping around.
+    async fn connect_from(&self, start: usize) -> Result<()> {
+        let count = self.endpoints.list.len();
+        let mut last_error = Error::ConnectionClosed;
+        for index in (0..count).map(|i| (start + i) % count) {
+            let uri = &self.endpoints.list[index].uri;
+            match self.open(uri).await {
+                Ok(ws) => {
+                    event!(info, endpoint = %crate::trace::redact_uri(uri), "connected");
+                    return self.activate(index, ws).await;
+                }
+                Err(e) => {
+                    event!(warn, endpoint = %crate::trace::redact_uri(uri), error = %e, "connect failed");
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn open(&self, uri: &str) -> Result<WebSocket> {
+        let url = Url::parse(uri)?;
+        let mut request = url.as_str().into_client_request()?;
+        let auth_header = http::HeaderValue::from_str(&self.auth_header).map_err(http::Error::from)?;
+        request.headers_mut().insert("Authorization", auth_header);
 
         let (ws_stream, _) = connect_async(request).await?;
-        *self.websocket.lock().await = Some(ws_stream);
+        Ok(ws_stream)
+    }
 
-        // Set issuer if provided
-        if let Some(issuer) = &self.options.as_ref().and_then(|o| o.issuer.as_ref()) {
-            self.query("rpc.set_issuer", serde_json::json!({"name": issuer}), true).await?;
+    /// Make `ws`, connected to endpoint `index`, the one in use.
+    async fn activate(&self, index: usize, ws: WebSocket) -> Result<()> {
+        if let Some(mut old) = self.websocket.lock().await.replace(ws) {
+            let _ = old.close(None).await;
+        }
+        self.endpoints.set_active(Some(index));
+        self.endpoints.generation.fetch_add(1, Ordering::SeqCst);
+
+        // Set issuer if provided; sent directly since `query` may call back into here
+        let issuer = {
+            let mut issuers = self.issuers.lock().unwrap();
+            issuers.current = None;
+            issuers.default.clone()
+        };
+        if let Some(issuer) = issuer {
+            self.send_query("rpc.set_issuer", serde_json::json!({"name": issuer}), true).await?;
         }
 
         Ok(())
     }
 
+    /// Move on from endpoint `failed` to the next one that accepts a connection. Does
+    /// nothing if another request already moved on.
+    async fn fail_over(&self, failed: usize) -> Result<()> {
+        let _switching = self.endpoints.switching.lock().await;
+        if self.endpoints.active() != Some(failed) {
+            return Ok(());
+        }
+        event!(warn, endpoint = %crate::trace::redact_uri(&self.endpoints.list[failed].uri), "endpoint unreachable, failing over");
+        self.connect_from(failed + 1).await
+    }
+
+    /// Open a new socket after a failure on the socket of `generation`, starting with the
+    /// endpoint in use. Does nothing if another request already did so.
+    async fn reconnect(&self, generation: u64) -> Result<()> {
+        let _switching = self.endpoints.switching.lock().await;
+        if self.endpoints.generation.load(Ordering::SeqCst) != generation {
+            return Ok(());
+        }
+        let active = self.endpoints.active().ok_or(Error::ConnectionClosed)?;
+        event!(info, endpoint = %crate::trace::redact_uri(&self.endpoints.list[active].uri), "reconnecting");
+        self.connect_from(active).await
+    }
+
+    /// Switch to the most preferred endpoint that is reachable, if it is preferred over the
+    /// one in use. Returns whether the connection switched.
+    pub async fn check_endpoints(&self) -> Result<bool> {
+        let _switching = self.endpoints.switching.lock().await;
+        let Some(active) = self.endpoints.active() else { return Ok(false) };
+        for index in 0..active {
+            if let Ok(ws) = self.open(&self.endpoints.list[index].uri).await {
+                event!(info, endpoint = %crate::trace::redact_uri(&self.endpoints.list[index].uri), "switching back to preferred endpoint");
+                self.activate(index, ws).await?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Call [`check_endpoints`](Self::check_endpoints) every `interval` until the task is dropped.
+    pub async fn run_health_checks(&self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let _ = self.check_endpoints().await;
+        }
+    }
+
     /// Send a JSON-RPC request and wait for response.
     pub async fn query(
         &self,
@@ -116,7 +535,218 @@ impl Connection {
         params: serde_json::Value,
         no_wait: bool,
     ) -> Result<serde_json::Value> {
+        self.query_with(method, params, no_wait, true).await
+    }
+
+    /// Like [`query`](Self::query), but not recorded in the undo log.
+    pub(crate) async fn query_unrecorded(&self, method: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+        self.query_with(method, params, false, false).await
+    }
+
+    async fn query_with(&self, method: &str, params: serde_json::Value, no_wait: bool, record_undo: bool) -> Result<serde_json::Value> {
+        let dry_run = self.context.is_dry_run() && crate::method::is_mutating_request(method, &params);
+        crate::trace::traced(method, params, dry_run, |params| self.intercepted(method, params, no_wait, record_undo)).await
+    }
+
+    /// Run the interceptors around [`process`](Self::process).
+    async fn intercepted(&self, method: &str, params: serde_json::Value, no_wait: bool, record_undo: bool) -> Result<serde_json::Value> {
+        let interceptors = if self.intercept { self.interceptors.lock().unwrap().clone() } else { Vec::new() };
+        if interceptors.is_empty() {
+            return self.process(method, params, no_wait, record_undo).await;
+        }
+        // Requests the interceptors make themselves skip the interceptors
+        let inner = Connection { intercept: false, ..self.clone() };
+        let mut request = Request::new(method, params, self.effective_issuer());
+        let mut entered = 0;
+        let mut answer = None;
+        for interceptor in &interceptors {
+            match interceptor.before(&inner, &mut request).await {
+                Ok(None) => entered += 1,
+                Ok(Some(reply)) => {
+                    entered += 1;
+                    answer = Some(Ok(reply));
+                    break;
+                }
+                Err(e) => {
+                    answer = Some(Err(e));
+                    break;
+                }
+            }
+        }
+        let mut result = match answer {
+            Some(result) => result,
+            None => self.process(&request.method, request.params.clone(), no_wait, record_undo).await,
+        };
+        for interceptor in interceptors[..entered].iter().rev() {
+            interceptor.after(&inner, &request, &mut result).await;
+        }
+        result
+    }
+
+    async fn process(&self, method: &str, params: serde_json::Value, no_wait: bool, record_undo: bool) -> Result<serde_json::Value> {
+        if self.context.is_dry_run() && crate::method::is_mutating_request(method, &params) {
+            return Ok(self.context.plan(method, params));
+        }
+        let undo = self.undo_log().filter(|_| record_undo && !no_wait && crate::undo::is_reversible(method));
+        let Some(undo) = undo else {
+            return self.audited(method, params, no_wait).await;
+        };
+        let prior = match crate::undo::prior_request(method, &params) {
+            Some((prior_method, prior_params)) => self.audited(prior_method, prior_params, false).await.ok(),
+            None => None,
+        };
+        let recorded_params = params.clone();
+        let result = self.audited(method, params, no_wait).await;
+        if let Ok(reply) = &result {
+            undo.record(method, recorded_params, prior.as_ref(), reply);
+        }
+        result
+    }
+
+    async fn audited(&self, method: &str, params: serde_json::Value, no_wait: bool) -> Result<serde_json::Value> {
+        let audit = self.audit_sink().filter(|_| crate::method::is_mutating_request(method, &params));
+        let Some(audit) = audit else {
+            return self.dispatch_retrying(method, params, no_wait).await;
+        };
+        let audited_params = params.clone();
+        let result = self.dispatch_retrying(method, params, no_wait).await;
+        let endpoint = self.active_endpoint().map(str::to_string);
+        let entry = AuditEntry::new(&self.api_user, self.effective_issuer(), endpoint, method, audited_params, &result);
+        // The call has been made either way; the sink deals with its own failures
+        let _ = audit.record(&entry).await;
+        result
+    }
+
+    /// [`dispatch`](Self::dispatch) under the retry policy, see the [`retry`](crate::retry) module.
+    async fn dispatch_retrying(&self, method: &str, params: serde_json::Value, no_wait: bool) -> Result<serde_json::Value> {
+        // Without a socket to reconnect, retrying can't help
+        let connected = self.pool.is_some() || self.endpoints.active().is_some();
+        let Some(policy) = self.retry_policy().filter(|_| !no_wait && connected) else {
+            return self.dispatch(method, params, no_wait).await;
+        };
+        let probe = if crate::retry::is_idempotent(method) || policy.retry_writes {
+            None
+        } else {
+            match crate::retry::probe(method, &params) {
+                Some(probe) => Some(probe),
+                None => return self.dispatch(method, params, no_wait).await,
+            }
+        };
+
+        let mut attempt = 1;
+        loop {
+            let generation = self.endpoints.generation.load(Ordering::SeqCst);
+            let error = match self.dispatch(method, params.clone(), false).await {
+                Err(e) if is_unreachable(&e) && attempt < policy.max_attempts => e,
+                result => return result,
+            };
+            tokio::time::sleep(policy.backoff(attempt)).await;
+            attempt += 1;
+            if self.pool.is_none() {
+                // A failed reconnect shows up as the error of the next attempt
+                let _ = self.reconnect(generation).await;
+            }
+            let Some((probe_method, probe_params)) = &probe else { continue };
+            let found = match self.dispatch(probe_method, probe_params.clone(), false).await {
+                Ok(reply) => Some(reply),
+                Err(Error::Rpc { code: RPC_NOT_FOUND, .. }) => None,
+                // Can't tell whether the write landed
+                Err(_) => return Err(error),
+            };
+            match (method.ends_with(".add"), found) {
+                (true, Some(reply)) => return Ok(reply),
+                (false, None) => return Ok(serde_json::json!({})),
+                _ => {}
+            }
+        }
+    }
+
+    async fn dispatch(&self, method: &str, params: serde_json::Value, no_wait: bool) -> Result<serde_json::Value> {
+        // Per call, so prior reads for the undo log, retries and probes are counted too
+        self.throttle(method).await;
+        if let Some(pool) = &self.pool {
+            let started = std::time::Instant::now();
+            let result = pool.query(self.issuer.as_deref(), method, params, no_wait).await;
+            self.metrics.observe(method, started.elapsed(), result.as_ref().err());
+            return result;
+        }
+        if no_wait {
+            return self.send_query(method, params, true).await;
+        }
+        let endpoint = self.endpoints.active();
+        let failover = self.endpoints.list.len() > 1 && endpoint.is_some();
+        let retry_params = (failover && !crate::method::is_mutating_request(method, &params)).then(|| params.clone());
+
+        let started = std::time::Instant::now();
+        let mut result = self.send_query(method, params, false).await;
+        if let (Err(e), Some(endpoint)) = (&result, endpoint.filter(|_| failover)) {
+            if is_unreachable(e) && self.fail_over(endpoint).await.is_ok() {
+                if let Some(params) = retry_params {
+                    self.throttle(method).await;
+                    result = self.send_query(method, params, false).await;
+                }
+            }
+        }
+        self.metrics.observe(method, started.elapsed(), result.as_ref().err());
+        result
+    }
+
+    /// Wait for the rate limiter, if any.
+    async fn throttle(&self, method: &str) {
+        if let Some(limiter) = self.rate_limiter() {
+            limiter.acquire(method).await;
+        }
+    }
+
+    /// Send a request that the connection of a [`Pool`](crate::pool::Pool) has already taken
+    /// through its pipeline, so it isn't intercepted, audited or throttled a second time.
+    pub(crate) async fn send_pooled(&self, method: &str, params: serde_json::Value, no_wait: bool) -> Result<serde_json::Value> {
+        self.send_query(method, params, no_wait).await
+    }
+
+    async fn send_query(&self, method: &str, params: serde_json::Value, no_wait: bool) -> Result<serde_json::Value> {
+        let mut ws_guard = self.websocket.lock().await;
+        let ws = ws_guard.as_mut().ok_or(Error::ConnectionClosed)?;
+
+        if method == "rpc.set_issuer" {
+            if self.issuer.is_some() {
+                return Err(Error::Other("rpc.set_issuer can't be sent on a connection made with as_issuer".to_string()));
+            }
+            let name = params.get("name").and_then(|n| n.as_str()).map(str::to_string);
+            let result = self.exchange(ws, method, params, no_wait).await;
+            if result.is_ok() {
+                let mut issuers = self.issuers.lock().unwrap();
+                issuers.default = name.clone();
+                issuers.current = name;
+            }
+            return result;
+        }
+        self.switch_issuer(ws).await?;
+        self.exchange(ws, method, params, no_wait).await
+    }
+
+    /// Put the issuer this clone wants in effect on the socket.
+    async fn switch_issuer(&self, ws: &mut WebSocket) -> Result<()> {
+        let (wanted, current) = {
+            let issuers = self.issuers.lock().unwrap();
+            (self.issuer.clone().or_else(|| issuers.default.clone()), issuers.current.clone())
+        };
+        if wanted == current {
+            return Ok(());
+        }
+        let name = wanted.clone().unwrap_or_else(|| self.api_user.clone());
+        self.exchange(ws, "rpc.set_issuer", serde_json::json!({"name": name}), false).await?;
+        self.issuers.lock().unwrap().current = wanted;
+        Ok(())
+    }
+
+    /// Send a request on a locked socket and, unless `no_wait`, wait for its reply.
+    async fn exchange(&self, ws: &mut WebSocket, method: &str, params: serde_json::Value, no_wait: bool) -> Result<serde_json::Value> {
         let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        crate::trace::record_request_id(id);
+
+        let recorder = self.recorder();
+        let recorded = recorder.as_ref().map(|recorder| recorder.request(method, &params));
 
         let request = JsonRpcRequest {
             jsonrpc: "2.0".to_string(),
@@ -128,9 +758,6 @@ impl Connection {
         let request_json = serde_json::to_string(&request)?;
         let message = Message::Text(request_json);
 
-        let mut ws_guard = self.websocket.lock().await;
-        let ws = ws_guard.as_mut().ok_or(Error::ConnectionClosed)?;
-
         ws.send(message).await?;
 
         if no_wait {
@@ -139,18 +766,36 @@ impl Connection {
 
         // Wait for response with timeout
         let timeout_duration = std::time::Duration::from_secs(10);
-        let response_msg = tokio::time::timeout(timeout_duration, ws.next())
-            .await
-            .map_err(|_| Error::Timeout)?
-            .ok_or(Error::ConnectionClosed)??;
-
-        let response_text = match response_msg {
-            Message::Text(text) => text,
-            Message::Close(_) => return Err(Error::ConnectionClosed),
-            _ => return Err(Error::InvalidResponse),
-        };
-
-        let response: JsonRpcResponse = serde_json::from_str(&response_text)?;
+        let response = tokio::time::timeout(timeout_duration, async {
+            loop {
+                let response_msg = ws.next().await.ok_or(Error::ConnectionClosed)??;
+                let response_text = match response_msg {
+                    Message::Text(text) => text,
+                    Message::Close(_) => return Err(Error::ConnectionClosed),
+                    Message::Ping(_) | Message::Pong(_) => continue,
+                    _ => return Err(Error::InvalidResponse),
+                };
+
+                // Skip replies to earlier no_wait requests; keep notifications for next_notification()
+                let raw: serde_json::Value = serde_json::from_str(&response_text)?;
+                let response: JsonRpcResponse = serde_json::from_value(raw.clone())?;
+                if let Some(recorder) = &recorder {
+                    match (response.id, recorded) {
+                        (Some(rid), Some(index)) if rid == id => recorder.reply(index, &raw),
+                        (None, _) => recorder.notification(&raw),
+                        _ => {}
+                    }
+                }
+                if response.id == Some(id) {
+                    return Ok(response);
+                }
+                if response.id.is_none() {
+                    self.push_notification(raw);
+                }
+            }
+        })
+        .await
+        .map_err(|_| Error::Timeout)??;
 
         if let Some(error) = response.error {
             *self.errno.lock().await = error.code;
@@ -170,6 +815,64 @@ impl Connection {
         }
     }
 
+    /// Wait up to `timeout` for the next notification pushed by the server, such as the
+    /// `log.event` messages sent after `log.subscribe`. Returns `None` if none arrived in time.
+    ///
+    /// Notifications that arrive while a query waits for its reply are buffered and returned
+    /// here first. The connection is locked while waiting, so keep `timeout` short if other
+    /// tasks send queries on clones of this connection.
+    pub async fn next_notification(&self, timeout: Duration) -> Result<Option<serde_json::Value>> {
+        if let Some(notification) = self.pop_notification() {
+            return Ok(Some(notification));
+        }
+
+        let recorder = self.recorder();
+        let mut ws_guard = self.websocket.lock().await;
+        // A query may have received one while we waited for the lock
+        if let Some(notification) = self.pop_notification() {
+            return Ok(Some(notification));
+        }
+        let ws = ws_guard.as_mut().ok_or(Error::ConnectionClosed)?;
+
+        let received = tokio::time::timeout(timeout, async {
+            loop {
+                let text = match ws.next().await.ok_or(Error::ConnectionClosed)?? {
+                    Message::Text(text) => text,
+                    Message::Close(_) => return Err(Error::ConnectionClosed),
+                    Message::Ping(_) | Message::Pong(_) => continue,
+                    _ => return Err(Error::InvalidResponse),
+                };
+                let raw: serde_json::Value = serde_json::from_str(&text)?;
+                // Replies to no_wait requests
+                if raw.get("id").is_some_and(|id| !id.is_null()) {
+                    continue;
+                }
+                if let Some(recorder) = &recorder {
+                    recorder.notification(&raw);
+                }
+                return Ok(raw);
+            }
+        })
+        .await;
+
+        match received {
+            Ok(notification) => notification.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn push_notification(&self, notification: serde_json::Value) {
+        let mut notifications = self.notifications.lock().unwrap();
+        if notifications.len() >= MAX_PENDING_NOTIFICATIONS {
+            notifications.pop_front();
+        }
+        notifications.push_back(notification);
+    }
+
+    fn pop_notification(&self) -> Option<serde_json::Value> {
+        self.notifications.lock().unwrap().pop_front()
+    }
+
     /// Get the last error code.
     pub async fn errno(&self) -> i64 {
         *self.errno.lock().await
@@ -182,6 +885,8 @@ impl Connection {
 
     /// Close the connection.
     pub async fn close(&mut self) -> Result<()> {
+        event!(info, endpoint = ?self.active_endpoint().map(crate::trace::redact_uri), "closing");
+        self.endpoints.set_active(None);
         if let Some(ws) = self.websocket.lock().await.as_mut() {
             ws.close(None).await?;
         }
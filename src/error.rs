@@ -4,7 +4,7 @@
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("WebSocket error: {0}")]
-    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
 
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
@@ -33,8 +33,23 @@ pub enum Error {
     #[error("Authentication failed")]
     AuthFailed,
 
+    #[error("Invalid ban mask: {0}")]
+    InvalidBanMask(String),
+
+    #[error("Permission denied: {0}")]
+    Denied(Box<crate::policy::Denial>),
+
+    #[error("Parse error: {0}")]
+    Parse(String),
+
     #[error("{0}")]
     Other(String),
 }
 
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(error: tokio_tungstenite::tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(error))
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
\ No newline at end of file
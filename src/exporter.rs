@@ -0,0 +1,251 @@
+//! Prometheus exporter.
+//!
+//! The [`Exporter`] queries the server every [`interval`](ExporterConfig::interval), not on
+//! every scrape, so Prometheus can scrape as often as it likes without adding load on the
+//! IRCd. `/metrics` serves the result of the last collection plus the RPC latency and error
+//! metrics of the connection itself.
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! use unrealircd_rpc::exporter::{Exporter, ExporterConfig};
+//!
+//! # async fn example(conn: Connection) -> unrealircd_rpc::error::Result<()> {
+//! let config = ExporterConfig { channel_min_users: 25, ..Default::default() };
+//! Exporter::new(conn, config).run("127.0.0.1:9808").await
+//! # }
+//! ```
+
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+use crate::metrics::{header, sample};
+use crate::tkl::{self, Tkl};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// How long a scraper has to send its request before the socket is closed.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the exporter collects, and how often.
+#[derive(Debug, Clone)]
+pub struct ExporterConfig {
+    /// Time between collections.
+    pub interval: Duration,
+    /// `object_detail_level` for `stats.get`.
+    pub stats_detail: i32,
+    /// `object_detail_level` for `channel.list`; `None` skips channels.
+    pub channel_detail: Option<i32>,
+    /// Only export per-channel user counts for channels with at least this many users.
+    pub channel_min_users: u64,
+    /// Fetch all TKL lists for counts by type and spamfilter hits.
+    pub tkls: bool,
+}
+
+impl Default for ExporterConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            stats_detail: 1,
+            channel_detail: Some(1),
+            channel_min_users: 0,
+            tkls: true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Collected {
+    text: String,
+    success: bool,
+    duration: f64,
+}
+
+/// Collects network metrics and serves them over HTTP.
+#[derive(Clone)]
+pub struct Exporter {
+    connection: Connection,
+    config: ExporterConfig,
+    collected: Arc<Mutex<Collected>>,
+}
+
+impl Exporter {
+    /// Create an exporter for a connected connection.
+    pub fn new(connection: Connection, config: ExporterConfig) -> Self {
+        Self { connection, config, collected: Arc::new(Mutex::new(Collected::default())) }
+    }
+
+    /// Query the server now and keep the result for [`render`](Self::render).
+    pub async fn collect(&self) -> Result<()> {
+        let started = Instant::now();
+        let result = self.collect_network().await;
+        let mut collected = self.collected.lock().unwrap();
+        collected.duration = started.elapsed().as_secs_f64();
+        collected.success = result.is_ok();
+        // Keep the last good numbers on failure; `unrealircd_up` tells they are stale
+        if let Ok(text) = &result {
+            collected.text = text.clone();
+        }
+        result.map(|_| ())
+    }
+
+    /// The full `/metrics` page.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        {
+            let collected = self.collected.lock().unwrap();
+            header(&mut out, "unrealircd_up", "gauge", "Whether the last collection succeeded.");
+            sample(&mut out, "unrealircd_up", &[], if collected.success { 1.0 } else { 0.0 });
+            header(&mut out, "unrealircd_collect_duration_seconds", "gauge", "Duration of the last collection.");
+            sample(&mut out, "unrealircd_collect_duration_seconds", &[], collected.duration);
+            out.push_str(&collected.text);
+        }
+        self.connection.metrics().encode(&mut out);
+        out
+    }
+
+    async fn collect_network(&self) -> Result<String> {
+        let mut out = String::new();
+
+        let stats = self.connection.stats().get(self.config.stats_detail).await?;
+        let gauges = [
+            ("unrealircd_users", "Connected users.", &stats["user"]["total"]),
+            ("unrealircd_users_ulined", "Connected users on U-lined servers.", &stats["user"]["ulined"]),
+            ("unrealircd_opers", "Connected IRC operators.", &stats["user"]["oper"]),
+            ("unrealircd_users_record", "Highest number of users seen.", &stats["user"]["record"]),
+            ("unrealircd_servers", "Linked servers.", &stats["server"]["total"]),
+            ("unrealircd_channels", "Channels.", &stats["channel"]["total"]),
+        ];
+        for (name, help, value) in gauges {
+            if let Some(value) = value.as_f64() {
+                header(&mut out, name, "gauge", help);
+                sample(&mut out, name, &[], value);
+            }
+        }
+
+        let servers = self.connection.server().get_all().await?;
+        header(&mut out, "unrealircd_server_users", "gauge", "Connected users per server.");
+        for server in servers.as_array().into_iter().flatten() {
+            let name = server["name"].as_str().unwrap_or_default();
+            let users = server["server"]["num_users"].as_f64().unwrap_or(0.0);
+            sample(&mut out, "unrealircd_server_users", &[("server", name)], users);
+        }
+
+        if let Some(detail) = self.config.channel_detail {
+            let channels = self.connection.channel().get_all(detail).await?;
+            header(&mut out, "unrealircd_channel_users", "gauge", "Users per channel.");
+            for channel in channels.as_array().into_iter().flatten() {
+                let users = channel["num_users"].as_u64().unwrap_or(0);
+                if users >= self.config.channel_min_users {
+                    let name = channel["name"].as_str().unwrap_or_default();
+                    sample(&mut out, "unrealircd_channel_users", &[("channel", name)], users as f64);
+                }
+            }
+        }
+
+        if self.config.tkls {
+            let tkls = tkl::fetch_all(&self.connection).await?;
+            let mut by_type: BTreeMap<&str, u64> = BTreeMap::new();
+            for t in &tkls {
+                *by_type.entry(t.tkl_type.as_str()).or_insert(0) += 1;
+            }
+            header(&mut out, "unrealircd_tkls", "gauge", "Server bans, exceptions, name bans and spamfilters by type.");
+            for (tkl_type, count) in by_type {
+                sample(&mut out, "unrealircd_tkls", &[("type", tkl_type)], count as f64);
+            }
+
+            let spamfilters: Vec<(&Tkl, [(&str, &str); 4])> = tkls
+                .iter()
+                .filter(|t| t.is_spamfilter())
+                .map(|t| {
+                    let labels = [
+                        ("name", t.name.as_str()),
+                        ("match_type", t.match_type.as_deref().unwrap_or_default()),
+                        ("targets", t.spamfilter_targets.as_deref().unwrap_or_default()),
+                        ("action", t.ban_action.as_deref().unwrap_or_default()),
+                    ];
+                    (t, labels)
+                })
+                .collect();
+            header(&mut out, "unrealircd_spamfilter_hits_total", "counter", "Spamfilter matches.");
+            for (t, labels) in &spamfilters {
+                sample(&mut out, "unrealircd_spamfilter_hits_total", labels, t.hits.unwrap_or(0) as f64);
+            }
+            header(&mut out, "unrealircd_spamfilter_hits_except_total", "counter", "Spamfilter matches that were exempted.");
+            for (t, labels) in &spamfilters {
+                sample(&mut out, "unrealircd_spamfilter_hits_except_total", labels, t.hits_except.unwrap_or(0) as f64);
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Collect every [`interval`](ExporterConfig::interval) until the task is dropped.
+    /// A lost or unresponsive connection is re-established before the next collection.
+    pub async fn run_collector(&self) {
+        let mut ticker = tokio::time::interval(self.config.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Err(Error::ConnectionClosed | Error::WebSocket(_) | Error::Timeout) = self.collect().await {
+                let _ = self.connection.clone().connect().await;
+            }
+        }
+    }
+
+    /// Serve `/metrics` on an already bound listener.
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await.map_err(|e| Error::Other(format!("accept: {}", e)))?;
+            let exporter = self.clone();
+            tokio::spawn(async move {
+                let _ = exporter.respond(stream).await;
+            });
+        }
+    }
+
+    /// Collect in the background and serve `/metrics` on `addr`.
+    pub async fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr).await.map_err(|e| Error::Other(format!("bind: {}", e)))?;
+        let collector = self.clone();
+        let task = tokio::spawn(async move { collector.run_collector().await });
+        let result = self.serve(listener).await;
+        task.abort();
+        result
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        let read = async {
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        tokio::time::timeout(READ_TIMEOUT, read).await.map_err(|_| std::io::ErrorKind::TimedOut)??;
+        let request = String::from_utf8_lossy(&request);
+        let mut parts = request.split_whitespace();
+        let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+
+        let (status, content_type, body) = match (method, path.split('?').next().unwrap_or_default()) {
+            ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4", self.render()),
+            ("GET", "/") => ("200 OK", "text/plain", "UnrealIRCd exporter, see /metrics\n".to_string()),
+            _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
@@ -0,0 +1,82 @@
+//! Hooks around every request.
+//!
+//! An [`Interceptor`] added to a connection (see
+//! [`Connection::add_interceptor`](crate::Connection::add_interceptor)) sees every request
+//! made through it and its clones, from any handler, before anything else happens to it:
+//! before dry-run mode, rate limiting, undo recording, auditing and retries. Its
+//! [`before`](Interceptor::before) hook can change the request, answer it without sending
+//! it, or refuse it with an error; its [`after`](Interceptor::after) hook sees the outcome
+//! and can replace it.
+//!
+//! With several interceptors, the `before` hooks run in the order the interceptors were
+//! added and the `after` hooks in reverse order. When a `before` hook answers or refuses,
+//! the remaining `before` hooks and the request itself are skipped, and only the interceptors
+//! whose `before` hook ran without error get their `after` hook called.
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! use async_trait::async_trait;
+//! use serde_json::Value;
+//! use std::sync::Arc;
+//! use unrealircd_rpc::error::{Error, Result};
+//! use unrealircd_rpc::interceptor::{Interceptor, Request};
+//!
+//! /// Refuses everything outside office hours.
+//! struct OfficeHours;
+//!
+//! #[async_trait]
+//! impl Interceptor for OfficeHours {
+//!     async fn before(&self, _connection: &Connection, request: &mut Request) -> Result<Option<Value>> {
+//!         if unrealircd_rpc::method::is_mutating(&request.method) && !in_office_hours() {
+//!             return Err(Error::Other(format!("{} refused outside office hours", request.method)));
+//!         }
+//!         Ok(None)
+//!     }
+//! }
+//! # fn in_office_hours() -> bool { true }
+//!
+//! # fn example(conn: Connection) {
+//! conn.add_interceptor(Arc::new(OfficeHours));
+//! # }
+//! ```
+
+use crate::connection::Connection;
+use crate::error::Result;
+use async_trait::async_trait;
+use serde_json::Value;
+
+/// A request on its way through the interceptors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub params: Value,
+    issuer: Option<String>,
+}
+
+impl Request {
+    pub(crate) fn new(method: &str, params: Value, issuer: Option<String>) -> Self {
+        Self { method: method.to_string(), params, issuer }
+    }
+
+    /// The issuer the request is made on behalf of, see
+    /// [`Connection::as_issuer`](crate::Connection::as_issuer).
+    pub fn issuer(&self) -> Option<&str> {
+        self.issuer.as_deref()
+    }
+}
+
+/// Hooks called around every request. Both do nothing by default.
+///
+/// The connection passed to the hooks is the one the request is made on; requests the hooks
+/// make through it, such as a `user.get` to check something, skip the interceptors.
+#[async_trait]
+pub trait Interceptor: Send + Sync {
+    /// Called before the request is made. Return `Ok(Some(reply))` to answer it without
+    /// sending it, or an error to refuse it.
+    async fn before(&self, _connection: &Connection, _request: &mut Request) -> Result<Option<Value>> {
+        Ok(None)
+    }
+
+    /// Called with the outcome of the request.
+    async fn after(&self, _connection: &Connection, _request: &Request, _result: &mut Result<Value>) {}
+}
@@ -2,7 +2,9 @@
 
 use crate::connection::Connection;
 use crate::error::Result;
+use crate::log_event::LogEvent;
 use serde_json;
+use std::time::Duration;
 
 /// Log handler for log operations.
 #[derive(Clone)]
@@ -43,4 +45,30 @@ impl Log {
             Ok(None)
         }
     }
-}
\ No newline at end of file
+
+    /// Get past log events as typed [`LogEvent`]s.
+    pub async fn get_all_events(&self, sources: Option<Vec<String>>) -> Result<Vec<LogEvent>> {
+        match self.get_all(sources).await? {
+            Some(list) => Ok(serde_json::from_value(list)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Wait up to `timeout` for the next event after [`subscribe`](Self::subscribe).
+    ///
+    /// Returns `None` if no event arrived in time. Other notifications are skipped.
+    pub async fn next_event(&self, timeout: Duration) -> Result<Option<LogEvent>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            let Some(notification) = self.connection.next_notification(remaining).await? else {
+                return Ok(None);
+            };
+            if notification.get("method").and_then(|m| m.as_str()) == Some("log.event") {
+                if let Some(event) = notification.get("result") {
+                    return Ok(Some(serde_json::from_value(event.clone())?));
+                }
+            }
+        }
+    }
+}
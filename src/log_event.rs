@@ -0,0 +1,38 @@
+//! Typed log events as returned by `log.list` and pushed after `log.subscribe`.
+
+use serde::{Deserialize, Serialize};
+
+/// The log levels used by UnrealIRCd, from least to most severe.
+pub const LEVELS: &[&str] = &["debug", "info", "warn", "error", "fatal"];
+
+/// A log event.
+///
+/// Fields other than the common ones (such as `client` or `channel`, depending on the
+/// event) are kept in `extra`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LogEvent {
+    #[serde(default)]
+    pub timestamp: String,
+    #[serde(default)]
+    pub level: String,
+    #[serde(default)]
+    pub subsystem: String,
+    #[serde(default)]
+    pub event_id: String,
+    #[serde(default)]
+    pub msg: String,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl LogEvent {
+    /// The position of the level in [`LEVELS`], or `None` for an unknown level.
+    pub fn severity(&self) -> Option<usize> {
+        severity(&self.level)
+    }
+}
+
+/// The position of a level name in [`LEVELS`], or `None` for an unknown level.
+pub fn severity(level: &str) -> Option<usize> {
+    LEVELS.iter().position(|l| l.eq_ignore_ascii_case(level))
+}
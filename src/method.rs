@@ -0,0 +1,58 @@
+//! Classification of JSON-RPC methods.
+
+use serde_json::Value;
+
+/// The kind of effect a JSON-RPC method has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MethodClass {
+    /// Only reads state, e.g. `user.list` or `stats.get`.
+    Read,
+    /// Changes state on the network, e.g. `server_ban.add` or `user.set_vhost`.
+    Write,
+    /// Only affects the RPC session itself, e.g. `rpc.set_issuer` or `log.subscribe`.
+    Session,
+}
+
+impl MethodClass {
+    /// Classify a method name.
+    ///
+    /// `rpc.add_timer` is a `Write`, as the request it runs is unknown here; see
+    /// [`of_request`](Self::of_request).
+    pub fn of(method: &str) -> Self {
+        match method {
+            "rpc.info" | "stats.get" => MethodClass::Read,
+            "rpc.add_timer" => MethodClass::Write,
+            "rpc.set_issuer" | "rpc.del_timer" | "log.subscribe" | "log.unsubscribe" => MethodClass::Session,
+            _ if method.ends_with(".list") || method.ends_with(".get") => MethodClass::Read,
+            _ => MethodClass::Write,
+        }
+    }
+
+    /// Classify a request. An `rpc.add_timer` is a `Write` if the request it runs is one,
+    /// and a `Session` otherwise.
+    pub fn of_request(method: &str, params: &Value) -> Self {
+        if method != "rpc.add_timer" {
+            return Self::of(method);
+        }
+        match timer_request(params) {
+            Some((method, params)) if Self::of_request(method, params) != MethodClass::Write => MethodClass::Session,
+            _ => MethodClass::Write,
+        }
+    }
+}
+
+/// The method and params of the request an `rpc.add_timer` runs.
+pub fn timer_request(params: &Value) -> Option<(&str, &Value)> {
+    let request = params.get("request")?;
+    Some((request.get("method")?.as_str()?, request.get("params").unwrap_or(&Value::Null)))
+}
+
+/// Whether a method changes state on the network.
+pub fn is_mutating(method: &str) -> bool {
+    MethodClass::of(method) == MethodClass::Write
+}
+
+/// Whether a request changes state on the network, looking into timers.
+pub fn is_mutating_request(method: &str, params: &Value) -> bool {
+    MethodClass::of_request(method, params) == MethodClass::Write
+}
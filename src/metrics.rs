@@ -0,0 +1,108 @@
+//! RPC latency and error metrics collected by [`Connection`](crate::Connection).
+//!
+//! Every query that waits for a reply is timed and counted per method. The numbers are
+//! available through [`Connection::metrics`](crate::Connection::metrics), as a snapshot or
+//! in Prometheus text format.
+
+use crate::error::Error;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the latency histogram buckets.
+pub const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Latency and errors of one RPC method.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MethodMetrics {
+    /// Number of completed requests, successful or not.
+    pub requests: u64,
+    /// Cumulative count per bucket in [`LATENCY_BUCKETS`].
+    pub buckets: Vec<u64>,
+    /// Total latency in seconds.
+    pub latency_sum: f64,
+    /// Failed requests by reason: the JSON-RPC error code, `timeout` or `connection`.
+    pub errors: BTreeMap<String, u64>,
+}
+
+/// RPC metrics of a connection and its clones.
+#[derive(Debug, Default)]
+pub struct RpcMetrics {
+    methods: Mutex<BTreeMap<String, MethodMetrics>>,
+}
+
+impl RpcMetrics {
+    pub(crate) fn observe(&self, method: &str, elapsed: Duration, error: Option<&Error>) {
+        let seconds = elapsed.as_secs_f64();
+        let mut methods = self.methods.lock().unwrap();
+        let entry = methods.entry(method.to_string()).or_insert_with(|| MethodMetrics {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            ..Default::default()
+        });
+        entry.requests += 1;
+        entry.latency_sum += seconds;
+        for (count, bound) in entry.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        if let Some(error) = error {
+            let reason = match error {
+                Error::Rpc { code, .. } => code.to_string(),
+                Error::Timeout => "timeout".to_string(),
+                _ => "connection".to_string(),
+            };
+            *entry.errors.entry(reason).or_insert(0) += 1;
+        }
+    }
+
+    /// The metrics so far, by method.
+    pub fn snapshot(&self) -> BTreeMap<String, MethodMetrics> {
+        self.methods.lock().unwrap().clone()
+    }
+
+    /// Append the metrics in Prometheus text format.
+    pub fn encode(&self, out: &mut String) {
+        let methods = self.snapshot();
+        let histogram = "unrealircd_rpc_request_duration_seconds";
+        header(out, histogram, "histogram", "Time from sending a JSON-RPC request to its reply.");
+        for (method, m) in &methods {
+            for (count, bound) in m.buckets.iter().zip(LATENCY_BUCKETS) {
+                sample(out, &format!("{}_bucket", histogram), &[("method", method), ("le", &bound.to_string())], *count as f64);
+            }
+            sample(out, &format!("{}_bucket", histogram), &[("method", method), ("le", "+Inf")], m.requests as f64);
+            sample(out, &format!("{}_sum", histogram), &[("method", method)], m.latency_sum);
+            sample(out, &format!("{}_count", histogram), &[("method", method)], m.requests as f64);
+        }
+        header(out, "unrealircd_rpc_errors_total", "counter", "Failed JSON-RPC requests by reason.");
+        for (method, m) in &methods {
+            for (reason, count) in &m.errors {
+                sample(out, "unrealircd_rpc_errors_total", &[("method", method), ("reason", reason)], *count as f64);
+            }
+        }
+    }
+}
+
+/// Append the `# HELP` and `# TYPE` lines of a metric.
+pub(crate) fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Append one sample line.
+pub(crate) fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, (key, value)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let escaped = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            let _ = write!(out, "{}=\"{}\"", key, escaped);
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", value);
+}
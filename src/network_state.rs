@@ -0,0 +1,477 @@
+//! A local mirror of the network, kept current by log events.
+//!
+//! [`NetworkState`] holds users, channels and servers. It is seeded from `user.list`,
+//! `channel.list` and `server.list`, and [`NetworkState::apply`] updates it from these log
+//! events:
+//!
+//! | Subsystem | Event ids                                 | Effect                        |
+//! |-----------|-------------------------------------------|-------------------------------|
+//! | `connect` | `LOCAL_CLIENT_CONNECT`, `REMOTE_CLIENT_CONNECT` | add the user            |
+//! | `connect` | `LOCAL_CLIENT_DISCONNECT`, `REMOTE_CLIENT_DISCONNECT` | remove the user   |
+//! | `nick`    | `NICK_COMMAND`, `REMOTE_NICK_CHANGE`      | rename the user (`new_nick`)  |
+//! | `join`    | `LOCAL_CLIENT_JOIN`, `REMOTE_CLIENT_JOIN` | add a channel member          |
+//! | `part`    | `LOCAL_CLIENT_PART`, `REMOTE_CLIENT_PART` | remove a channel member       |
+//! | `kick`    | `LOCAL_CLIENT_KICK`, `REMOTE_CLIENT_KICK` | remove the `victim`           |
+//! | `mode`    | any                                       | update channel or user modes  |
+//!
+//! Every handled event that carries a `client` object also refreshes the cached copy of that
+//! client. [`NetworkCache`] drives all this: it subscribes, seeds, applies events and does a
+//! periodic full resync, reporting any [`Drift`] between the mirror and the server.
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! use unrealircd_rpc::network_state::{CacheConfig, NetworkCache};
+//!
+//! # async fn example(conn: Connection) -> unrealircd_rpc::error::Result<()> {
+//! let cache = NetworkCache::start(conn, CacheConfig::default()).await?;
+//! let runner = cache.clone();
+//! tokio::spawn(async move { runner.run().await });
+//!
+//! let state = cache.read();
+//! for client in state.users_by_ip("198.51.100.23") {
+//!     println!("{} is in {} channels", client.name, state.channels_of(&client.name).len());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::client::Client;
+use crate::connection::Connection;
+use crate::error::Result;
+use crate::log_event::LogEvent;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+/// The `log.subscribe` sources needed to keep a [`NetworkState`] current.
+pub const SOURCES: &[&str] = &["connect", "nick", "join", "part", "kick", "mode"];
+
+/// A channel in the mirror.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CachedChannel {
+    pub name: String,
+    pub modes: Option<String>,
+    pub topic: Option<String>,
+    pub creation_time: Option<String>,
+    /// Nicks of the members.
+    pub members: BTreeSet<String>,
+}
+
+impl CachedChannel {
+    fn new(name: &str) -> Self {
+        Self { name: name.to_string(), ..Default::default() }
+    }
+
+    fn update_from(&mut self, channel: &serde_json::Value) {
+        if let Some(modes) = channel["modes"].as_str() {
+            self.modes = Some(modes.to_string());
+        }
+        if let Some(topic) = channel["topic"].as_str() {
+            self.topic = Some(topic.to_string());
+        }
+        if let Some(created) = channel["creation_time"].as_str() {
+            self.creation_time = Some(created.to_string());
+        }
+    }
+
+    fn remove_member(&mut self, nick: &str) -> bool {
+        let before = self.members.len();
+        self.members.retain(|m| !m.eq_ignore_ascii_case(nick));
+        self.members.len() != before
+    }
+}
+
+/// Differences found between the mirror and the server at a resync.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Drift {
+    /// Users on the server that were not in the mirror.
+    pub missing_users: Vec<String>,
+    /// Users in the mirror that are no longer on the server.
+    pub stale_users: Vec<String>,
+    /// Channels on the server that were not in the mirror.
+    pub missing_channels: Vec<String>,
+    /// Channels in the mirror that no longer exist.
+    pub stale_channels: Vec<String>,
+    /// Channels whose member list differed.
+    pub membership_changed: Vec<String>,
+}
+
+impl Drift {
+    /// Whether the mirror was accurate.
+    pub fn is_empty(&self) -> bool {
+        self.missing_users.is_empty()
+            && self.stale_users.is_empty()
+            && self.missing_channels.is_empty()
+            && self.stale_channels.is_empty()
+            && self.membership_changed.is_empty()
+    }
+}
+
+fn key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// A channel reference in a client or event: a name, or an object with a `name`.
+fn channel_name(value: &serde_json::Value) -> Option<&str> {
+    value.as_str().or_else(|| value["name"].as_str())
+}
+
+/// Users, channels and servers of the network.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkState {
+    users: HashMap<String, Client>,
+    channels: HashMap<String, CachedChannel>,
+    servers: BTreeMap<String, serde_json::Value>,
+    events_applied: u64,
+}
+
+impl NetworkState {
+    /// Build the state from the results of `user.list`, `channel.list` and `server.list`.
+    ///
+    /// Membership is taken from the channel `members` lists where present (detail level 4),
+    /// and otherwise from the users' `channels` lists (user detail level 2 or higher).
+    pub fn from_lists(users: Vec<Client>, channels: &[serde_json::Value], servers: &[serde_json::Value]) -> Self {
+        let mut state = Self::default();
+        for channel in channels {
+            let Some(name) = channel["name"].as_str() else { continue };
+            let mut cached = CachedChannel::new(name);
+            cached.update_from(channel);
+            for member in channel["members"].as_array().into_iter().flatten() {
+                if let Some(nick) = channel_name(member) {
+                    cached.members.insert(nick.to_string());
+                }
+            }
+            state.channels.insert(key(name), cached);
+        }
+        for mut client in users {
+            if let Some(user) = &mut client.user {
+                for channel in std::mem::take(&mut user.channels) {
+                    if let Some(name) = channel_name(&channel) {
+                        let cached = state.channels.entry(key(name)).or_insert_with(|| CachedChannel::new(name));
+                        cached.members.insert(client.name.clone());
+                    }
+                }
+            }
+            state.users.insert(key(&client.name), client);
+        }
+        for server in servers {
+            if let Some(name) = server["name"].as_str() {
+                state.servers.insert(name.to_string(), server.clone());
+            }
+        }
+        state
+    }
+
+    /// Fetch the full state from the server.
+    pub async fn fetch(connection: &Connection, user_detail: i32, channel_detail: i32) -> Result<Self> {
+        let users = connection.user().get_all_clients(user_detail).await?;
+        let channels = connection.channel().get_all(channel_detail).await?;
+        let servers = connection.server().get_all().await?;
+        let empty = Vec::new();
+        Ok(Self::from_lists(
+            users,
+            channels.as_array().unwrap_or(&empty),
+            servers.as_array().unwrap_or(&empty),
+        ))
+    }
+
+    /// Apply a log event. Returns whether the state changed.
+    pub fn apply(&mut self, event: &LogEvent) -> bool {
+        let client = event.extra.get("client").filter(|c| c.is_object());
+        let channel = event.extra.get("channel");
+        let id = event.event_id.as_str();
+        let applied = match event.subsystem.as_str() {
+            "connect" if id.ends_with("CLIENT_CONNECT") => match client {
+                Some(client) => self.refresh_client(client),
+                None => false,
+            },
+            "connect" if id.ends_with("CLIENT_DISCONNECT") => match client.and_then(|c| c["name"].as_str()) {
+                Some(nick) => self.remove_user(nick),
+                None => false,
+            },
+            "nick" => {
+                let old = client.and_then(|c| c["name"].as_str());
+                let new = event.extra.get("new_nick").and_then(|n| n.as_str());
+                match (old, new) {
+                    (Some(old), Some(new)) => self.rename_user(old, new),
+                    _ => false,
+                }
+            }
+            "join" | "part" | "kick" => {
+                let nick = match event.subsystem.as_str() {
+                    "kick" => event.extra.get("victim").and_then(|v| v["name"].as_str()),
+                    _ => client.and_then(|c| c["name"].as_str()),
+                };
+                let (Some(nick), Some(channel)) = (nick, channel) else { return false };
+                let Some(name) = channel_name(channel) else { return false };
+                if event.subsystem == "join" {
+                    if let Some(client) = client {
+                        self.refresh_client(client);
+                    }
+                    let cached = self.channels.entry(key(name)).or_insert_with(|| CachedChannel::new(name));
+                    cached.update_from(channel);
+                    cached.members.insert(nick.to_string());
+                    true
+                } else {
+                    self.remove_member(name, nick)
+                }
+            }
+            "mode" => {
+                let mut changed = false;
+                if let Some(channel) = channel {
+                    if let Some(cached) = channel_name(channel).and_then(|n| self.channels.get_mut(&key(n))) {
+                        cached.update_from(channel);
+                        changed = true;
+                    }
+                }
+                if let Some(client) = client {
+                    changed |= self.refresh_client(client);
+                }
+                changed
+            }
+            _ => false,
+        };
+        if applied {
+            self.events_applied += 1;
+        }
+        applied
+    }
+
+    /// Replace the cached copy of a client, keeping its channel memberships.
+    fn refresh_client(&mut self, client: &serde_json::Value) -> bool {
+        let Ok(mut client) = serde_json::from_value::<Client>(client.clone()) else { return false };
+        if client.name.is_empty() {
+            return false;
+        }
+        if let Some(user) = &mut client.user {
+            user.channels.clear();
+        }
+        self.users.insert(key(&client.name), client);
+        true
+    }
+
+    fn remove_user(&mut self, nick: &str) -> bool {
+        let removed = self.users.remove(&key(nick)).is_some();
+        let names: Vec<String> = self.channels.values().map(|c| c.name.clone()).collect();
+        for name in names {
+            self.remove_member(&name, nick);
+        }
+        removed
+    }
+
+    fn rename_user(&mut self, old: &str, new: &str) -> bool {
+        let Some(mut client) = self.users.remove(&key(old)) else { return false };
+        client.name = new.to_string();
+        self.users.insert(key(new), client);
+        for channel in self.channels.values_mut() {
+            if channel.remove_member(old) {
+                channel.members.insert(new.to_string());
+            }
+        }
+        true
+    }
+
+    /// Remove a member; a channel without members ceases to exist, unless it is permanent (`+P`).
+    fn remove_member(&mut self, channel: &str, nick: &str) -> bool {
+        let Some(cached) = self.channels.get_mut(&key(channel)) else { return false };
+        let removed = cached.remove_member(nick);
+        let permanent = cached.modes.as_deref().is_some_and(|m| m.split(' ').next().unwrap_or("").contains('P'));
+        if cached.members.is_empty() && !permanent {
+            self.channels.remove(&key(channel));
+        }
+        removed
+    }
+
+    /// Number of events that changed the state.
+    pub fn events_applied(&self) -> u64 {
+        self.events_applied
+    }
+
+    /// Look up a user by nick (case-insensitive).
+    pub fn user(&self, nick: &str) -> Option<&Client> {
+        self.users.get(&key(nick))
+    }
+
+    /// All users.
+    pub fn users(&self) -> impl Iterator<Item = &Client> {
+        self.users.values()
+    }
+
+    /// Look up a channel by name (case-insensitive).
+    pub fn channel(&self, name: &str) -> Option<&CachedChannel> {
+        self.channels.get(&key(name))
+    }
+
+    /// All channels.
+    pub fn channels(&self) -> impl Iterator<Item = &CachedChannel> {
+        self.channels.values()
+    }
+
+    /// All server objects, by name.
+    pub fn servers(&self) -> &BTreeMap<String, serde_json::Value> {
+        &self.servers
+    }
+
+    /// Users connecting from an IP address.
+    pub fn users_by_ip(&self, ip: &str) -> Vec<&Client> {
+        self.sorted(self.users.values().filter(|c| c.ip.as_deref() == Some(ip)))
+    }
+
+    /// Users connected to a server.
+    pub fn users_on_server(&self, server: &str) -> Vec<&Client> {
+        self.sorted(self.users.values().filter(|c| c.servername().is_some_and(|s| s.eq_ignore_ascii_case(server))))
+    }
+
+    /// Channels a user is a member of.
+    pub fn channels_of(&self, nick: &str) -> Vec<&CachedChannel> {
+        let mut channels: Vec<&CachedChannel> =
+            self.channels.values().filter(|c| c.members.iter().any(|m| m.eq_ignore_ascii_case(nick))).collect();
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+        channels
+    }
+
+    /// The members of a channel that are known users.
+    pub fn members(&self, channel: &str) -> Vec<&Client> {
+        match self.channel(channel) {
+            Some(c) => self.sorted(c.members.iter().filter_map(|m| self.user(m))),
+            None => Vec::new(),
+        }
+    }
+
+    fn sorted<'a>(&self, clients: impl Iterator<Item = &'a Client>) -> Vec<&'a Client> {
+        let mut clients: Vec<&Client> = clients.collect();
+        clients.sort_by(|a, b| a.name.cmp(&b.name));
+        clients
+    }
+
+    /// What `fresh` (the server) has that this state doesn't, and vice versa.
+    pub fn diff(&self, fresh: &NetworkState) -> Drift {
+        let names = |users: &HashMap<String, Client>, other: &HashMap<String, Client>| {
+            let mut names: Vec<String> =
+                users.iter().filter(|(k, _)| !other.contains_key(*k)).map(|(_, c)| c.name.clone()).collect();
+            names.sort();
+            names
+        };
+        let channel_names = |channels: &HashMap<String, CachedChannel>, other: &HashMap<String, CachedChannel>| {
+            let mut names: Vec<String> =
+                channels.iter().filter(|(k, _)| !other.contains_key(*k)).map(|(_, c)| c.name.clone()).collect();
+            names.sort();
+            names
+        };
+        let lower = |c: &CachedChannel| c.members.iter().map(|m| key(m)).collect::<BTreeSet<String>>();
+        let mut membership_changed: Vec<String> = fresh
+            .channels
+            .iter()
+            .filter(|(k, c)| self.channels.get(*k).is_some_and(|mine| lower(mine) != lower(c)))
+            .map(|(_, c)| c.name.clone())
+            .collect();
+        membership_changed.sort();
+        Drift {
+            missing_users: names(&fresh.users, &self.users),
+            stale_users: names(&self.users, &fresh.users),
+            missing_channels: channel_names(&fresh.channels, &self.channels),
+            stale_channels: channel_names(&self.channels, &fresh.channels),
+            membership_changed,
+        }
+    }
+}
+
+/// How a [`NetworkCache`] fetches the state.
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// `object_detail_level` for `user.list`; at least 2 for channel membership.
+    pub user_detail: i32,
+    /// `object_detail_level` for `channel.list`.
+    pub channel_detail: i32,
+    /// Time between full resyncs; `None` disables them.
+    pub resync_interval: Option<Duration>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self { user_detail: 2, channel_detail: 1, resync_interval: Some(Duration::from_secs(600)) }
+    }
+}
+
+/// Keeps a [`NetworkState`] in sync with the server.
+///
+/// Use a dedicated connection: the cache subscribes to the log (replacing any other
+/// subscription on the connection) and consumes its notifications.
+#[derive(Clone)]
+pub struct NetworkCache {
+    connection: Connection,
+    config: CacheConfig,
+    state: Arc<RwLock<NetworkState>>,
+    last_drift: Arc<Mutex<Option<Drift>>>,
+}
+
+impl NetworkCache {
+    /// Subscribe to the events and seed the state.
+    ///
+    /// Events that arrive while seeding are applied afterwards; all updates are idempotent,
+    /// so events already reflected in the seed do no harm.
+    pub async fn start(connection: Connection, config: CacheConfig) -> Result<Self> {
+        connection.log().subscribe(SOURCES.iter().map(|s| s.to_string()).collect()).await?;
+        let state = NetworkState::fetch(&connection, config.user_detail, config.channel_detail).await?;
+        Ok(Self {
+            connection,
+            config,
+            state: Arc::new(RwLock::new(state)),
+            last_drift: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Read access to the state.
+    pub fn read(&self) -> RwLockReadGuard<'_, NetworkState> {
+        self.state.read().unwrap()
+    }
+
+    /// The drift found at the last resync.
+    pub fn last_drift(&self) -> Option<Drift> {
+        self.last_drift.lock().unwrap().clone()
+    }
+
+    /// Apply the events that arrive within `timeout`. Returns how many changed the state.
+    pub async fn poll(&self, timeout: Duration) -> Result<usize> {
+        let log = self.connection.log();
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut applied = 0;
+        loop {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            match log.next_event(remaining).await? {
+                Some(event) => {
+                    if self.state.write().unwrap().apply(&event) {
+                        applied += 1;
+                    }
+                }
+                None => return Ok(applied),
+            }
+        }
+    }
+
+    /// Fetch the full state, replace the mirror with it and report what differed.
+    pub async fn resync(&self) -> Result<Drift> {
+        // Apply what is already queued, so it isn't counted as drift
+        self.poll(Duration::ZERO).await?;
+        let fresh = NetworkState::fetch(&self.connection, self.config.user_detail, self.config.channel_detail).await?;
+        let mut state = self.state.write().unwrap();
+        let drift = state.diff(&fresh);
+        let events_applied = state.events_applied;
+        *state = fresh;
+        state.events_applied = events_applied;
+        *self.last_drift.lock().unwrap() = Some(drift.clone());
+        Ok(drift)
+    }
+
+    /// Apply events and resync on schedule until the connection fails.
+    pub async fn run(&self) -> Result<()> {
+        let mut last_resync = Instant::now();
+        loop {
+            self.poll(Duration::from_millis(500)).await?;
+            if self.config.resync_interval.is_some_and(|i| last_resync.elapsed() >= i) {
+                self.resync().await?;
+                last_resync = Instant::now();
+            }
+        }
+    }
+}
@@ -0,0 +1,381 @@
+//! Authorization of requests by role, for handing out limited tools.
+//!
+//! A [`Policy`] is an [`Interceptor`] that checks every request against the role of its
+//! issuer before it is made. Roles list the methods they may call, optionally with
+//! constraints on the params; everything else is refused with an [`Error::Denied`]
+//! carrying a [`Denial`] that says why.
+//!
+//! Policies are loaded from a [`PolicyConfig`] document:
+//!
+//! ```toml
+//! # Role for issuers not matched below; without it they are refused everything
+//! default_role = "helpdesk"
+//!
+//! [[issuers]]
+//! pattern = "admin-*"
+//! role = "admin"
+//!
+//! [[roles.admin.allow]]
+//! methods = ["*"]
+//!
+//! [[roles.helpdesk.allow]]
+//! methods = ["*.list", "*.get", "rpc.info", "user.set_vhost"]
+//!
+//! [[roles.helpdesk.allow]]
+//! methods = ["channel.kick"]
+//! params = { channel = "#help*" }
+//!
+//! [[roles.helpdesk.allow]]
+//! methods = ["server_ban.add"]
+//! params = { type = "kline" }
+//! max_duration = "1d"
+//!
+//! [[roles.helpdesk.allow]]
+//! methods = ["user.kill"]
+//! protect_opers = true
+//!
+//! [[roles.helpdesk.deny]]
+//! methods = ["user.set_oper"]
+//! ```
+//!
+//! Issuers are matched by wildcard pattern, in the order they are listed; see
+//! [`Connection::as_issuer`](crate::Connection::as_issuer) to make requests on behalf of
+//! someone. A request is refused if a `deny` rule of the role matches it, and allowed if
+//! an `allow` rule matches it and all of that rule's constraints hold. An `rpc.add_timer`
+//! must be allowed itself, and so must the request it runs.
+//!
+//! The issuer is not authenticated: it is whatever the code holding the connection passes
+//! to `as_issuer`, and anyone with the connection can pick any issuer. A policy therefore
+//! only limits what trusted code does on behalf of its users, such as a web panel that sets
+//! the issuer from its own login. Code that must not get more than a role should get its
+//! own RPC user with its own connection, not a policy.
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! use std::sync::Arc;
+//! use unrealircd_rpc::policy::{Policy, PolicyConfig};
+//! use unrealircd_rpc::Error;
+//!
+//! # async fn example(conn: Connection) -> unrealircd_rpc::error::Result<()> {
+//! conn.add_interceptor(Arc::new(Policy::new(PolicyConfig::from_path("policy.toml")?)));
+//!
+//! match conn.as_issuer("bob").server_ban().add("*@192.0.2.1", "gline", "1d", "Drones").await {
+//!     Err(Error::Denied(denial)) => eprintln!("{}", denial.reason),
+//!     other => { other?; }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::ban_mask::wildcard_match;
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+use crate::interceptor::{Interceptor, Request};
+use crate::reconcile::StateFormat;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+/// A policy document.
+///
+/// Roles are picked by the request's issuer, which the holder of the connection chooses
+/// freely; see the [module docs](self) for what that means.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PolicyConfig {
+    /// Role for issuers that match none of `issuers`, including requests without issuer.
+    #[serde(default)]
+    pub default_role: Option<String>,
+    /// Roles of issuers, tried in order.
+    #[serde(default)]
+    pub issuers: Vec<IssuerRole>,
+    #[serde(default)]
+    pub roles: BTreeMap<String, Role>,
+}
+
+/// The role of the issuers matching a wildcard pattern.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IssuerRole {
+    pub pattern: String,
+    pub role: String,
+}
+
+/// What a role may do.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Role {
+    #[serde(default)]
+    pub allow: Vec<Permission>,
+    /// Checked before `allow`; constraints other than `methods` and `params` are ignored here.
+    #[serde(default)]
+    pub deny: Vec<Permission>,
+}
+
+/// Methods, and the constraints their requests must meet.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Permission {
+    /// Wildcard patterns for the method, such as `channel.*`.
+    pub methods: Vec<String>,
+    /// Wildcard patterns for params by name. All must match; a missing param doesn't.
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+    /// Longest `duration_string` (or spamfilter `ban_duration`) allowed, such as `1d`.
+    /// Permanent entries exceed every limit.
+    #[serde(default)]
+    pub max_duration: Option<String>,
+    /// Refuse requests whose `nick` is an IRC operator, checked with `user.get`.
+    #[serde(default)]
+    pub protect_opers: bool,
+}
+
+impl PolicyConfig {
+    /// Parse a policy document, checking that the roles it refers to exist.
+    pub fn parse(input: &str, format: StateFormat) -> Result<Self> {
+        let config: Self = format.deserialize(input)?;
+        for issuer in &config.issuers {
+            if !config.roles.contains_key(&issuer.role) {
+                return Err(Error::Parse(format!("issuer '{}': unknown role '{}'", issuer.pattern, issuer.role)));
+            }
+        }
+        if let Some(role) = config.default_role.as_ref().filter(|r| !config.roles.contains_key(*r)) {
+            return Err(Error::Parse(format!("default_role: unknown role '{}'", role)));
+        }
+        for (name, role) in &config.roles {
+            for permission in &role.allow {
+                if let Some(max) = permission.max_duration.as_deref() {
+                    if !matches!(parse_duration(max), Some(Some(_))) {
+                        return Err(Error::Parse(format!("role '{}': invalid max_duration '{}'", name, max)));
+                    }
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Read a policy document, picking the format from the file extension.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let format = StateFormat::from_path(path)
+            .ok_or_else(|| Error::Parse(format!("{}: unknown file extension", path.display())))?;
+        let input = std::fs::read_to_string(path).map_err(|e| Error::Other(format!("{}: {}", path.display(), e)))?;
+        Self::parse(&input, format)
+    }
+}
+
+/// Parse a duration such as `90`, `30m` or `1d12h` into seconds. `Some(None)` for a
+/// permanent one (`0` or `permanent`).
+fn parse_duration(s: &str) -> Option<Option<u64>> {
+    let s = s.trim();
+    if s == "0" || s.eq_ignore_ascii_case("permanent") {
+        return Some(None);
+    }
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let n: u64 = number.parse().ok()?;
+        number.clear();
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            'y' => 31536000,
+            _ => return None,
+        };
+        total = total.checked_add(n.checked_mul(unit)?)?;
+    }
+    if !number.is_empty() {
+        total = total.checked_add(number.parse().ok()?)?;
+    }
+    Some(if total == 0 { None } else { Some(total) })
+}
+
+/// Why a request was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DenialReason {
+    /// The issuer has no role, and there is no `default_role`.
+    NoRole,
+    /// No `allow` rule of the role covers the method.
+    MethodNotAllowed,
+    /// A `deny` rule of the role matches.
+    DeniedByRule,
+    /// A param is missing or doesn't match its pattern.
+    Param { name: String, pattern: String, value: Option<String> },
+    /// The duration is longer than allowed, or permanent.
+    Duration { max: String, requested: String },
+    /// The target user is an IRC operator.
+    ProtectedOper { nick: String },
+}
+
+impl fmt::Display for DenialReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DenialReason::NoRole => write!(f, "no role"),
+            DenialReason::MethodNotAllowed => write!(f, "method not allowed"),
+            DenialReason::DeniedByRule => write!(f, "denied by rule"),
+            DenialReason::Param { name, pattern, value: Some(value) } => {
+                write!(f, "{} '{}' does not match '{}'", name, value, pattern)
+            }
+            DenialReason::Param { name, pattern, value: None } => write!(f, "{} missing, must match '{}'", name, pattern),
+            DenialReason::Duration { max, requested } => write!(f, "duration {} exceeds {}", requested, max),
+            DenialReason::ProtectedOper { nick } => write!(f, "{} is an IRC operator", nick),
+        }
+    }
+}
+
+/// A refused request, as carried by [`Error::Denied`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Denial {
+    pub issuer: Option<String>,
+    pub role: Option<String>,
+    pub method: String,
+    pub reason: DenialReason,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} for {} (role {}): {}",
+            self.method,
+            self.issuer.as_deref().unwrap_or("-"),
+            self.role.as_deref().unwrap_or("-"),
+            self.reason
+        )
+    }
+}
+
+/// The string form of a param for matching: strings as they are, numbers and booleans as
+/// written in JSON.
+fn param_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+impl Permission {
+    fn covers(&self, method: &str) -> bool {
+        self.methods.iter().any(|pattern| wildcard_match(pattern, method))
+    }
+
+    /// The first param that doesn't match, if any.
+    fn param_mismatch(&self, params: &Value) -> Option<DenialReason> {
+        for (name, pattern) in &self.params {
+            let value = params.get(name).and_then(param_string);
+            if !value.as_deref().is_some_and(|v| wildcard_match(pattern, v)) {
+                return Some(DenialReason::Param { name: name.clone(), pattern: pattern.clone(), value });
+            }
+        }
+        None
+    }
+
+    fn duration_exceeded(&self, params: &Value) -> Option<DenialReason> {
+        let max = self.max_duration.as_deref()?;
+        let limit = parse_duration(max).flatten()?;
+        let requested = ["duration_string", "ban_duration"].iter().find_map(|name| params.get(*name).and_then(param_string));
+        // Adds without a duration are permanent
+        let requested = requested.unwrap_or_else(|| "0".to_string());
+        match parse_duration(&requested) {
+            Some(Some(seconds)) if seconds <= limit => None,
+            _ => Some(DenialReason::Duration { max: max.to_string(), requested }),
+        }
+    }
+
+    async fn oper_target(&self, connection: &Connection, params: &Value) -> Result<Option<DenialReason>> {
+        if !self.protect_opers {
+            return Ok(None);
+        }
+        let Some(nick) = params.get("nick").and_then(Value::as_str) else {
+            return Ok(None);
+        };
+        let client = match connection.user().get(nick, 2).await {
+            Ok(client) => client,
+            // The request will fail on its own
+            Err(Error::Rpc { .. }) => None,
+            Err(e) => return Err(e),
+        };
+        let user = client.as_ref().map(|c| &c["user"]);
+        let is_oper = user.is_some_and(|u| {
+            u.get("operlogin").is_some_and(|l| !l.is_null()) || u["modes"].as_str().unwrap_or("").contains('o')
+        });
+        Ok(is_oper.then(|| DenialReason::ProtectedOper { nick: nick.to_string() }))
+    }
+}
+
+/// Checks requests against a [`PolicyConfig`].
+pub struct Policy {
+    config: PolicyConfig,
+}
+
+impl Policy {
+    pub fn new(config: PolicyConfig) -> Self {
+        Self { config }
+    }
+
+    /// The role of an issuer.
+    pub fn role_of(&self, issuer: Option<&str>) -> Option<&str> {
+        let listed = issuer.and_then(|issuer| {
+            self.config.issuers.iter().find(|i| wildcard_match(&i.pattern, issuer)).map(|i| &i.role)
+        });
+        listed.or(self.config.default_role.as_ref()).map(String::as_str)
+    }
+
+    /// Check a request without making it. `connection` is used for the lookups that
+    /// `protect_opers` needs.
+    pub async fn check(&self, connection: &Connection, issuer: Option<&str>, method: &str, params: &Value) -> Result<()> {
+        let role_name = self.role_of(issuer);
+        let deny = |reason| {
+            Err(Error::Denied(Box::new(Denial {
+                issuer: issuer.map(str::to_string),
+                role: role_name.map(str::to_string),
+                method: method.to_string(),
+                reason,
+            })))
+        };
+        let Some(role) = role_name.and_then(|name| self.config.roles.get(name)) else {
+            return deny(DenialReason::NoRole);
+        };
+        if role.deny.iter().any(|p| p.covers(method) && p.param_mismatch(params).is_none()) {
+            return deny(DenialReason::DeniedByRule);
+        }
+
+        let mut first_failure = None;
+        for permission in role.allow.iter().filter(|p| p.covers(method)) {
+            let failure = match permission.param_mismatch(params).or_else(|| permission.duration_exceeded(params)) {
+                Some(failure) => Some(failure),
+                None => permission.oper_target(connection, params).await?,
+            };
+            match failure {
+                None => return self.check_timer(connection, issuer, method, params).await,
+                Some(failure) => {
+                    first_failure.get_or_insert(failure);
+                }
+            }
+        }
+        deny(first_failure.unwrap_or(DenialReason::MethodNotAllowed))
+    }
+
+    /// Check the request an allowed `rpc.add_timer` runs.
+    async fn check_timer(&self, connection: &Connection, issuer: Option<&str>, method: &str, params: &Value) -> Result<()> {
+        match crate::method::timer_request(params).filter(|_| method == "rpc.add_timer") {
+            Some((method, params)) => Box::pin(self.check(connection, issuer, method, params)).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Interceptor for Policy {
+    async fn before(&self, connection: &Connection, request: &mut Request) -> Result<Option<Value>> {
+        self.check(connection, request.issuer(), &request.method, &request.params).await?;
+        Ok(None)
+    }
+}
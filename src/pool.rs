@@ -0,0 +1,405 @@
+//! A pool of connections to one server, for services that send many requests at once.
+//!
+//! A single [`Connection`] sends one request at a time. A [`Pool`] keeps up to
+//! [`max_size`](PoolConfig::max_size) authenticated connections open and hands them out
+//! with [`get`](Pool::get); the connection goes back to the pool when the returned
+//! [`PooledConnection`] is dropped. Each connection is set up by [`Connection::connect`],
+//! so an issuer set in the [`Options`](crate::Options) is sent with `rpc.set_issuer` on
+//! every one of them.
+//!
+//! The pool also has the handler accessors of a connection. Every request made through
+//! them checks out a connection for just that request:
+//!
+//! ```rust,no_run
+//! use unrealircd_rpc::pool::{Pool, PoolConfig};
+//! use unrealircd_rpc::Connection;
+//!
+//! # async fn example() -> unrealircd_rpc::error::Result<()> {
+//! let pool = Pool::new(
+//!     || Connection::new("wss://127.0.0.1:8600/".to_string(), "username:password".to_string(), None),
+//!     PoolConfig { max_size: 16, ..Default::default() },
+//! );
+//! let users = pool.user().get_all(1).await?;
+//!
+//! // Several requests on one connection
+//! let conn = pool.get().await?;
+//! conn.channel().set_topic("#help", "Ask away", None, None).await?;
+//! conn.channel().set_mode("#help", "+t", "").await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Pooled connections can't be used for `log.subscribe`; give a subscriber its own
+//! connection.
+
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+use crate::metrics::{header, sample};
+use crate::profile::Profile;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Size and health checking of a [`Pool`].
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Maximum number of open connections.
+    pub max_size: usize,
+    /// How long [`Pool::get`] waits for a free connection before failing with
+    /// [`Error::Timeout`].
+    pub checkout_timeout: Duration,
+    /// Idle connections unused for this long are checked with `rpc.info` before they are
+    /// handed out.
+    pub health_check_after: Duration,
+    /// [`Pool::reap`] closes connections idle for longer than this.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 8,
+            checkout_timeout: Duration::from_secs(10),
+            health_check_after: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(600)),
+        }
+    }
+}
+
+/// A snapshot of the pool's counters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PoolStats {
+    /// Open connections, in use or idle.
+    pub open: usize,
+    /// Connections checked out right now.
+    pub in_use: usize,
+    /// Idle connections.
+    pub idle: usize,
+    /// Successful checkouts.
+    pub checkouts: u64,
+    /// Total time spent waiting for a free connection, in seconds.
+    pub wait_seconds: f64,
+    /// Longest wait for a free connection, in seconds.
+    pub max_wait_seconds: f64,
+    /// Checkouts that timed out waiting for a free connection.
+    pub timeouts: u64,
+    /// Failed connection attempts and failed health checks.
+    pub failures: u64,
+    /// Connections closed because they were broken or idle too long.
+    pub reaped: u64,
+}
+
+impl PoolStats {
+    /// Append the counters in Prometheus text format.
+    pub fn encode(&self, out: &mut String) {
+        let gauges = [
+            ("unrealircd_pool_connections_open", "Open pooled connections.", self.open as f64),
+            ("unrealircd_pool_connections_in_use", "Pooled connections checked out.", self.in_use as f64),
+            ("unrealircd_pool_connections_idle", "Idle pooled connections.", self.idle as f64),
+        ];
+        for (name, help, value) in gauges {
+            header(out, name, "gauge", help);
+            sample(out, name, &[], value);
+        }
+        let counters = [
+            ("unrealircd_pool_checkouts_total", "Successful checkouts.", self.checkouts as f64),
+            ("unrealircd_pool_wait_seconds_total", "Time spent waiting for a free connection.", self.wait_seconds),
+            ("unrealircd_pool_timeouts_total", "Checkouts that timed out.", self.timeouts as f64),
+            ("unrealircd_pool_failures_total", "Failed connects and health checks.", self.failures as f64),
+            ("unrealircd_pool_reaped_total", "Connections closed by the pool.", self.reaped as f64),
+        ];
+        for (name, help, value) in counters {
+            header(out, name, "counter", help);
+            sample(out, name, &[], value);
+        }
+    }
+}
+
+struct Idle {
+    connection: Connection,
+    since: Instant,
+}
+
+type Factory = Box<dyn Fn() -> Connection + Send + Sync>;
+
+pub(crate) struct PoolInner {
+    factory: Factory,
+    config: PoolConfig,
+    permits: Arc<Semaphore>,
+    idle: Mutex<Vec<Idle>>,
+    open: AtomicUsize,
+    in_use: AtomicUsize,
+    stats: Mutex<PoolStats>,
+}
+
+impl PoolInner {
+    pub(crate) async fn checkout(self: &Arc<Self>) -> Result<PooledConnection> {
+        let started = Instant::now();
+        let permit = match tokio::time::timeout(self.config.checkout_timeout, self.permits.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => permit,
+            Ok(Err(_)) => return Err(Error::ConnectionClosed),
+            Err(_) => {
+                self.stats.lock().unwrap().timeouts += 1;
+                return Err(Error::Timeout);
+            }
+        };
+        let waited = started.elapsed().as_secs_f64();
+
+        let connection = loop {
+            let idle = self.idle.lock().unwrap().pop();
+            match idle {
+                Some(Idle { connection, since }) => {
+                    if since.elapsed() < self.config.health_check_after || Box::pin(connection.rpc().info()).await.is_ok() {
+                        break connection;
+                    }
+                    self.open.fetch_sub(1, Ordering::SeqCst);
+                    {
+                        let mut stats = self.stats.lock().unwrap();
+                        stats.failures += 1;
+                        stats.reaped += 1;
+                    }
+                    let _ = connection.clone().close().await;
+                }
+                None => {
+                    let mut connection = (self.factory)();
+                    if let Err(e) = Box::pin(connection.connect()).await {
+                        self.stats.lock().unwrap().failures += 1;
+                        return Err(e);
+                    }
+                    self.open.fetch_add(1, Ordering::SeqCst);
+                    break connection;
+                }
+            }
+        };
+
+        self.in_use.fetch_add(1, Ordering::SeqCst);
+        let mut stats = self.stats.lock().unwrap();
+        stats.checkouts += 1;
+        stats.wait_seconds += waited;
+        stats.max_wait_seconds = stats.max_wait_seconds.max(waited);
+        drop(stats);
+        Ok(PooledConnection { connection: Some(connection), pool: self.clone(), discard: false, _permit: permit })
+    }
+
+    /// Send one request on a checked out connection; used by the pool's own [`Connection`],
+    /// which has already run the interceptors, audit sink and rate limiter for it.
+    pub(crate) async fn query(
+        self: &Arc<Self>,
+        issuer: Option<&str>,
+        method: &str,
+        params: serde_json::Value,
+        no_wait: bool,
+    ) -> Result<serde_json::Value> {
+        let mut conn = self.checkout().await?;
+        let target = match issuer {
+            Some(issuer) => conn.as_issuer(issuer),
+            None => Connection::clone(&conn),
+        };
+        let result = Box::pin(target.send_pooled(method, params, no_wait)).await;
+        if matches!(result, Err(Error::ConnectionClosed | Error::WebSocket(_) | Error::Timeout)) {
+            conn.discard = true;
+        }
+        result
+    }
+}
+
+/// A connection checked out of a [`Pool`]. It goes back to the pool when dropped.
+pub struct PooledConnection {
+    connection: Option<Connection>,
+    pool: Arc<PoolInner>,
+    discard: bool,
+    // Dropped after the connection is back in the idle list
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledConnection {
+    /// Close the connection instead of returning it to the pool, e.g. after an error that
+    /// left it in an unknown state.
+    pub fn discard(mut self) {
+        self.discard = true;
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.connection.as_ref().expect("pooled connection is present until dropped")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        self.pool.in_use.fetch_sub(1, Ordering::SeqCst);
+        let Some(connection) = self.connection.take() else { return };
+        if self.discard {
+            self.pool.open.fetch_sub(1, Ordering::SeqCst);
+            self.pool.stats.lock().unwrap().reaped += 1;
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                let mut connection = connection;
+                runtime.spawn(async move {
+                    let _ = connection.close().await;
+                });
+            }
+        } else {
+            self.pool.idle.lock().unwrap().push(Idle { connection, since: Instant::now() });
+        }
+    }
+}
+
+/// A pool of connections to one server.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<PoolInner>,
+    connection: Connection,
+}
+
+impl Pool {
+    /// Create a pool. `factory` creates a new, not yet connected, connection; the pool
+    /// connects it. No connection is opened until the first request.
+    ///
+    /// `factory` is also called once here for the pool's own [`connection`](Self::connection).
+    /// That one is never connected: it only carries the settings (interceptors, audit sink,
+    /// rate limits, retry policy) that requests made through the pool go through, once each,
+    /// before they are sent on a pooled connection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config.max_size` is 0.
+    pub fn new(factory: impl Fn() -> Connection + Send + Sync + 'static, config: PoolConfig) -> Self {
+        assert!(config.max_size > 0, "a pool needs room for at least one connection");
+        let template = factory();
+        let inner = Arc::new(PoolInner {
+            factory: Box::new(factory),
+            permits: Arc::new(Semaphore::new(config.max_size)),
+            config,
+            idle: Mutex::new(Vec::new()),
+            open: AtomicUsize::new(0),
+            in_use: AtomicUsize::new(0),
+            stats: Mutex::new(PoolStats::default()),
+        });
+        Self { connection: template.into_pooled(inner.clone()), inner }
+    }
+
+    /// Create a pool of connections for a profile.
+    pub fn from_profile(profile: &Profile, config: PoolConfig) -> Self {
+        let profile = profile.clone();
+        Self::new(move || profile.connection(), config)
+    }
+
+    /// Check out a connection, opening a new one if none is idle and the pool isn't full.
+    /// Waits up to [`checkout_timeout`](PoolConfig::checkout_timeout) for a free one.
+    pub async fn get(&self) -> Result<PooledConnection> {
+        self.inner.checkout().await
+    }
+
+    /// A connection that checks out a pooled connection for each request. Its dry-run
+    /// mode and metrics are its own; the handler accessors of the pool use it.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// A connection like [`connection`](Self::connection) whose requests are made on behalf
+    /// of `issuer`. See [`Connection::as_issuer`].
+    pub fn as_issuer(&self, issuer: &str) -> Connection {
+        self.connection.as_issuer(issuer)
+    }
+
+    /// Health-check all idle connections, closing the broken ones and those idle for
+    /// longer than [`idle_timeout`](PoolConfig::idle_timeout). Returns how many were closed.
+    pub async fn reap(&self) -> usize {
+        let mut closed = 0;
+        // One at a time, each holding a permit while it is out of the idle list, so
+        // checkouts meanwhile neither miss the others nor open more than `max_size`
+        let count = self.inner.idle.lock().unwrap().len();
+        for _ in 0..count {
+            // Without a free permit, every connection is checked out
+            let Ok(_permit) = self.inner.permits.clone().try_acquire_owned() else { break };
+            let entry = {
+                let mut idle = self.inner.idle.lock().unwrap();
+                if idle.is_empty() {
+                    break;
+                }
+                idle.remove(0)
+            };
+            let expired = self.inner.config.idle_timeout.is_some_and(|t| entry.since.elapsed() > t);
+            if !expired && entry.connection.rpc().info().await.is_ok() {
+                self.inner.idle.lock().unwrap().push(Idle { connection: entry.connection, since: Instant::now() });
+                continue;
+            }
+            self.inner.open.fetch_sub(1, Ordering::SeqCst);
+            {
+                let mut stats = self.inner.stats.lock().unwrap();
+                stats.reaped += 1;
+                if !expired {
+                    stats.failures += 1;
+                }
+            }
+            let _ = entry.connection.clone().close().await;
+            closed += 1;
+        }
+        closed
+    }
+
+    /// Call [`reap`](Self::reap) every `interval` until the task is dropped.
+    pub async fn run_maintenance(&self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            self.reap().await;
+        }
+    }
+
+    /// The pool's counters.
+    pub fn pool_stats(&self) -> PoolStats {
+        let mut stats = self.inner.stats.lock().unwrap().clone();
+        stats.open = self.inner.open.load(Ordering::SeqCst);
+        stats.in_use = self.inner.in_use.load(Ordering::SeqCst);
+        stats.idle = self.inner.idle.lock().unwrap().len();
+        stats
+    }
+
+    // Handler accessors
+    pub fn rpc(&self) -> crate::rpc::Rpc {
+        self.connection.rpc()
+    }
+
+    pub fn server(&self) -> crate::server::Server {
+        self.connection.server()
+    }
+
+    pub fn user(&self) -> crate::user::User {
+        self.connection.user()
+    }
+
+    pub fn channel(&self) -> crate::channel::Channel {
+        self.connection.channel()
+    }
+
+    pub fn server_ban(&self) -> crate::server_ban::ServerBan {
+        self.connection.server_ban()
+    }
+
+    pub fn spamfilter(&self) -> crate::spamfilter::Spamfilter {
+        self.connection.spamfilter()
+    }
+
+    pub fn name_ban(&self) -> crate::name_ban::NameBan {
+        self.connection.name_ban()
+    }
+
+    pub fn log(&self) -> crate::log::Log {
+        self.connection.log()
+    }
+
+    pub fn stats(&self) -> crate::stats::Stats {
+        self.connection.stats()
+    }
+
+    pub fn server_ban_exception(&self) -> crate::server_ban_exception::ServerBanException {
+        self.connection.server_ban_exception()
+    }
+}
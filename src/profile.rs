@@ -0,0 +1,185 @@
+//! Connection profiles from environment variables and configuration files.
+//!
+//! A profile file is TOML with one table per profile:
+//!
+//! ```toml
+//! [default]
+//! url = "wss://127.0.0.1:8600/"
+//! username = "adminpanel"
+//! password = "secret"
+//! tls_verify = false
+//!
+//! [hub2]
+//! url = "wss://hub2.example.org:8600/"
+//! username = "adminpanel"
+//! password = "secret"
+//! issuer = "helpdesk"
+//! fallback_urls = ["wss://hub3.example.org:8600/"]
+//!
+//! [hub2.rate_limits]
+//! write = { per_second = 10, burst = 20 }
+//! kill = { per_second = 2, burst = 1 }
+//! ```
+//!
+//! The environment variables `UNREALIRCD_WS_URL`, `UNREALIRCD_API_USERNAME` and
+//! `UNREALIRCD_API_PASSWORD` override the corresponding profile fields.
+
+use crate::connection::{Connection, Endpoint, Options};
+use crate::error::{Error, Result};
+use crate::reconcile::StateFormat;
+use crate::throttle::RateLimits;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The URL used when neither the profile nor the environment sets one.
+pub const DEFAULT_URL: &str = "wss://127.0.0.1:8600/";
+
+/// Everything needed to create a [`Connection`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(default = "default_url")]
+    pub url: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    #[serde(default = "default_tls_verify")]
+    pub tls_verify: bool,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// Endpoints to fail over to when `url` is unreachable, in order of preference.
+    #[serde(default)]
+    pub fallback_urls: Vec<String>,
+    /// Client-side rate limits, see the [`throttle`](crate::throttle) module.
+    #[serde(default)]
+    pub rate_limits: Option<RateLimits>,
+}
+
+fn default_url() -> String {
+    DEFAULT_URL.to_string()
+}
+
+fn default_tls_verify() -> bool {
+    true
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            url: default_url(),
+            username: String::new(),
+            password: String::new(),
+            tls_verify: true,
+            issuer: None,
+            fallback_urls: Vec::new(),
+            rate_limits: None,
+        }
+    }
+}
+
+impl Profile {
+    /// The default profile file: `$UNREALIRCD_RPC_CONFIG`, or `unrealircd-rpc/profiles.toml`
+    /// in `$XDG_CONFIG_HOME` or `~/.config`.
+    pub fn default_config_path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("UNREALIRCD_RPC_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+        Some(base.join("unrealircd-rpc").join("profiles.toml"))
+    }
+
+    /// Load all profiles from a profile file. Needs the `toml` feature.
+    pub fn load_all(path: &Path) -> Result<BTreeMap<String, Profile>> {
+        let input = std::fs::read_to_string(path).map_err(|e| Error::Other(format!("{}: {}", path.display(), e)))?;
+        StateFormat::Toml.deserialize(&input).map_err(|e| match e {
+            Error::Parse(e) => Error::Parse(format!("{}: {}", path.display(), e)),
+            e => e,
+        })
+    }
+
+    /// Load one profile from a profile file.
+    pub fn load(path: &Path, name: &str) -> Result<Self> {
+        Self::load_all(path)?
+            .remove(name)
+            .ok_or_else(|| Error::Other(format!("{}: no profile named '{}'", path.display(), name)))
+    }
+
+    /// Build a profile from the environment variables only.
+    pub fn from_env() -> Result<Self> {
+        let mut profile = Profile::default();
+        profile.apply_env();
+        profile.check()?;
+        Ok(profile)
+    }
+
+    /// Find the profile to use.
+    ///
+    /// `name` (default `default`) is looked up in `config`, or in the default profile file
+    /// if that exists. Environment variables are applied on top.
+    pub fn resolve(config: Option<&Path>, name: Option<&str>) -> Result<Self> {
+        let config = config.map(Path::to_path_buf).or_else(|| Self::default_config_path().filter(|p| p.exists()));
+        let mut profile = match (&config, name) {
+            (Some(path), name) => {
+                let mut all = Self::load_all(path)?;
+                match (all.remove(name.unwrap_or("default")), name) {
+                    (Some(profile), _) => profile,
+                    (None, None) => Profile::default(),
+                    (None, Some(name)) => {
+                        return Err(Error::Other(format!("{}: no profile named '{}'", path.display(), name)))
+                    }
+                }
+            }
+            (None, Some(name)) => return Err(Error::Other(format!("no profile file found for profile '{}'", name))),
+            (None, None) => Profile::default(),
+        };
+        profile.apply_env();
+        profile.check()?;
+        Ok(profile)
+    }
+
+    /// The `username:password` API login.
+    pub fn api_login(&self) -> String {
+        format!("{}:{}", self.username, self.password)
+    }
+
+    /// Create a (not yet connected) connection for this profile.
+    pub fn connection(&self) -> Connection {
+        let options = Some(Options {
+            tls_verify: self.tls_verify,
+            issuer: self.issuer.clone(),
+        });
+        let connection = if self.fallback_urls.is_empty() {
+            Connection::new(self.url.clone(), self.api_login(), options)
+        } else {
+            // Equal weights, so the endpoints are tried in the listed order
+            let endpoints = std::iter::once(&self.url).chain(&self.fallback_urls).map(|url| Endpoint::new(url)).collect();
+            Connection::with_endpoints(endpoints, self.api_login(), options)
+        };
+        connection.set_rate_limits(self.rate_limits);
+        connection
+    }
+
+    fn apply_env(&mut self) {
+        if let Ok(url) = std::env::var("UNREALIRCD_WS_URL") {
+            self.url = url;
+        }
+        if let Ok(username) = std::env::var("UNREALIRCD_API_USERNAME") {
+            self.username = username;
+        }
+        if let Ok(password) = std::env::var("UNREALIRCD_API_PASSWORD") {
+            self.password = password;
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.username.is_empty() || self.password.is_empty() {
+            return Err(Error::Other(
+                "no API credentials: set UNREALIRCD_API_USERNAME and UNREALIRCD_API_PASSWORD or use a profile".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,486 @@
+//! Declarative reconciliation of bans, exceptions, name bans and spamfilters.
+//!
+//! A [`DesiredState`] document (JSON, TOML or YAML) describes the TKLs that should exist.
+//! [`Reconciler::plan`] diffs it against the server and produces a [`Plan`] of adds and
+//! deletes, which [`Reconciler::apply`] then executes.
+//!
+//! Only entries owned by the tool are ever deleted or replaced. Ownership is decided by
+//! [`Ownership`]: a matching `set_by`, or a tag contained in the reason.
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! # use unrealircd_rpc::reconcile::{DesiredState, Ownership, Reconciler};
+//! # async fn example(conn: Connection) -> unrealircd_rpc::error::Result<()> {
+//! let desired = DesiredState::from_path("bans.toml")?;
+//! let reconciler = Reconciler::new(conn, Ownership::tag("[managed]"));
+//! let plan = reconciler.plan(&desired).await?;
+//! print!("{}", plan);
+//! for result in reconciler.apply(&plan).await {
+//!     println!("{}: {:?}", result.change, result.result.is_ok());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::ban_mask::BanMask;
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+use crate::throttle::Bulk;
+use crate::tkl::{parse_duration, remaining_duration, Tkl};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+
+/// The format of a desired state document, or of the other documents read the same way.
+///
+/// TOML needs the `toml` feature and YAML the `yaml` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl StateFormat {
+    /// Guess the format from a file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(StateFormat::Json),
+            "toml" => Some(StateFormat::Toml),
+            "yaml" | "yml" => Some(StateFormat::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Deserialize a document in this format.
+    pub fn deserialize<T: DeserializeOwned>(self, input: &str) -> Result<T> {
+        match self {
+            StateFormat::Json => Ok(serde_json::from_str(input)?),
+            #[cfg(any(test, feature = "toml"))]
+            StateFormat::Toml => toml::from_str(input).map_err(|e| Error::Parse(e.to_string())),
+            #[cfg(not(any(test, feature = "toml")))]
+            StateFormat::Toml => Err(Error::Parse("TOML documents need the `toml` feature".to_string())),
+            #[cfg(any(test, feature = "yaml"))]
+            StateFormat::Yaml => serde_yaml_ng::from_str(input).map_err(|e| Error::Parse(e.to_string())),
+            #[cfg(not(any(test, feature = "yaml")))]
+            StateFormat::Yaml => Err(Error::Parse("YAML documents need the `yaml` feature".to_string())),
+        }
+    }
+}
+
+/// The TKLs that should exist on the network.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DesiredState {
+    #[serde(default)]
+    pub server_bans: Vec<DesiredServerBan>,
+    #[serde(default)]
+    pub exceptions: Vec<DesiredException>,
+    #[serde(default)]
+    pub name_bans: Vec<DesiredNameBan>,
+    #[serde(default)]
+    pub spamfilters: Vec<DesiredSpamfilter>,
+}
+
+/// A desired server ban.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DesiredServerBan {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ban_type: String,
+    pub reason: String,
+    #[serde(default = "permanent")]
+    pub duration: String,
+}
+
+/// A desired server ban exception.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DesiredException {
+    pub name: String,
+    pub exception_types: String,
+    pub reason: String,
+    #[serde(default = "permanent")]
+    pub duration: String,
+}
+
+/// A desired name ban (QLine).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DesiredNameBan {
+    pub name: String,
+    pub reason: String,
+    #[serde(default = "permanent")]
+    pub duration: String,
+}
+
+/// A desired spamfilter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DesiredSpamfilter {
+    pub name: String,
+    pub match_type: String,
+    pub targets: String,
+    pub action: String,
+    #[serde(default = "permanent")]
+    pub ban_duration: String,
+    pub reason: String,
+}
+
+fn permanent() -> String {
+    "0".to_string()
+}
+
+impl DesiredState {
+    /// Parse a desired state document. Two entries with the same key are an error.
+    pub fn parse(input: &str, format: StateFormat) -> Result<Self> {
+        let state: Self = format.deserialize(input)?;
+        let mut keys = BTreeSet::new();
+        for tkl in state.to_tkls(&Ownership::default()) {
+            if !keys.insert(tkl.key()) {
+                return Err(Error::Parse(format!("duplicate entry '{}'", tkl.key())));
+            }
+        }
+        Ok(state)
+    }
+
+    /// Read a desired state document, picking the format from the file extension.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let format = StateFormat::from_path(path)
+            .ok_or_else(|| Error::Parse(format!("{}: unknown file extension", path.display())))?;
+        let input = std::fs::read_to_string(path).map_err(|e| Error::Other(format!("{}: {}", path.display(), e)))?;
+        Self::parse(&input, format)
+    }
+
+    /// All desired entries as TKL objects, with ownership markers applied.
+    pub fn to_tkls(&self, ownership: &Ownership) -> Vec<Tkl> {
+        let mut tkls = Vec::new();
+        for b in &self.server_bans {
+            tkls.push(Tkl {
+                tkl_type: b.ban_type.clone(),
+                name: normalize_mask(&b.name),
+                reason: Some(b.reason.clone()),
+                duration_string: Some(b.duration.clone()),
+                ..Default::default()
+            });
+        }
+        for e in &self.exceptions {
+            tkls.push(Tkl {
+                tkl_type: "exception".to_string(),
+                name: normalize_mask(&e.name),
+                exception_types: Some(e.exception_types.clone()),
+                reason: Some(e.reason.clone()),
+                duration_string: Some(e.duration.clone()),
+                ..Default::default()
+            });
+        }
+        for n in &self.name_bans {
+            tkls.push(Tkl {
+                tkl_type: "qline".to_string(),
+                name: n.name.clone(),
+                reason: Some(n.reason.clone()),
+                duration_string: Some(n.duration.clone()),
+                ..Default::default()
+            });
+        }
+        for s in &self.spamfilters {
+            tkls.push(Tkl {
+                tkl_type: "spamfilter".to_string(),
+                name: s.name.clone(),
+                match_type: Some(s.match_type.clone()),
+                spamfilter_targets: Some(s.targets.clone()),
+                ban_action: Some(s.action.clone()),
+                ban_duration_string: Some(s.ban_duration.clone()),
+                reason: Some(s.reason.clone()),
+                ..Default::default()
+            });
+        }
+        for tkl in &mut tkls {
+            ownership.mark(tkl);
+        }
+        tkls
+    }
+}
+
+/// A mask in the form the server lists it, e.g. `*@198.51.100.0/24` for `198.51.100.0/24`.
+/// Masks that don't parse are kept as they are, for the server to reject.
+fn normalize_mask(name: &str) -> String {
+    BanMask::parse(name).map(|mask| mask.to_string()).unwrap_or_else(|_| name.to_string())
+}
+
+/// Decides which entries on the server are managed by the reconciler.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Ownership {
+    /// Entries with this `set_by` are owned. Added entries get this `set_by`.
+    pub set_by: Option<String>,
+    /// Entries whose reason contains this tag are owned. Added entries get it appended to the reason.
+    pub tag: Option<String>,
+}
+
+impl Ownership {
+    /// Ownership by `set_by`.
+    pub fn set_by(set_by: &str) -> Self {
+        Self { set_by: Some(set_by.to_string()), tag: None }
+    }
+
+    /// Ownership by a tag in the reason, such as `[managed]`.
+    pub fn tag(tag: &str) -> Self {
+        Self { set_by: None, tag: Some(tag.to_string()) }
+    }
+
+    /// Whether an entry on the server is owned by the reconciler.
+    pub fn owns(&self, tkl: &Tkl) -> bool {
+        if tkl.set_in_config == Some(true) {
+            return false;
+        }
+        let by_set_by = self.set_by.is_some() && tkl.set_by == self.set_by;
+        let by_tag = match (&self.tag, &tkl.reason) {
+            (Some(tag), Some(reason)) => reason.contains(tag.as_str()),
+            _ => false,
+        };
+        by_set_by || by_tag
+    }
+
+    fn mark(&self, tkl: &mut Tkl) {
+        if let Some(tag) = &self.tag {
+            let reason = tkl.reason.get_or_insert_with(String::new);
+            if !reason.contains(tag.as_str()) {
+                if !reason.is_empty() {
+                    reason.push(' ');
+                }
+                reason.push_str(tag);
+            }
+        }
+        if self.set_by.is_some() {
+            tkl.set_by = self.set_by.clone();
+        }
+    }
+}
+
+/// Whether a change adds or deletes an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Add,
+    Delete,
+}
+
+/// A single step of a [`Plan`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub action: Action,
+    pub tkl: Tkl,
+}
+
+impl Change {
+    /// The JSON-RPC request that carries out this change.
+    pub fn request(&self) -> Option<(String, serde_json::Value)> {
+        match self.action {
+            Action::Add => self.tkl.add_request(),
+            Action::Delete => self.tkl.del_request(),
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = match self.action {
+            Action::Add => '+',
+            Action::Delete => '-',
+        };
+        write!(f, "{} {}", sign, self.tkl.key())
+    }
+}
+
+/// The changes needed to bring the server in line with a [`DesiredState`].
+///
+/// New entries are added before stale ones are deleted, so the network is never less
+/// protected than before. An entry that changed appears as a delete followed by an add, as
+/// the server refuses a second entry with the same key; see [`Reconciler::apply_with`].
+///
+/// The reason, exception types, duration and spamfilter ban duration are compared. The
+/// duration of a live entry is the time between its `set_at` and `expire_at`. A spamfilter's
+/// action is part of its key, so a changed action adds the new spamfilter and deletes the old one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Plan {
+    pub changes: Vec<Change>,
+}
+
+impl Plan {
+    /// Diff the desired entries against the entries currently on the server.
+    pub fn diff(desired: &DesiredState, live: &[Tkl], ownership: &Ownership) -> Self {
+        let live: BTreeMap<String, &Tkl> = live.iter().filter(|t| t.kind().is_some()).map(|t| (t.key(), t)).collect();
+        let wanted: BTreeMap<String, Tkl> = desired.to_tkls(ownership).into_iter().map(|t| (t.key(), t)).collect();
+        let wanted_keys: BTreeSet<String> = wanted.keys().cloned().collect();
+
+        let mut plan = Plan::default();
+        for (key, tkl) in wanted {
+            match live.get(&key) {
+                None => plan.push(Action::Add, tkl),
+                // Entries we don't own are left alone, even if they differ
+                Some(current) if !ownership.owns(current) => {}
+                Some(current) if differs(current, &tkl) => {
+                    plan.push(Action::Delete, (*current).clone());
+                    plan.push(Action::Add, tkl);
+                }
+                Some(_) => {}
+            }
+        }
+        for (key, current) in &live {
+            if ownership.owns(current) && !wanted_keys.contains(key) {
+                plan.push(Action::Delete, (*current).clone());
+            }
+        }
+        plan
+    }
+
+    /// Whether there is nothing to do.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    fn push(&mut self, action: Action, tkl: Tkl) {
+        self.changes.push(Change { action, tkl });
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            writeln!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+fn differs(current: &Tkl, wanted: &Tkl) -> bool {
+    let sorted = |s: &Option<String>| {
+        let mut chars: Vec<char> = s.as_deref().unwrap_or("").chars().collect();
+        chars.sort_unstable();
+        chars
+    };
+    current.reason != wanted.reason
+        || sorted(&current.exception_types) != sorted(&wanted.exception_types)
+        || durations_differ(current.ban_duration_string.as_deref(), wanted.ban_duration_string.as_deref())
+        || (!current.is_spamfilter() && duration_differs(current, wanted))
+}
+
+/// Whether two spamfilter ban durations differ, comparing them as seconds if they parse.
+fn durations_differ(current: Option<&str>, wanted: Option<&str>) -> bool {
+    let (current, wanted) = (current.unwrap_or("0"), wanted.unwrap_or("0"));
+    match (parse_duration(current), parse_duration(wanted)) {
+        (Some(current), Some(wanted)) => current != wanted,
+        _ => current != wanted,
+    }
+}
+
+/// Whether the duration of a live entry differs from the wanted `duration_string`. Entries
+/// whose duration can't be told are taken to match.
+fn duration_differs(current: &Tkl, wanted: &Tkl) -> bool {
+    let Some(wanted) = parse_duration(wanted.duration_string.as_deref().unwrap_or("0")) else {
+        return false;
+    };
+    let time = |t: &str| chrono::DateTime::parse_from_rfc3339(t).ok();
+    let current = match current.expire_at.as_deref().map(str::trim) {
+        None | Some("" | "0") => None,
+        Some(never) if never.eq_ignore_ascii_case("never") => None,
+        Some(expire_at) => match (current.set_at.as_deref().and_then(time), time(expire_at)) {
+            (Some(set_at), Some(expire_at)) => Some((expire_at - set_at).num_seconds().max(0) as u64),
+            _ => return false,
+        },
+    };
+    match (current, wanted) {
+        (Some(current), Some(wanted)) => current.abs_diff(wanted) > DURATION_SLACK,
+        (current, wanted) => current.is_some() != wanted.is_some(),
+    }
+}
+
+/// Seconds a live duration may be off from the wanted one, for rounding on the server.
+const DURATION_SLACK: u64 = 60;
+
+/// The outcome of applying one [`Change`].
+#[derive(Debug)]
+pub struct ChangeResult {
+    pub change: Change,
+    pub result: Result<serde_json::Value>,
+}
+
+/// Plans and applies changes against a server.
+#[derive(Clone)]
+pub struct Reconciler {
+    connection: Connection,
+    ownership: Ownership,
+}
+
+impl Reconciler {
+    /// Create a new reconciler.
+    pub fn new(connection: Connection, ownership: Ownership) -> Self {
+        Self { connection, ownership }
+    }
+
+    /// Compute the changes needed to reach the desired state.
+    pub async fn plan(&self, desired: &DesiredState) -> Result<Plan> {
+        let live = crate::tkl::fetch_all(&self.connection).await?;
+        Ok(Plan::diff(desired, &live, &self.ownership))
+    }
+
+    /// Apply a plan, in order. Failures do not stop the remaining changes.
+    pub async fn apply(&self, plan: &Plan) -> Vec<ChangeResult> {
+        self.apply_with(plan, &Bulk::new()).await
+    }
+
+    /// Like [`apply`](Self::apply), with progress reporting and pause/resume through `bulk`.
+    /// After a cancel, only the changes that were made are in the results.
+    ///
+    /// Replacing a changed entry is not atomic: the old entry is deleted before the new one is
+    /// added. If that add fails, the old entry is added back with the time it had left and the
+    /// add's error is returned. If putting it back fails too, the entry is gone and the error
+    /// says so.
+    pub async fn apply_with(&self, plan: &Plan, bulk: &Bulk) -> Vec<ChangeResult> {
+        let deleted: std::sync::Mutex<Option<&Tkl>> = std::sync::Mutex::new(None);
+        let results = bulk
+            .run(&plan.changes, |change| {
+                let deleted = &deleted;
+                async move {
+                    let result = apply_change(&self.connection, change).await;
+                    let replaced = deleted.lock().unwrap().take().filter(|old| old.key() == change.tkl.key());
+                    match (change.action, result) {
+                        (Action::Delete, result) => {
+                            if result.is_ok() {
+                                *deleted.lock().unwrap() = Some(&change.tkl);
+                            }
+                            result
+                        }
+                        (Action::Add, Err(e)) => match replaced {
+                            Some(old) => match self.put_back(old).await {
+                                Ok(_) => Err(e),
+                                Err(again) => Err(Error::Other(format!(
+                                    "{}; putting back the old entry failed too, so it is gone: {}",
+                                    e, again
+                                ))),
+                            },
+                            None => Err(e),
+                        },
+                        (Action::Add, result) => result,
+                    }
+                }
+            })
+            .await;
+        plan.changes.iter().zip(results).map(|(change, result)| ChangeResult { change: change.clone(), result }).collect()
+    }
+
+    /// Add a deleted entry back with the time it had left.
+    async fn put_back(&self, old: &Tkl) -> Result<serde_json::Value> {
+        let Some(duration) = remaining_duration(old.expire_at.as_deref())? else {
+            return Err(Error::Other(format!("'{}' expired in the meantime", old.key())));
+        };
+        let tkl = Tkl { duration_string: Some(duration), ..old.clone() };
+        apply_change(&self.connection, &Change { action: Action::Add, tkl }).await
+    }
+}
+
+/// Send the request for one change.
+pub(crate) async fn apply_change(connection: &Connection, change: &Change) -> Result<serde_json::Value> {
+    match change.request() {
+        Some((method, params)) => connection.query(&method, params, false).await,
+        None => Err(Error::Other(format!("unknown TKL type '{}'", change.tkl.tkl_type))),
+    }
+}
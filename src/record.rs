@@ -0,0 +1,174 @@
+//! Recording of JSON-RPC exchanges into fixture files.
+//!
+//! Attach a [`Recorder`] to a [`Connection`](crate::Connection) with
+//! [`Connection::set_recorder`](crate::Connection::set_recorder) to capture every request,
+//! its reply, and any pushed notifications, with their timing relative to the start of the
+//! recording. The resulting [`Fixture`] can be saved to a file and served back with
+//! `testing::ReplayServer` (available with the `testing` feature).
+
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// One recorded message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RecordedEntry {
+    /// A request sent by the client and the reply it got.
+    Request {
+        /// Milliseconds since the start of the recording, when the request was sent.
+        at_ms: u64,
+        method: String,
+        params: serde_json::Value,
+        /// The reply without `jsonrpc` and `id`, or `None` for requests sent with `no_wait`
+        /// and requests that got no reply.
+        reply: Option<serde_json::Value>,
+    },
+    /// A message pushed by the server without a request, such as a log event.
+    Notification {
+        /// Milliseconds since the start of the recording.
+        at_ms: u64,
+        message: serde_json::Value,
+    },
+}
+
+impl RecordedEntry {
+    /// Milliseconds since the start of the recording.
+    pub fn at_ms(&self) -> u64 {
+        match self {
+            RecordedEntry::Request { at_ms, .. } | RecordedEntry::Notification { at_ms, .. } => *at_ms,
+        }
+    }
+}
+
+/// A recorded session: all exchanges in the order they happened.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Fixture {
+    pub entries: Vec<RecordedEntry>,
+}
+
+impl Fixture {
+    /// Load a fixture from a JSON file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path).map_err(|e| Error::Other(format!("{}: {}", path.display(), e)))?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    /// Save the fixture as pretty-printed JSON.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let data = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, data).map_err(|e| Error::Other(format!("{}: {}", path.display(), e)))
+    }
+}
+
+/// Captures exchanges from a connection into a [`Fixture`].
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    start: Instant,
+    fixture: Arc<Mutex<Fixture>>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recorder {
+    /// Start a new, empty recording.
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            fixture: Arc::new(Mutex::new(Fixture::default())),
+        }
+    }
+
+    /// A copy of everything recorded so far.
+    pub fn fixture(&self) -> Fixture {
+        self.fixture.lock().unwrap().clone()
+    }
+
+    /// Save everything recorded so far to a file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.fixture().save(path)
+    }
+
+    /// Record a request as it is sent. Returns its index, for [`reply`](Self::reply).
+    pub(crate) fn request(&self, method: &str, params: &serde_json::Value) -> usize {
+        let mut fixture = self.fixture.lock().unwrap();
+        fixture.entries.push(RecordedEntry::Request {
+            at_ms: self.elapsed_ms(),
+            method: method.to_string(),
+            params: params.clone(),
+            reply: None,
+        });
+        fixture.entries.len() - 1
+    }
+
+    /// Attach the reply to the request recorded at `index`.
+    pub(crate) fn reply(&self, index: usize, reply: &serde_json::Value) {
+        let mut reply = reply.clone();
+        if let Some(obj) = reply.as_object_mut() {
+            obj.remove("jsonrpc");
+            obj.remove("id");
+        }
+        if let Some(RecordedEntry::Request { reply: slot, .. }) = self.fixture.lock().unwrap().entries.get_mut(index) {
+            *slot = Some(reply);
+        }
+    }
+
+    pub(crate) fn notification(&self, message: &serde_json::Value) {
+        self.push(RecordedEntry::Notification {
+            at_ms: self.elapsed_ms(),
+            message: message.clone(),
+        });
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn push(&self, entry: RecordedEntry) {
+        self.fixture.lock().unwrap().entries.push(entry);
+    }
+}
+
+/// Describe the differences between two JSON values, one line per differing path.
+///
+/// Paths start at `root`, e.g. `params.nick`. Returns an empty list when the values are equal.
+pub fn json_diff(root: &str, expected: &serde_json::Value, actual: &serde_json::Value) -> Vec<String> {
+    let mut diffs = Vec::new();
+    diff_at(root, expected, actual, &mut diffs);
+    diffs
+}
+
+fn diff_at(path: &str, expected: &serde_json::Value, actual: &serde_json::Value, diffs: &mut Vec<String>) {
+    use serde_json::Value;
+    match (expected, actual) {
+        (Value::Object(e), Value::Object(a)) => {
+            for (key, ev) in e {
+                let sub = format!("{}.{}", path, key);
+                match a.get(key) {
+                    Some(av) => diff_at(&sub, ev, av, diffs),
+                    None => diffs.push(format!("{}: expected {}, but it is missing", sub, ev)),
+                }
+            }
+            for (key, av) in a {
+                if !e.contains_key(key) {
+                    diffs.push(format!("{}.{}: unexpected {}", path, key, av));
+                }
+            }
+        }
+        (Value::Array(e), Value::Array(a)) if e.len() == a.len() => {
+            for (i, (ev, av)) in e.iter().zip(a).enumerate() {
+                diff_at(&format!("{}[{}]", path, i), ev, av, diffs);
+            }
+        }
+        _ if expected != actual => diffs.push(format!("{}: expected {}, got {}", path, expected, actual)),
+        _ => {}
+    }
+}
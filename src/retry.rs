@@ -0,0 +1,89 @@
+//! Retrying requests after transient failures.
+//!
+//! With a [`RetryPolicy`] set on a connection (see
+//! [`Connection::set_retry_policy`](crate::Connection::set_retry_policy)), a request that
+//! fails because the server could not be reached, the connection dropped or the reply timed
+//! out is sent again after a backoff, reconnecting first. Which requests are retried
+//! depends on whether sending them twice is harmless:
+//!
+//! - Reads (see [`is_idempotent`]) are always retried.
+//! - Adding or removing a ban, exception, name ban or spamfilter is retried only once a
+//!   `.get` of the same entry shows the first attempt did not land. If it did land, that
+//!   counts as success: an add returns the entry as `.get` found it, a removal returns an
+//!   empty object.
+//! - Other writes are retried only with [`RetryPolicy::retry_writes`].
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! use unrealircd_rpc::retry::RetryPolicy;
+//!
+//! # fn example(conn: Connection) {
+//! conn.set_retry_policy(Some(RetryPolicy { max_attempts: 5, ..Default::default() }));
+//! # }
+//! ```
+
+use crate::method::MethodClass;
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+/// When and how often to retry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first.
+    pub max_attempts: u32,
+    /// Wait before the first retry.
+    pub initial_backoff: Duration,
+    /// Upper bound for the wait between attempts.
+    pub max_backoff: Duration,
+    /// Factor the wait grows by after each retry.
+    pub multiplier: f64,
+    /// Also retry writes that can't be checked, accepting that they may be applied twice.
+    pub retry_writes: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            retry_writes: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait before retry number `retry`, starting at 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.max(1.0).powi(retry.saturating_sub(1) as i32);
+        // Past `max_backoff` the product may not fit a Duration, or not be a number at all
+        Duration::try_from_secs_f64(self.initial_backoff.as_secs_f64() * factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// Whether sending a request twice has the same effect as sending it once, such as
+/// `*.list`, `*.get`, `stats.get` and `rpc.info`.
+pub fn is_idempotent(method: &str) -> bool {
+    MethodClass::of(method) == MethodClass::Read
+}
+
+/// The `.get` request that shows whether a TKL `.add` or `.del` landed.
+pub(crate) fn probe(method: &str, params: &Value) -> Option<(String, Value)> {
+    let (prefix, action) = method.rsplit_once('.')?;
+    if !matches!(action, "add" | "del") {
+        return None;
+    }
+    let keys: &[&str] = match prefix {
+        "server_ban" => &["name", "type"],
+        "server_ban_exception" | "name_ban" => &["name"],
+        "spamfilter" => &["name", "match_type", "spamfilter_targets", "ban_action"],
+        _ => return None,
+    };
+    let mut key = Map::new();
+    for name in keys {
+        key.insert(name.to_string(), params.get(*name)?.clone());
+    }
+    Some((format!("{}.get", prefix), Value::Object(key)))
+}
@@ -0,0 +1,548 @@
+//! Automation rules driven by log events.
+//!
+//! A rule says "when events matching this filter and these conditions occur `count` times
+//! within `window_secs`, run this action". Counting is per rule, or per value of a field
+//! of the event with `group_by` (for example one counter per IP address). After a rule
+//! fires for a key, it doesn't fire again for that key until `cooldown_secs` have passed.
+//!
+//! Rules are loaded from a [`RulesConfig`] document:
+//!
+//! ```toml
+//! sources = ["all", "!debug"]
+//!
+//! [[rules]]
+//! name = "connect-flood"
+//! event_ids = ["LOCAL_CLIENT_CONNECT"]
+//! group_by = "client.ip"
+//! count = 5
+//! window_secs = 30
+//! cooldown_secs = 600
+//! action = { type = "server_ban", mask = "*@{client.ip}", duration = "1h", reason = "Connect flood" }
+//!
+//! [[rules]]
+//! name = "spam-kill"
+//! event_ids = ["SPAMFILTER_MATCH"]
+//! conditions = { "client.user.username" = "~*" }
+//! dry_run = true
+//! action = { type = "kill", nick = "{client.name}", reason = "Spam" }
+//! ```
+//!
+//! Action fields may contain `{path}` placeholders, which are replaced by the value at that
+//! dotted path in the triggering event, and `{rule}`, `{key}` and `{count}`.
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! use unrealircd_rpc::rules::{RuleEngine, RulesConfig};
+//!
+//! # async fn example(conn: Connection) -> unrealircd_rpc::error::Result<()> {
+//! let mut engine = RuleEngine::new(conn, &RulesConfig::from_path("rules.toml")?)?;
+//! engine.watch("rules.toml");
+//! engine.on_fire(|firing| println!("{}: {}", firing.rule, firing.action));
+//! engine.run().await
+//! # }
+//! ```
+//!
+//! A rule, or the whole document, can be set to `dry_run`: matching and counting happen as
+//! usual, but the action is only reported. Actions are also not run when the connection is
+//! in [dry-run mode](crate::Connection::set_dry_run).
+
+use crate::ban_mask::wildcard_match;
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+use crate::log_event::LogEvent;
+use crate::reconcile::StateFormat;
+use crate::tail::LogFilter;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long [`RuleEngine::run`] waits for an event before checking the rules file.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a rule does when it fires.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleAction {
+    /// [`User::kill`](crate::user::User::kill).
+    Kill { nick: String, reason: String },
+    /// [`ServerBan::add`](crate::server_ban::ServerBan::add).
+    ServerBan {
+        mask: String,
+        #[serde(default = "default_ban_type")]
+        ban_type: String,
+        duration: String,
+        reason: String,
+    },
+    /// [`Channel::set_mode`](crate::channel::Channel::set_mode).
+    ChannelMode {
+        channel: String,
+        modes: String,
+        #[serde(default)]
+        parameters: String,
+    },
+    /// [`User::set_vhost`](crate::user::User::set_vhost).
+    SetVhost { nick: String, vhost: String },
+    /// POST `{"rule", "key", "count", "event"}` as JSON to `url`.
+    Webhook { url: String },
+}
+
+fn default_ban_type() -> String {
+    "gline".to_string()
+}
+
+impl RuleAction {
+    /// The action with all placeholders replaced.
+    fn render(&self, vars: &Vars) -> Result<RuleAction> {
+        let r = |s: &String| vars.render(s);
+        Ok(match self {
+            RuleAction::Kill { nick, reason } => RuleAction::Kill { nick: r(nick)?, reason: r(reason)? },
+            RuleAction::ServerBan { mask, ban_type, duration, reason } => RuleAction::ServerBan {
+                mask: r(mask)?,
+                ban_type: r(ban_type)?,
+                duration: r(duration)?,
+                reason: r(reason)?,
+            },
+            RuleAction::ChannelMode { channel, modes, parameters } => {
+                RuleAction::ChannelMode { channel: r(channel)?, modes: r(modes)?, parameters: r(parameters)? }
+            }
+            RuleAction::SetVhost { nick, vhost } => RuleAction::SetVhost { nick: r(nick)?, vhost: r(vhost)? },
+            RuleAction::Webhook { url } => RuleAction::Webhook { url: r(url)? },
+        })
+    }
+}
+
+impl fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleAction::Kill { nick, reason } => write!(f, "kill {} ({})", nick, reason),
+            RuleAction::ServerBan { mask, ban_type, duration, reason } => {
+                write!(f, "{} {} for {} ({})", ban_type, mask, duration, reason)
+            }
+            RuleAction::ChannelMode { channel, modes, parameters } if parameters.is_empty() => {
+                write!(f, "mode {} {}", channel, modes)
+            }
+            RuleAction::ChannelMode { channel, modes, parameters } => write!(f, "mode {} {} {}", channel, modes, parameters),
+            RuleAction::SetVhost { nick, vhost } => write!(f, "vhost {} {}", nick, vhost),
+            RuleAction::Webhook { url } => write!(f, "webhook {}", url),
+        }
+    }
+}
+
+fn one() -> usize {
+    1
+}
+
+/// A rule in a [`RulesConfig`]. Criteria that are left out match everything.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuleConfig {
+    /// Unique name, used in reports and to keep counters across reloads.
+    pub name: String,
+    #[serde(default)]
+    pub level: Option<String>,
+    #[serde(default)]
+    pub subsystems: Vec<String>,
+    /// Glob patterns for the event id.
+    #[serde(default)]
+    pub event_ids: Vec<String>,
+    /// Regular expression on the message.
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Glob patterns on event fields by dotted path, such as `client.ip`. All must match.
+    #[serde(default)]
+    pub conditions: BTreeMap<String, String>,
+    /// Matching events needed to fire.
+    #[serde(default = "one")]
+    pub count: usize,
+    /// The time `count` events must fall within. Required when `count` is above 1.
+    #[serde(default)]
+    pub window_secs: u64,
+    /// Dotted path of the field to count by. Events without the field are ignored.
+    #[serde(default)]
+    pub group_by: Option<String>,
+    /// Time after firing before the rule can fire again for the same key.
+    #[serde(default)]
+    pub cooldown_secs: u64,
+    /// Report instead of running the action.
+    #[serde(default)]
+    pub dry_run: bool,
+    pub action: RuleAction,
+}
+
+fn all_sources() -> Vec<String> {
+    vec!["all".to_string()]
+}
+
+/// A rules document: the `log.subscribe` sources and the rules.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RulesConfig {
+    #[serde(default = "all_sources")]
+    pub sources: Vec<String>,
+    /// Report instead of running the actions of all rules.
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+impl RulesConfig {
+    /// Parse a rules document.
+    pub fn parse(input: &str, format: StateFormat) -> Result<Self> {
+        format.deserialize(input)
+    }
+
+    /// Read a rules document, picking the format from the file extension.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let format = StateFormat::from_path(path)
+            .ok_or_else(|| Error::Parse(format!("{}: unknown file extension", path.display())))?;
+        let input = std::fs::read_to_string(path).map_err(|e| Error::Other(format!("{}: {}", path.display(), e)))?;
+        Self::parse(&input, format)
+    }
+}
+
+/// A rule that has reached its count for a key.
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    pub rule: String,
+    /// The `group_by` value, or empty without `group_by`.
+    pub key: String,
+    /// Matching events within the window, including this one.
+    pub count: usize,
+    /// The event that made the rule fire.
+    pub event: LogEvent,
+    /// The action as configured, before placeholders are replaced.
+    pub action: RuleAction,
+    /// Whether the rule or the document is in dry-run mode.
+    pub dry_run: bool,
+}
+
+/// The outcome of a fired rule.
+#[derive(Debug)]
+pub struct Firing {
+    pub rule: String,
+    pub key: String,
+    pub count: usize,
+    /// The action with placeholders replaced, or as configured if that failed.
+    pub action: RuleAction,
+    /// Whether the action was only reported.
+    pub dry_run: bool,
+    /// The server's reply, `null` for a dry run or a webhook.
+    pub result: Result<Value>,
+}
+
+struct Rule {
+    config: RuleConfig,
+    filter: LogFilter,
+}
+
+impl Rule {
+    fn compile(config: &RuleConfig) -> Result<Self> {
+        if config.count == 0 {
+            return Err(Error::Parse(format!("rule '{}': count must be at least 1", config.name)));
+        }
+        if config.count > 1 && config.window_secs == 0 {
+            return Err(Error::Parse(format!("rule '{}': window_secs is required when count is above 1", config.name)));
+        }
+        let mut filter = LogFilter {
+            min_level: config.level.clone(),
+            subsystems: config.subsystems.clone(),
+            event_ids: config.event_ids.clone(),
+            message: None,
+        };
+        if let Some(pattern) = &config.message {
+            filter.set_message(pattern).map_err(|e| Error::Parse(format!("rule '{}': {}", config.name, e)))?;
+        }
+        Ok(Self { config: config.clone(), filter })
+    }
+
+    /// The counting key for an event, or `None` if the rule doesn't apply to it.
+    fn key(&self, event: &LogEvent, fields: &Value) -> Option<String> {
+        if !self.filter.matches(event) {
+            return None;
+        }
+        for (path, pattern) in &self.config.conditions {
+            if !lookup(fields, path).is_some_and(|value| wildcard_match(pattern, &value)) {
+                return None;
+            }
+        }
+        match &self.config.group_by {
+            Some(path) => lookup(fields, path),
+            None => Some(String::new()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct RuleState {
+    seen: HashMap<String, VecDeque<Instant>>,
+    cooldown_until: HashMap<String, Instant>,
+}
+
+impl RuleState {
+    fn prune(&mut self, now: Instant, window: Duration) {
+        self.seen.retain(|_, times| {
+            while times.front().is_some_and(|t| now.duration_since(*t) > window) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        self.cooldown_until.retain(|_, until| *until > now);
+    }
+}
+
+/// The value at a dotted path in an event, as a string.
+fn lookup(fields: &Value, path: &str) -> Option<String> {
+    let value = path.split('.').try_fold(fields, |value, key| value.get(key))?;
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Null | Value::Object(_) | Value::Array(_) => None,
+        other => Some(other.to_string()),
+    }
+}
+
+/// Values for the placeholders of an action.
+struct Vars<'a> {
+    trigger: &'a Trigger,
+    fields: Value,
+}
+
+impl Vars<'_> {
+    fn render(&self, template: &str) -> Result<String> {
+        let mut out = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| Error::Parse(format!("unclosed placeholder in '{}'", template)))?;
+            let name = &rest[start + 1..start + end];
+            let value = match name {
+                "rule" => Some(self.trigger.rule.clone()),
+                "key" => Some(self.trigger.key.clone()),
+                "count" => Some(self.trigger.count.to_string()),
+                path => lookup(&self.fields, path),
+            };
+            let value = value.ok_or_else(|| Error::Other(format!("event has no field '{}'", name)))?;
+            out.push_str(&value);
+            rest = &rest[start + end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+type FireHandler = Box<dyn Fn(&Firing) + Send + Sync>;
+type ErrorHandler = Box<dyn Fn(&Error) + Send + Sync>;
+
+/// Runs rules against log events.
+pub struct RuleEngine {
+    connection: Connection,
+    http: reqwest::Client,
+    config: RulesConfig,
+    rules: Vec<Rule>,
+    state: HashMap<String, RuleState>,
+    watch: Option<(PathBuf, Option<SystemTime>)>,
+    on_fire: Option<FireHandler>,
+    on_error: Option<ErrorHandler>,
+}
+
+impl RuleEngine {
+    /// Compile the rules. Fails on an invalid rule or a duplicate name.
+    pub fn new(connection: Connection, config: &RulesConfig) -> Result<Self> {
+        let mut engine = Self {
+            connection,
+            http: reqwest::Client::new(),
+            config: RulesConfig { sources: all_sources(), dry_run: false, rules: Vec::new() },
+            rules: Vec::new(),
+            state: HashMap::new(),
+            watch: None,
+            on_fire: None,
+            on_error: None,
+        };
+        engine.reload(config)?;
+        Ok(engine)
+    }
+
+    /// Replace the rules. On error the current rules stay in place.
+    ///
+    /// Rules that are unchanged keep their counters; changed rules start counting afresh.
+    /// Cooldowns are kept for every rule whose name still exists.
+    pub fn reload(&mut self, config: &RulesConfig) -> Result<()> {
+        let mut names = HashSet::new();
+        let mut rules = Vec::new();
+        for rule in &config.rules {
+            if !names.insert(rule.name.as_str()) {
+                return Err(Error::Parse(format!("duplicate rule name '{}'", rule.name)));
+            }
+            rules.push(Rule::compile(rule)?);
+        }
+        let mut state = HashMap::new();
+        for rule in &rules {
+            let name = &rule.config.name;
+            let Some(mut old) = self.state.remove(name) else { continue };
+            let unchanged = self.rules.iter().any(|r| r.config == rule.config);
+            if !unchanged {
+                old.seen.clear();
+            }
+            state.insert(name.clone(), old);
+        }
+        self.config = config.clone();
+        self.rules = rules;
+        self.state = state;
+        Ok(())
+    }
+
+    /// Reload from `path` in [`run`](Self::run) whenever the file changes.
+    pub fn watch(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        let path = path.into();
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        self.watch = Some((path, modified));
+        self
+    }
+
+    /// Reload if the watched file changed since the last load. Returns whether it did.
+    pub fn reload_if_changed(&mut self) -> Result<bool> {
+        let Some((path, loaded)) = &self.watch else { return Ok(false) };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified.is_none() || modified == *loaded {
+            return Ok(false);
+        }
+        let path = path.clone();
+        // Remember the new time even if the file is broken, so it isn't reparsed every poll
+        self.watch = Some((path.clone(), modified));
+        self.reload(&RulesConfig::from_path(&path)?)?;
+        Ok(true)
+    }
+
+    /// The names of the loaded rules.
+    pub fn rules(&self) -> Vec<&str> {
+        self.rules.iter().map(|r| r.config.name.as_str()).collect()
+    }
+
+    /// Called for every fired rule, including dry runs.
+    pub fn on_fire(&mut self, handler: impl Fn(&Firing) + Send + Sync + 'static) -> &mut Self {
+        self.on_fire = Some(Box::new(handler));
+        self
+    }
+
+    /// Called when reloading the watched file fails in [`run`](Self::run).
+    pub fn on_error(&mut self, handler: impl Fn(&Error) + Send + Sync + 'static) -> &mut Self {
+        self.on_error = Some(Box::new(handler));
+        self
+    }
+
+    /// Count an event that happened at `now` and return the rules it makes fire.
+    pub fn evaluate(&mut self, event: &LogEvent, now: Instant) -> Vec<Trigger> {
+        let fields = serde_json::to_value(event).unwrap_or_default();
+        let mut triggers = Vec::new();
+        for rule in &self.rules {
+            let Some(key) = rule.key(event, &fields) else { continue };
+            let config = &rule.config;
+            let state = self.state.entry(config.name.clone()).or_default();
+            state.prune(now, Duration::from_secs(config.window_secs));
+
+            let seen = state.seen.entry(key.clone()).or_default();
+            seen.push_back(now);
+            if seen.len() < config.count || state.cooldown_until.contains_key(&key) {
+                continue;
+            }
+            let count = seen.len();
+            state.seen.remove(&key);
+            if config.cooldown_secs > 0 {
+                state.cooldown_until.insert(key.clone(), now + Duration::from_secs(config.cooldown_secs));
+            }
+            triggers.push(Trigger {
+                rule: config.name.clone(),
+                key,
+                count,
+                event: event.clone(),
+                action: config.action.clone(),
+                dry_run: self.config.dry_run || config.dry_run,
+            });
+        }
+        triggers
+    }
+
+    /// Run the action of a fired rule.
+    pub async fn execute(&self, trigger: &Trigger) -> Firing {
+        let dry_run = trigger.dry_run || self.connection.context().is_dry_run();
+        let vars = Vars { trigger, fields: serde_json::to_value(&trigger.event).unwrap_or_default() };
+        let (action, result) = match trigger.action.render(&vars) {
+            Ok(action) if dry_run => (action, Ok(Value::Null)),
+            Ok(action) => {
+                let result = self.run_action(&action, trigger).await;
+                (action, result)
+            }
+            Err(e) => (trigger.action.clone(), Err(e)),
+        };
+        Firing { rule: trigger.rule.clone(), key: trigger.key.clone(), count: trigger.count, action, dry_run, result }
+    }
+
+    async fn run_action(&self, action: &RuleAction, trigger: &Trigger) -> Result<Value> {
+        match action {
+            RuleAction::Kill { nick, reason } => self.connection.user().kill(nick, reason).await,
+            RuleAction::ServerBan { mask, ban_type, duration, reason } => {
+                let reply = self.connection.server_ban().add(mask, ban_type, duration, reason).await?;
+                Ok(reply.unwrap_or(Value::Null))
+            }
+            RuleAction::ChannelMode { channel, modes, parameters } => {
+                self.connection.channel().set_mode(channel, modes, parameters).await
+            }
+            RuleAction::SetVhost { nick, vhost } => self.connection.user().set_vhost(nick, vhost).await,
+            RuleAction::Webhook { url } => {
+                let body = json!({"rule": trigger.rule, "key": trigger.key, "count": trigger.count, "event": trigger.event});
+                self.http
+                    .post(url)
+                    .json(&body)
+                    .timeout(Duration::from_secs(10))
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())?;
+                Ok(Value::Null)
+            }
+        }
+    }
+
+    /// Evaluate an event and run the actions of the rules it makes fire.
+    pub async fn process(&mut self, event: &LogEvent) -> Vec<Firing> {
+        let mut firings = Vec::new();
+        for trigger in self.evaluate(event, Instant::now()) {
+            let firing = self.execute(&trigger).await;
+            if let Some(handler) = &self.on_fire {
+                handler(&firing);
+            }
+            firings.push(firing);
+        }
+        firings
+    }
+
+    /// Subscribe to the configured sources and process events until the connection fails,
+    /// reloading the watched file when it changes.
+    ///
+    /// This consumes the notifications of the connection, so give the engine its own.
+    pub async fn run(&mut self) -> Result<()> {
+        let log = self.connection.log();
+        let mut sources = self.config.sources.clone();
+        log.subscribe(sources.clone()).await?;
+        loop {
+            if let Some(event) = log.next_event(POLL_INTERVAL).await? {
+                self.process(&event).await;
+            }
+            match self.reload_if_changed() {
+                Ok(true) if self.config.sources != sources => {
+                    sources = self.config.sources.clone();
+                    log.subscribe(sources.clone()).await?;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    if let Some(handler) = &self.on_error {
+                        handler(&e);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -41,4 +41,15 @@ impl Server {
             Ok(None)
         }
     }
-}
\ No newline at end of file
+
+    /// Rehash a server (reload its configuration).
+    pub async fn rehash(&self, server: Option<&str>) -> Result<serde_json::Value> {
+        let params = if let Some(srv) = server {
+            serde_json::json!({"server": srv})
+        } else {
+            serde_json::Value::Null
+        };
+
+        self.connection.query("server.rehash", params, false).await
+    }
+}
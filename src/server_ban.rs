@@ -1,8 +1,52 @@
 //! Server ban operations module.
 
+use crate::ban_mask::BanMask;
+use crate::client::Client;
 use crate::connection::Connection;
 use crate::error::Result;
+use crate::tkl::Tkl;
 use serde_json;
+use std::collections::BTreeMap;
+
+/// The result of [`ServerBan::preview`]: who a ban would affect if it were added now.
+#[derive(Debug, Clone)]
+pub struct BanPreview {
+    pub mask: BanMask,
+    pub ban_type: String,
+    /// All connected clients matching the mask, including excepted ones.
+    pub affected: Vec<Client>,
+    /// Nicks of affected clients, grouped by server name.
+    pub by_server: BTreeMap<String, Vec<String>>,
+    /// Nicks of affected clients, grouped by GeoIP country code (`??` if unknown).
+    pub by_country: BTreeMap<String, Vec<String>>,
+    pub warnings: Vec<PreviewWarning>,
+}
+
+/// Something in a [`BanPreview`] that deserves a second look before adding the ban.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PreviewWarning {
+    /// The ban would hit an IRC Operator.
+    Oper { nick: String },
+    /// The ban would hit a network service.
+    Service { nick: String },
+    /// The client matches, but an existing ban exception protects it.
+    Excepted { nick: String, exception: String },
+}
+
+impl BanPreview {
+    /// Number of clients that would actually be banned (matching and not excepted).
+    pub fn effective_count(&self) -> usize {
+        self.affected
+            .iter()
+            .filter(|c| {
+                !self
+                    .warnings
+                    .iter()
+                    .any(|w| matches!(w, PreviewWarning::Excepted { nick, .. } if *nick == c.name))
+            })
+            .count()
+    }
+}
 
 /// ServerBan handler for server ban operations.
 #[derive(Clone)]
@@ -46,6 +90,62 @@ impl ServerBan {
         }
     }
 
+    /// Preview which connected users a ban would affect, without adding it.
+    ///
+    /// The mask is validated locally with [`BanMask::parse`] and matched against `user.list`.
+    /// Existing ban exceptions covering `ban_type` are taken into account. Z-lines only
+    /// match on IP address, see [`BanMask::matches_ip`].
+    pub async fn preview(&self, mask: &str, ban_type: &str) -> Result<BanPreview> {
+        let mask = BanMask::parse(mask)?;
+        let clients = self.connection.user().get_all_clients(2).await?;
+        let exceptions: Vec<Tkl> = serde_json::from_value(self.connection.server_ban_exception().get_all().await?)?;
+        let exceptions: Vec<(BanMask, &Tkl)> = exceptions
+            .iter()
+            .filter(|e| e.excepts(ban_type))
+            .filter_map(|e| BanMask::parse(&e.name).ok().map(|m| (m, e)))
+            .collect();
+
+        let mut preview = BanPreview {
+            mask,
+            ban_type: ban_type.to_string(),
+            affected: Vec::new(),
+            by_server: BTreeMap::new(),
+            by_country: BTreeMap::new(),
+            warnings: Vec::new(),
+        };
+
+        let ip_only = matches!(ban_type, "zline" | "gzline");
+        let hits = |c: &Client| if ip_only { preview.mask.matches_ip(c) } else { preview.mask.matches(c) };
+        for client in clients.into_iter().filter(|c| hits(c)) {
+            let nick = client.name.clone();
+            if client.is_oper() {
+                preview.warnings.push(PreviewWarning::Oper { nick: nick.clone() });
+            }
+            if client.is_service() {
+                preview.warnings.push(PreviewWarning::Service { nick: nick.clone() });
+            }
+            if let Some((_, exception)) = exceptions.iter().find(|(m, _)| m.matches(&client)) {
+                preview.warnings.push(PreviewWarning::Excepted {
+                    nick: nick.clone(),
+                    exception: exception.name.clone(),
+                });
+            }
+            preview
+                .by_server
+                .entry(client.servername().unwrap_or("??").to_string())
+                .or_default()
+                .push(nick.clone());
+            preview
+                .by_country
+                .entry(client.country_code().unwrap_or("??").to_string())
+                .or_default()
+                .push(nick);
+            preview.affected.push(client);
+        }
+
+        Ok(preview)
+    }
+
     /// Get a list of all bans.
     pub async fn get_all(&self) -> Result<serde_json::Value> {
         let result = self.connection.query("server_ban.list", serde_json::Value::Null, false).await?;
@@ -0,0 +1,687 @@
+//! Forwarding log events to syslog, files and webhooks.
+//!
+//! A [`LogRouter`] holds named [`LogSink`]s and a table of [`Route`]s. Every event is sent
+//! to the sinks of all routes whose filter matches it, each sink at most once.
+//!
+//! The router can be built by hand or from a [`RouterConfig`] document:
+//!
+//! ```toml
+//! sources = ["all", "!debug"]
+//!
+//! [sinks.syslog]
+//! type = "syslog"
+//! transport = "udp"
+//! address = "127.0.0.1:514"
+//!
+//! [sinks.archive]
+//! type = "file"
+//! path = "/var/log/unrealircd/events.jsonl"
+//! max_bytes = 10485760
+//! keep = 5
+//!
+//! [sinks.alerts]
+//! type = "webhook"
+//! url = "http://127.0.0.1:9000/irc-events"
+//! batch_size = 20
+//!
+//! [[routes]]
+//! sinks = ["archive"]
+//!
+//! [[routes]]
+//! level = "warn"
+//! subsystems = ["link", "oper"]
+//! sinks = ["syslog", "alerts"]
+//! ```
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! # use unrealircd_rpc::sink::RouterConfig;
+//! # async fn example(conn: Connection) -> unrealircd_rpc::error::Result<()> {
+//! let config = RouterConfig::from_path("relay.toml")?;
+//! let mut router = config.build().await?;
+//! router.on_error(|sink, e| eprintln!("{}: {}", sink, e));
+//! router.run(&conn, config.sources.clone()).await
+//! # }
+//! ```
+
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+use crate::log_event::{severity, LogEvent};
+use crate::reconcile::StateFormat;
+use crate::tail::LogFilter;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+
+/// Somewhere log events can be sent.
+#[async_trait]
+pub trait LogSink: Send {
+    /// Send (or queue) one event.
+    async fn send(&mut self, event: &LogEvent) -> Result<()>;
+
+    /// Send anything queued. Called regularly by [`LogRouter::run`].
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn io_error(what: &str, e: std::io::Error) -> Error {
+    Error::Other(format!("{}: {}", what, e))
+}
+
+/// How syslog messages are sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogTransport {
+    /// One datagram per message.
+    Udp,
+    /// Octet-counting framing (RFC 6587).
+    Tcp,
+    /// A local datagram socket such as `/dev/log`.
+    Unix,
+}
+
+enum SyslogSocket {
+    Udp(tokio::net::UdpSocket),
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixDatagram),
+}
+
+/// Sends events as RFC 5424 syslog messages.
+///
+/// The event id becomes the MSGID and the level is mapped to the syslog severity. The
+/// subsystem and level go in a structured data element named by `sd_id`.
+pub struct SyslogSink {
+    transport: SyslogTransport,
+    address: String,
+    socket: Option<SyslogSocket>,
+    /// Syslog facility; the default is 16 (local0).
+    pub facility: u8,
+    /// HOSTNAME field; `-` when empty.
+    pub hostname: String,
+    /// APP-NAME field.
+    pub app_name: String,
+    /// SD-ID of the element with the subsystem and level, in the `name@<PEN>` form RFC 5424
+    /// requires for private ones. The default uses 32473, the PEN reserved for documentation;
+    /// set your own organisation's.
+    pub sd_id: String,
+}
+
+impl SyslogSink {
+    /// Connect to a syslog server. `address` is `host:port`, or a socket path for
+    /// [`SyslogTransport::Unix`].
+    pub async fn connect(transport: SyslogTransport, address: &str) -> Result<Self> {
+        let mut sink = Self {
+            transport,
+            address: address.to_string(),
+            socket: None,
+            facility: 16,
+            hostname: String::new(),
+            app_name: "unrealircd".to_string(),
+            sd_id: "unrealircd@32473".to_string(),
+        };
+        sink.socket = Some(sink.open().await?);
+        Ok(sink)
+    }
+
+    async fn open(&self) -> Result<SyslogSocket> {
+        let what = format!("syslog {}", self.address);
+        match self.transport {
+            SyslogTransport::Udp => {
+                let target = tokio::net::lookup_host(&self.address)
+                    .await
+                    .map_err(|e| io_error(&what, e))?
+                    .next()
+                    .ok_or_else(|| Error::Other(format!("{}: no address found", what)))?;
+                let local = if target.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+                let socket = tokio::net::UdpSocket::bind(local).await.map_err(|e| io_error(&what, e))?;
+                socket.connect(target).await.map_err(|e| io_error(&what, e))?;
+                Ok(SyslogSocket::Udp(socket))
+            }
+            SyslogTransport::Tcp => {
+                let stream = tokio::net::TcpStream::connect(&self.address).await.map_err(|e| io_error(&what, e))?;
+                Ok(SyslogSocket::Tcp(stream))
+            }
+            #[cfg(unix)]
+            SyslogTransport::Unix => {
+                let socket = tokio::net::UnixDatagram::unbound().map_err(|e| io_error(&what, e))?;
+                socket.connect(&self.address).map_err(|e| io_error(&what, e))?;
+                Ok(SyslogSocket::Unix(socket))
+            }
+            #[cfg(not(unix))]
+            SyslogTransport::Unix => Err(Error::Other("UNIX sockets are not supported on this platform".to_string())),
+        }
+    }
+
+    /// Format an event as an RFC 5424 message.
+    pub fn format(&self, event: &LogEvent) -> String {
+        let severity = match severity(&event.level) {
+            Some(0) => 7,
+            Some(1) => 6,
+            Some(2) => 4,
+            Some(3) => 3,
+            Some(_) => 2,
+            None => 5,
+        };
+        let priority = u32::from(self.facility) * 8 + severity;
+        format!(
+            "<{}>1 {} {} {} - {} [origin software=\"UnrealIRCd\"][{} subsystem=\"{}\" level=\"{}\"] {}",
+            priority,
+            header_field(&event.timestamp, 255),
+            header_field(&self.hostname, 255),
+            header_field(&self.app_name, 48),
+            header_field(&event.event_id, 32),
+            sd_name(&self.sd_id),
+            sd_escape(&event.subsystem),
+            sd_escape(&event.level),
+            event.msg
+        )
+    }
+
+    async fn write(&mut self, message: &str) -> Result<()> {
+        let what = format!("syslog {}", self.address);
+        let socket = match &mut self.socket {
+            Some(socket) => socket,
+            None => self.socket.insert(self.open().await?),
+        };
+        let result = match socket {
+            SyslogSocket::Udp(socket) => socket.send(message.as_bytes()).await.map(|_| ()),
+            SyslogSocket::Tcp(stream) => {
+                let framed = format!("{} {}", message.len(), message);
+                stream.write_all(framed.as_bytes()).await
+            }
+            #[cfg(unix)]
+            SyslogSocket::Unix(socket) => socket.send(message.as_bytes()).await.map(|_| ()),
+        };
+        if let Err(e) = result {
+            // Reconnect on the next message
+            self.socket = None;
+            return Err(io_error(&what, e));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LogSink for SyslogSink {
+    async fn send(&mut self, event: &LogEvent) -> Result<()> {
+        let message = self.format(event);
+        if self.write(&message).await.is_ok() {
+            return Ok(());
+        }
+        // One retry on a fresh connection, e.g. after the syslog server restarted
+        self.write(&message).await
+    }
+}
+
+/// A syslog header field: printable ASCII without spaces, `-` when empty.
+fn header_field(value: &str, max: usize) -> String {
+    let field: String = value.chars().filter(|c| c.is_ascii_graphic()).take(max).collect();
+    if field.is_empty() {
+        "-".to_string()
+    } else {
+        field
+    }
+}
+
+/// An SD-NAME: printable ASCII other than `=`, space, `]` and `"`, at most 32 characters.
+fn sd_name(value: &str) -> String {
+    value.chars().filter(|c| c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"')).take(32).collect()
+}
+
+fn sd_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+/// Appends events as JSON lines to a file, rotating it by size.
+///
+/// When the file would grow beyond `max_bytes`, it is renamed to `<path>.1` (shifting older
+/// files up to `<path>.<keep>`, and deleting the oldest) and a new file is started.
+///
+/// The file is written on the blocking thread pool, so a slow disk doesn't stall the runtime.
+pub struct FileSink {
+    file: Arc<Mutex<RotatingFile>>,
+}
+
+impl FileSink {
+    /// Create a sink writing to `path`. A `max_bytes` of 0 disables rotation.
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64, keep: usize) -> Self {
+        let file = RotatingFile { path: path.into(), max_bytes, keep, file: None, size: 0 };
+        Self { file: Arc::new(Mutex::new(file)) }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: Option<std::fs::File>,
+    size: u64,
+}
+
+impl RotatingFile {
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file = None;
+        let what = self.path.display().to_string();
+        if self.keep == 0 {
+            return std::fs::remove_file(&self.path).map_err(|e| io_error(&what, e));
+        }
+        let _ = std::fs::remove_file(self.rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(n + 1)).map_err(|e| io_error(&what, e))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1)).map_err(|e| io_error(&what, e))
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        let what = self.path.display().to_string();
+        let len = line.len() as u64 + 1;
+        if self.file.is_none() {
+            self.size = std::fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        }
+        if self.max_bytes > 0 && self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+            self.size = 0;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .map_err(|e| io_error(&what, e))?;
+                self.file.insert(file)
+            }
+        };
+        writeln!(file, "{}", line).map_err(|e| io_error(&what, e))?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LogSink for FileSink {
+    async fn send(&mut self, event: &LogEvent) -> Result<()> {
+        let line = serde_json::to_string(event)?;
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || file.lock().unwrap().write_line(&line))
+            .await
+            .map_err(|e| Error::Other(format!("file sink: {}", e)))?
+    }
+}
+
+/// POSTs events as JSON arrays to an HTTP endpoint.
+///
+/// Events are queued and sent once `batch_size` are waiting, or on [`flush`](LogSink::flush).
+/// After a failed POST, nothing is posted until a backoff delay has passed, so an endpoint
+/// that is down doesn't hold up the other sinks. The delay starts at `backoff` and doubles
+/// with every further failure, up to `max_backoff`. Meanwhile events stay queued, up to
+/// `max_queue` events (older ones are dropped).
+pub struct WebhookSink {
+    client: reqwest::Client,
+    url: String,
+    queue: VecDeque<LogEvent>,
+    /// When the next POST may be made, after a failure.
+    retry_at: Option<Instant>,
+    /// The delay after the next failure.
+    delay: Duration,
+    /// Events per POST.
+    pub batch_size: usize,
+    /// Delay after the first failed POST; doubled for each further failure.
+    pub backoff: Duration,
+    /// Longest delay between POSTs while the endpoint keeps failing.
+    pub max_backoff: Duration,
+    /// Timeout of a POST.
+    pub timeout: Duration,
+    /// Maximum number of queued events.
+    pub max_queue: usize,
+}
+
+impl WebhookSink {
+    /// Create a sink posting to `url`.
+    pub fn new(url: &str) -> Self {
+        let backoff = Duration::from_millis(500);
+        Self {
+            client: reqwest::Client::new(),
+            url: url.to_string(),
+            queue: VecDeque::new(),
+            retry_at: None,
+            delay: backoff,
+            batch_size: 50,
+            backoff,
+            max_backoff: Duration::from_secs(60),
+            timeout: Duration::from_secs(5),
+            max_queue: 10_000,
+        }
+    }
+
+    /// Number of events waiting to be sent.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    async fn post(&mut self, batch: &[LogEvent]) -> Result<()> {
+        let result = self
+            .client
+            .post(&self.url)
+            .json(batch)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        match result {
+            Ok(_) => {
+                self.retry_at = None;
+                self.delay = self.backoff;
+                Ok(())
+            }
+            Err(e) => {
+                let delay = if self.retry_at.is_some() { self.delay } else { self.backoff };
+                self.retry_at = Some(Instant::now() + delay);
+                self.delay = (delay * 2).min(self.max_backoff);
+                Err(e.into())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LogSink for WebhookSink {
+    async fn send(&mut self, event: &LogEvent) -> Result<()> {
+        if self.queue.len() >= self.max_queue {
+            self.queue.pop_front();
+        }
+        self.queue.push_back(event.clone());
+        if self.queue.len() >= self.batch_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return Ok(());
+        }
+        while !self.queue.is_empty() {
+            let n = self.queue.len().min(self.batch_size.max(1));
+            let batch: Vec<LogEvent> = self.queue.iter().take(n).cloned().collect();
+            self.post(&batch).await?;
+            self.queue.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+/// A routing rule: events matching `filter` go to the named sinks.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub filter: LogFilter,
+    pub sinks: Vec<String>,
+}
+
+type ErrorHandler = Box<dyn Fn(&str, &Error) + Send + Sync>;
+
+/// Routes log events to sinks.
+#[derive(Default)]
+pub struct LogRouter {
+    sinks: BTreeMap<String, Box<dyn LogSink>>,
+    routes: Vec<Route>,
+    on_error: Option<ErrorHandler>,
+    /// How often [`run`](Self::run) flushes the sinks when idle.
+    pub flush_interval: Option<Duration>,
+}
+
+impl LogRouter {
+    /// Create an empty router.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a named sink.
+    pub fn add_sink(&mut self, name: &str, sink: impl LogSink + 'static) -> &mut Self {
+        self.sinks.insert(name.to_string(), Box::new(sink));
+        self
+    }
+
+    /// Add a route. Fails if it names an unknown sink.
+    pub fn add_route(&mut self, filter: LogFilter, sinks: &[&str]) -> Result<&mut Self> {
+        if let Some(unknown) = sinks.iter().find(|s| !self.sinks.contains_key(**s)) {
+            return Err(Error::Other(format!("route refers to unknown sink '{}'", unknown)));
+        }
+        self.routes.push(Route { filter, sinks: sinks.iter().map(|s| s.to_string()).collect() });
+        Ok(self)
+    }
+
+    /// Called with the sink name and error when a sink fails in [`run`](Self::run).
+    /// Without a handler, sink errors are ignored.
+    pub fn on_error(&mut self, handler: impl Fn(&str, &Error) + Send + Sync + 'static) -> &mut Self {
+        self.on_error = Some(Box::new(handler));
+        self
+    }
+
+    /// The names of the sinks an event is routed to.
+    pub fn targets(&self, event: &LogEvent) -> Vec<&str> {
+        let mut targets: Vec<&str> = Vec::new();
+        for route in self.routes.iter().filter(|r| r.filter.matches(event)) {
+            for sink in &route.sinks {
+                if !targets.contains(&sink.as_str()) {
+                    targets.push(sink);
+                }
+            }
+        }
+        targets
+    }
+
+    /// Send an event to all sinks it is routed to. All sinks are tried; the errors are
+    /// returned with the sink names.
+    pub async fn dispatch(&mut self, event: &LogEvent) -> Vec<(String, Error)> {
+        let targets: Vec<String> = self.targets(event).into_iter().map(str::to_string).collect();
+        let mut errors = Vec::new();
+        for name in targets {
+            if let Some(sink) = self.sinks.get_mut(&name) {
+                if let Err(e) = sink.send(event).await {
+                    errors.push((name, e));
+                }
+            }
+        }
+        errors
+    }
+
+    /// Flush all sinks.
+    pub async fn flush(&mut self) -> Vec<(String, Error)> {
+        let mut errors = Vec::new();
+        for (name, sink) in &mut self.sinks {
+            if let Err(e) = sink.flush().await {
+                errors.push((name.clone(), e));
+            }
+        }
+        errors
+    }
+
+    /// Subscribe to `sources` and forward events until the connection fails.
+    ///
+    /// When it fails, the sinks are flushed once more before the error is returned. Events a
+    /// webhook holds back while backing off stay queued in the router: they go out with the
+    /// next `run` or [`flush`](Self::flush), and are only lost if the router is dropped.
+    pub async fn run(&mut self, connection: &Connection, sources: Vec<String>) -> Result<()> {
+        let log = connection.log();
+        log.subscribe(sources).await?;
+        let interval = self.flush_interval.unwrap_or(Duration::from_secs(5));
+        let mut last_flush = tokio::time::Instant::now();
+        loop {
+            let errors = match log.next_event(interval).await {
+                Ok(Some(event)) => self.dispatch(&event).await,
+                Ok(None) => Vec::new(),
+                Err(e) => {
+                    let errors = self.flush().await;
+                    self.report(errors);
+                    return Err(e);
+                }
+            };
+            self.report(errors);
+            if last_flush.elapsed() >= interval {
+                let errors = self.flush().await;
+                self.report(errors);
+                last_flush = tokio::time::Instant::now();
+            }
+        }
+    }
+
+    fn report(&self, errors: Vec<(String, Error)>) {
+        if let Some(handler) = &self.on_error {
+            for (sink, error) in errors {
+                handler(&sink, &error);
+            }
+        }
+    }
+}
+
+/// A sink in a [`RouterConfig`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkConfig {
+    Syslog {
+        transport: SyslogTransport,
+        address: String,
+        #[serde(default)]
+        facility: Option<u8>,
+        #[serde(default)]
+        hostname: Option<String>,
+        #[serde(default)]
+        app_name: Option<String>,
+        #[serde(default)]
+        sd_id: Option<String>,
+    },
+    File {
+        path: PathBuf,
+        #[serde(default)]
+        max_bytes: u64,
+        #[serde(default)]
+        keep: usize,
+    },
+    Webhook {
+        url: String,
+        #[serde(default)]
+        batch_size: Option<usize>,
+        /// Longest delay between POSTs while the endpoint keeps failing.
+        #[serde(default)]
+        max_backoff_secs: Option<u64>,
+    },
+}
+
+/// A route in a [`RouterConfig`]. Criteria that are left out match everything.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RouteConfig {
+    #[serde(default)]
+    pub level: Option<String>,
+    #[serde(default)]
+    pub subsystems: Vec<String>,
+    #[serde(default)]
+    pub event_ids: Vec<String>,
+    /// Regular expression on the message.
+    #[serde(default)]
+    pub message: Option<String>,
+    pub sinks: Vec<String>,
+}
+
+fn all_sources() -> Vec<String> {
+    vec!["all".to_string()]
+}
+
+/// A router description: the `log.subscribe` sources, the sinks and the routes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouterConfig {
+    #[serde(default = "all_sources")]
+    pub sources: Vec<String>,
+    #[serde(default)]
+    pub flush_interval_secs: Option<u64>,
+    pub sinks: BTreeMap<String, SinkConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+}
+
+impl RouterConfig {
+    /// Parse a router description.
+    pub fn parse(input: &str, format: StateFormat) -> Result<Self> {
+        format.deserialize(input)
+    }
+
+    /// Read a router description, picking the format from the file extension.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let format = StateFormat::from_path(path)
+            .ok_or_else(|| Error::Parse(format!("{}: unknown file extension", path.display())))?;
+        let input = std::fs::read_to_string(path).map_err(|e| io_error(&path.display().to_string(), e))?;
+        Self::parse(&input, format)
+    }
+
+    /// Open all sinks and build the router.
+    pub async fn build(&self) -> Result<LogRouter> {
+        let mut router = LogRouter::new();
+        router.flush_interval = self.flush_interval_secs.map(Duration::from_secs);
+        for (name, sink) in &self.sinks {
+            match sink {
+                SinkConfig::Syslog { transport, address, facility, hostname, app_name, sd_id } => {
+                    let mut syslog = SyslogSink::connect(transport.clone(), address).await?;
+                    if let Some(facility) = facility {
+                        syslog.facility = *facility;
+                    }
+                    if let Some(hostname) = hostname {
+                        syslog.hostname = hostname.clone();
+                    }
+                    if let Some(app_name) = app_name {
+                        syslog.app_name = app_name.clone();
+                    }
+                    if let Some(sd_id) = sd_id {
+                        syslog.sd_id = sd_id.clone();
+                    }
+                    router.add_sink(name, syslog);
+                }
+                SinkConfig::File { path, max_bytes, keep } => {
+                    router.add_sink(name, FileSink::new(path, *max_bytes, *keep));
+                }
+                SinkConfig::Webhook { url, batch_size, max_backoff_secs } => {
+                    let mut webhook = WebhookSink::new(url);
+                    if let Some(batch_size) = batch_size {
+                        webhook.batch_size = *batch_size;
+                    }
+                    if let Some(secs) = max_backoff_secs {
+                        webhook.max_backoff = Duration::from_secs(*secs);
+                    }
+                    router.add_sink(name, webhook);
+                }
+            }
+        }
+        for route in &self.routes {
+            let mut filter = LogFilter {
+                min_level: route.level.clone(),
+                subsystems: route.subsystems.clone(),
+                event_ids: route.event_ids.clone(),
+                message: None,
+            };
+            if let Some(pattern) = &route.message {
+                filter.set_message(pattern)?;
+            }
+            let sinks: Vec<&str> = route.sinks.iter().map(String::as_str).collect();
+            router.add_route(filter, &sinks)?;
+        }
+        Ok(router)
+    }
+}
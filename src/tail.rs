@@ -0,0 +1,260 @@
+//! Following the log: history first, then live events, with filtering and formatting.
+//!
+//! ```rust,no_run
+//! use unrealircd_rpc::tail::{LogFilter, LogFormat, LogTail};
+//! # async fn example(conn: unrealircd_rpc::Connection) -> unrealircd_rpc::error::Result<()> {
+//! let mut filter = LogFilter { min_level: Some("warn".to_string()), ..Default::default() };
+//! filter.set_message(r"(?i)flood")?;
+//!
+//! let mut tail = LogTail::start(&conn, vec!["all".to_string()], Some(50), filter).await?;
+//! loop {
+//!     let event = tail.next().await?;
+//!     println!("{}", LogFormat::Color.format(&event));
+//! }
+//! # }
+//! ```
+
+use crate::ban_mask::wildcard_match;
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+use crate::log::Log;
+use crate::log_event::{severity, LogEvent};
+use regex::Regex;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long [`LogTail::next`] holds the connection while waiting for an event.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Client-side filter on log events. Empty criteria match everything.
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    /// Minimum level: `debug`, `info`, `warn`, `error` or `fatal`.
+    pub min_level: Option<String>,
+    /// Subsystems to show (case-insensitive).
+    pub subsystems: Vec<String>,
+    /// Glob patterns for the event id, such as `*_FLOOD`.
+    pub event_ids: Vec<String>,
+    /// Regular expression the message must match.
+    pub message: Option<Regex>,
+}
+
+impl LogFilter {
+    /// Set the message regex.
+    pub fn set_message(&mut self, pattern: &str) -> Result<()> {
+        let regex = Regex::new(pattern).map_err(|e| Error::Parse(e.to_string()))?;
+        self.message = Some(regex);
+        Ok(())
+    }
+
+    /// Whether an event passes the filter.
+    pub fn matches(&self, event: &LogEvent) -> bool {
+        if let Some(min) = self.min_level.as_deref().and_then(severity) {
+            // Unknown levels are never hidden
+            if event.severity().is_some_and(|s| s < min) {
+                return false;
+            }
+        }
+        if !self.subsystems.is_empty() && !self.subsystems.iter().any(|s| s.eq_ignore_ascii_case(&event.subsystem)) {
+            return false;
+        }
+        if !self.event_ids.is_empty() && !self.event_ids.iter().any(|p| wildcard_match(p, &event.event_id)) {
+            return false;
+        }
+        self.message.as_ref().is_none_or(|re| re.is_match(&event.msg))
+    }
+}
+
+/// How log events are rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `[timestamp] subsystem.EVENT_ID [level] message`, like `ircd.log`.
+    Text,
+    /// Like `Text`, with ANSI colours by level.
+    Color,
+    /// One JSON object per line.
+    JsonLines,
+    /// `key=value` pairs.
+    Logfmt,
+}
+
+impl LogFormat {
+    /// Render one event, without a trailing newline.
+    pub fn format(&self, event: &LogEvent) -> String {
+        match self {
+            LogFormat::Text => {
+                format!("[{}] {}.{} [{}] {}", event.timestamp, event.subsystem, event.event_id, event.level, event.msg)
+            }
+            LogFormat::Color => {
+                let colour = match severity(&event.level) {
+                    Some(0) => "2",
+                    Some(1) => "32",
+                    Some(2) => "33",
+                    Some(3) => "31",
+                    Some(_) => "1;31",
+                    None => "0",
+                };
+                format!(
+                    "\x1b[2m[{}]\x1b[0m \x1b[36m{}.{}\x1b[0m \x1b[{}m[{}]\x1b[0m {}",
+                    event.timestamp, event.subsystem, event.event_id, colour, event.level, event.msg
+                )
+            }
+            LogFormat::JsonLines => serde_json::to_string(event).unwrap_or_default(),
+            LogFormat::Logfmt => {
+                let mut line = String::new();
+                for (key, value) in [
+                    ("ts", &event.timestamp),
+                    ("level", &event.level),
+                    ("subsystem", &event.subsystem),
+                    ("event_id", &event.event_id),
+                    ("msg", &event.msg),
+                ] {
+                    if !line.is_empty() {
+                        line.push(' ');
+                    }
+                    let _ = write!(line, "{}={}", key, logfmt_value(value));
+                }
+                line
+            }
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "color" | "colour" => Ok(LogFormat::Color),
+            "json" | "jsonl" | "json-lines" => Ok(LogFormat::JsonLines),
+            "logfmt" => Ok(LogFormat::Logfmt),
+            _ => Err(Error::Parse(format!("unknown log format '{}'", s))),
+        }
+    }
+}
+
+fn logfmt_value(value: &str) -> String {
+    let plain = !value.is_empty() && !value.chars().any(|c| c.is_whitespace() || c == '"' || c == '=' || c == '\\');
+    if plain {
+        return value.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Follows the log: replays history from `log.list`, then continues with live events.
+///
+/// The subscription is made before the history is fetched, so no event is lost in between.
+/// Live events that were already part of the history are skipped.
+pub struct LogTail {
+    log: Log,
+    filter: LogFilter,
+    pending: VecDeque<LogEvent>,
+    /// History events that may arrive again as live events, by [`ReplayKey`], with their count
+    overlap: HashMap<ReplayKey, usize>,
+    /// Timestamp of the newest history event; later live events can't be replays
+    overlap_until: String,
+}
+
+/// What identifies a log event when comparing live events against the history: the
+/// timestamp, event ID and message. Other fields may be serialized differently.
+type ReplayKey = (String, String, String);
+
+fn replay_key(event: &LogEvent) -> ReplayKey {
+    (event.timestamp.clone(), event.event_id.clone(), event.msg.clone())
+}
+
+impl LogTail {
+    /// Subscribe to `sources` and fetch the history.
+    ///
+    /// `history` limits how many past (matching) events are replayed; `None` replays all
+    /// that the server keeps, `Some(0)` none.
+    pub async fn start(conn: &Connection, sources: Vec<String>, history: Option<usize>, filter: LogFilter) -> Result<Self> {
+        let log = conn.log();
+        log.subscribe(sources.clone()).await?;
+        let past = log.get_all_events(Some(sources)).await?;
+
+        let mut overlap = HashMap::new();
+        for event in &past {
+            *overlap.entry(replay_key(event)).or_insert(0) += 1;
+        }
+        let overlap_until = past.iter().map(|e| e.timestamp.clone()).max().unwrap_or_default();
+        let mut pending: VecDeque<LogEvent> = past.into_iter().filter(|e| filter.matches(e)).collect();
+        if let Some(limit) = history {
+            let skip = pending.len().saturating_sub(limit);
+            pending.drain(..skip);
+        }
+
+        Ok(Self { log, filter, pending, overlap, overlap_until })
+    }
+
+    /// Wait for the next matching event.
+    pub async fn next(&mut self) -> Result<LogEvent> {
+        loop {
+            if let Some(event) = self.next_timeout(POLL_INTERVAL).await? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for the next matching event.
+    pub async fn next_timeout(&mut self, timeout: Duration) -> Result<Option<LogEvent>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Ok(Some(event));
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            let Some(event) = self.log.next_event(remaining).await? else {
+                return Ok(None);
+            };
+            if self.is_replayed(&event) || !self.filter.matches(&event) {
+                continue;
+            }
+            return Ok(Some(event));
+        }
+    }
+
+    /// Unsubscribe from the log.
+    pub async fn stop(self) -> Result<()> {
+        self.log.unsubscribe().await?;
+        Ok(())
+    }
+
+    /// Whether a live event was already returned by `log.list`. Only live events up to the
+    /// newest history timestamp can be; after the first later one, checking stops.
+    fn is_replayed(&mut self, event: &LogEvent) -> bool {
+        if self.overlap.is_empty() {
+            return false;
+        }
+        if event.timestamp > self.overlap_until {
+            self.overlap.clear();
+            return false;
+        }
+        let key = replay_key(event);
+        match self.overlap.get_mut(&key) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 0 {
+                    self.overlap.remove(&key);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,1285 @@
+//! In-process mock UnrealIRCd JSON-RPC server for tests.
+//!
+//! [`MockServer`] listens on an ephemeral local port and speaks the same WebSocket
+//! JSON-RPC protocol as UnrealIRCd. It keeps users, channels, servers, TKLs and log
+//! events in memory and implements the methods used by the handlers in this crate,
+//! with the same result shapes and error codes. Joins, parts, kicks, nick and mode changes
+//! and quits made through the API are logged as events, like the IRCd does.
+//!
+//! [`ReplayServer`] serves a [`Fixture`](crate::record::Fixture) captured with a
+//! [`Recorder`](crate::record::Recorder) back to the client, to catch regressions in how
+//! requests are built without any IRCd at all.
+//!
+//! This module is available with the `testing` cargo feature.
+//!
+//! ```rust,no_run
+//! use unrealircd_rpc::testing::{MockServer, MockState};
+//!
+//! # async fn example() -> unrealircd_rpc::error::Result<()> {
+//! let mut state = MockState::new();
+//! state.add_user("alice", "alice", "host.example.net", "198.51.100.23");
+//! let server = MockServer::start_with(state, "rpc:secret").await?;
+//!
+//! let conn = server.connect().await?;
+//! let users = conn.user().get_all(2).await?;
+//! assert_eq!(users.as_array().unwrap().len(), 1);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::ban_mask::{wildcard_match, BanMask};
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+use crate::record::{json_diff, Fixture, RecordedEntry};
+use crate::tkl::Tkl;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// JSON-RPC error codes used by UnrealIRCd.
+pub mod error_code {
+    pub const PARSE_ERROR: i64 = -32700;
+    pub const INVALID_REQUEST: i64 = -32600;
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+    pub const NOT_FOUND: i64 = -1000;
+    pub const ALREADY_EXISTS: i64 = -1001;
+    pub const INVALID_NAME: i64 = -1002;
+    pub const USER_NOT_IN_CHANNEL: i64 = -1003;
+}
+
+/// The methods implemented by the mock server.
+pub const METHODS: &[&str] = &[
+    "rpc.info",
+    "rpc.set_issuer",
+    "rpc.add_timer",
+    "rpc.del_timer",
+    "stats.get",
+    "server.list",
+    "server.get",
+    "server.rehash",
+    "user.list",
+    "user.get",
+    "user.set_nick",
+    "user.set_username",
+    "user.set_realname",
+    "user.set_vhost",
+    "user.set_mode",
+    "user.set_snomask",
+    "user.set_oper",
+    "user.join",
+    "user.part",
+    "user.quit",
+    "user.kill",
+    "channel.list",
+    "channel.get",
+    "channel.set_mode",
+    "channel.set_topic",
+    "channel.kick",
+    "server_ban.add",
+    "server_ban.del",
+    "server_ban.get",
+    "server_ban.list",
+    "server_ban_exception.add",
+    "server_ban_exception.del",
+    "server_ban_exception.get",
+    "server_ban_exception.list",
+    "name_ban.add",
+    "name_ban.del",
+    "name_ban.get",
+    "name_ban.list",
+    "spamfilter.add",
+    "spamfilter.del",
+    "spamfilter.get",
+    "spamfilter.list",
+    "log.subscribe",
+    "log.unsubscribe",
+    "log.list",
+];
+
+/// A request received by the mock server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedRequest {
+    pub method: String,
+    pub params: Value,
+    /// The issuer in effect for the session when the request arrived.
+    pub issuer: Option<String>,
+}
+
+/// The in-memory network state of a [`MockServer`].
+#[derive(Debug, Clone)]
+pub struct MockState {
+    /// Name of the server the RPC connection is made to.
+    pub server_name: String,
+    pub servers: Vec<Value>,
+    pub users: Vec<Value>,
+    pub channels: Vec<Value>,
+    pub tkls: Vec<Tkl>,
+    /// Past log events, as returned by `log.list`.
+    pub log: Vec<Value>,
+    /// All requests received so far, in order.
+    pub requests: Vec<ReceivedRequest>,
+    /// Number of further requests to handle without replying: the session is closed instead,
+    /// as if the connection dropped after the server applied them.
+    pub drop_replies: usize,
+    timers: Vec<String>,
+}
+
+impl Default for MockState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockState {
+    /// Create a state with a single server, `irc.example.org`, and nothing else.
+    pub fn new() -> Self {
+        let mut state = Self {
+            server_name: "irc.example.org".to_string(),
+            servers: Vec::new(),
+            users: Vec::new(),
+            channels: Vec::new(),
+            tkls: Vec::new(),
+            log: Vec::new(),
+            requests: Vec::new(),
+            drop_replies: 0,
+            timers: Vec::new(),
+        };
+        state.add_server("irc.example.org");
+        state
+    }
+
+    /// Add a server object.
+    pub fn add_server(&mut self, name: &str) -> &mut Value {
+        self.servers.push(json!({
+            "name": name,
+            "id": format!("{:03}", self.servers.len() + 1),
+            "info": "Mock UnrealIRCd server",
+            "server": {
+                "info": "Mock UnrealIRCd server",
+                "num_users": 0,
+                "boot_time": now(),
+                "synced": true,
+                "ulined": false,
+                "features": {"software": "UnrealIRCd-6.1.0-mock", "protocol": 6100}
+            }
+        }));
+        self.servers.last_mut().unwrap()
+    }
+
+    /// Add a client object on [`server_name`](Self::server_name).
+    ///
+    /// The returned object can be modified further, e.g. to set an account or TLS fingerprint.
+    pub fn add_user(&mut self, nick: &str, username: &str, hostname: &str, ip: &str) -> &mut Value {
+        self.users.push(json!({
+            "name": nick,
+            "id": format!("001{:06}", self.users.len() + 1),
+            "hostname": hostname,
+            "ip": ip,
+            "details": format!("{}!{}@{}", nick, username, hostname),
+            "connected_since": now(),
+            "idle_since": now(),
+            "user": {
+                "username": username,
+                "realname": nick,
+                "vhost": hostname,
+                "servername": self.server_name,
+                "reputation": 0,
+                "security-groups": ["unknown-users"],
+                "modes": "iwx",
+                "channels": []
+            },
+            "geoip": {}
+        }));
+        self.users.last_mut().unwrap()
+    }
+
+    /// Add an empty channel object.
+    pub fn add_channel(&mut self, name: &str) -> &mut Value {
+        self.channels.push(json!({
+            "name": name,
+            "creation_time": now(),
+            "num_users": 0,
+            "modes": "nt",
+            "members": [],
+            "bans": [],
+            "ban_exemptions": [],
+            "invite_exceptions": []
+        }));
+        self.channels.last_mut().unwrap()
+    }
+
+    /// Add a TKL as if it was set in the past.
+    pub fn add_tkl(&mut self, tkl: Tkl) {
+        self.tkls.push(tkl);
+    }
+
+    /// Find a user by nick (case-insensitive).
+    pub fn user(&self, nick: &str) -> Option<&Value> {
+        self.user_index(nick).map(|i| &self.users[i])
+    }
+
+    /// Find a channel by name (case-insensitive).
+    pub fn channel(&self, name: &str) -> Option<&Value> {
+        self.channel_index(name).map(|i| &self.channels[i])
+    }
+
+    fn user_index(&self, nick: &str) -> Option<usize> {
+        self.users.iter().position(|u| u["name"].as_str().is_some_and(|n| n.eq_ignore_ascii_case(nick)))
+    }
+
+    fn channel_index(&self, name: &str) -> Option<usize> {
+        self.channels.iter().position(|c| c["name"].as_str().is_some_and(|n| n.eq_ignore_ascii_case(name)))
+    }
+}
+
+/// A running mock UnrealIRCd JSON-RPC server. It is shut down when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    api_login: String,
+    state: Arc<Mutex<MockState>>,
+    events: broadcast::Sender<Value>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Start a server with an empty [`MockState`] and the API login `rpc:secret`.
+    pub async fn start() -> Result<Self> {
+        Self::start_with(MockState::new(), "rpc:secret").await
+    }
+
+    /// Start a server with the given state, accepting only `api_login` (`user:password`).
+    pub async fn start_with(state: MockState, api_login: &str) -> Result<Self> {
+        Self::start_on("127.0.0.1:0".parse().unwrap(), state, api_login).await
+    }
+
+    /// Like [`start_with`](Self::start_with), listening on `addr`; for example to bring a
+    /// server back up on the address of one that was [`shut down`](Self::shutdown).
+    pub async fn start_on(addr: SocketAddr, state: MockState, api_login: &str) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| Error::Other(format!("mock server bind failed: {}", e)))?;
+        let addr = listener
+            .local_addr()
+            .map_err(|e| Error::Other(format!("mock server bind failed: {}", e)))?;
+        let state = Arc::new(Mutex::new(state));
+        let (events, _) = broadcast::channel(1024);
+        let (shutdown, _) = watch::channel(false);
+
+        let auth_header = format!(
+            "Basic {}",
+            base64::Engine::encode(&base64::engine::general_purpose::STANDARD, api_login.as_bytes())
+        );
+        let rpc_user = api_login.split(':').next().unwrap_or("").to_string();
+        let task = {
+            let state = state.clone();
+            let events = events.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let session = Session {
+                        state: state.clone(),
+                        events: events.clone(),
+                        rpc_user: rpc_user.clone(),
+                        issuer: None,
+                        sources: None,
+                    };
+                    tokio::spawn(session.run(stream, auth_header.clone(), events.subscribe(), shutdown.subscribe()));
+                }
+            })
+        };
+
+        Ok(Self {
+            addr,
+            api_login: api_login.to_string(),
+            state,
+            events,
+            shutdown,
+            task,
+        })
+    }
+
+    /// Stop accepting connections and close all open sessions, as if the server went down.
+    pub fn shutdown(&self) {
+        self.task.abort();
+        let _ = self.shutdown.send(true);
+    }
+
+    /// The WebSocket URI of the server, e.g. `ws://127.0.0.1:41234/`.
+    pub fn uri(&self) -> String {
+        format!("ws://{}/", self.addr)
+    }
+
+    /// The address the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The API login accepted by the server.
+    pub fn api_login(&self) -> &str {
+        &self.api_login
+    }
+
+    /// A connection to this server that has not been connected yet.
+    pub fn connection(&self) -> Connection {
+        Connection::new(self.uri(), self.api_login.clone(), None)
+    }
+
+    /// A connected connection to this server.
+    pub async fn connect(&self) -> Result<Connection> {
+        let mut conn = self.connection();
+        conn.connect().await?;
+        Ok(conn)
+    }
+
+    /// Lock the server state for inspection or modification.
+    pub fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Add a log event and send it to all sessions subscribed to a matching source.
+    pub fn push_log(&self, event: Value) {
+        self.state().log.push(event.clone());
+        // No receivers just means nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    /// Add a user as if it just connected, logging a `LOCAL_CLIENT_CONNECT` event.
+    pub fn connect_user(&self, nick: &str, username: &str, hostname: &str, ip: &str) -> Value {
+        let mut state = self.state();
+        let client = state.add_user(nick, username, hostname, ip).clone();
+        let msg = format!("Client connecting: {} ({}@{}) [{}]", nick, username, hostname, ip);
+        emit(&self.events, &mut state, "connect", "LOCAL_CLIENT_CONNECT", &msg, json!({"client": client}));
+        client
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Serves a recorded [`Fixture`] back to a client, for deterministic tests.
+///
+/// Requests must arrive in the recorded order with the recorded method and params. Each is
+/// answered with the recorded reply; recorded notifications are pushed in between. Any
+/// mismatch is answered with an error and collected, so [`verify`](Self::verify) can report
+/// a readable diff of expected and actual params.
+pub struct ReplayServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<ReplayState>>,
+    task: JoinHandle<()>,
+}
+
+struct ReplayState {
+    entries: VecDeque<RecordedEntry>,
+    served: usize,
+    mismatches: Vec<String>,
+}
+
+impl ReplayServer {
+    /// Start serving a fixture.
+    ///
+    /// With `realtime`, notifications are delayed by their recorded distance to the previous
+    /// entry; otherwise they are sent as soon as their turn comes.
+    pub async fn start(fixture: Fixture, realtime: bool) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(|e| Error::Other(format!("replay server bind failed: {}", e)))?;
+        let addr = listener
+            .local_addr()
+            .map_err(|e| Error::Other(format!("replay server bind failed: {}", e)))?;
+        let state = Arc::new(Mutex::new(ReplayState {
+            entries: fixture.entries.into(),
+            served: 0,
+            mismatches: Vec::new(),
+        }));
+
+        let task = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    replay_session(stream, state.clone(), realtime).await;
+                }
+            })
+        };
+
+        Ok(Self { addr, state, task })
+    }
+
+    /// The WebSocket URI of the server.
+    pub fn uri(&self) -> String {
+        format!("ws://{}/", self.addr)
+    }
+
+    /// A connection to this server that has not been connected yet. Any login is accepted.
+    pub fn connection(&self) -> Connection {
+        Connection::new(self.uri(), "replay:replay".to_string(), None)
+    }
+
+    /// A connected connection to this server.
+    pub async fn connect(&self) -> Result<Connection> {
+        let mut conn = self.connection();
+        conn.connect().await?;
+        Ok(conn)
+    }
+
+    /// Check that every recorded request was received as recorded.
+    ///
+    /// The error lists each mismatch and each recorded request that was never sent.
+    pub fn verify(&self) -> std::result::Result<(), String> {
+        let state = self.state.lock().unwrap();
+        let mut problems = state.mismatches.clone();
+        for entry in &state.entries {
+            if let RecordedEntry::Request { method, .. } = entry {
+                problems.push(format!("expected request {} was never sent", method));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n"))
+        }
+    }
+}
+
+impl Drop for ReplayServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn replay_session(stream: TcpStream, state: Arc<Mutex<ReplayState>>, realtime: bool) {
+    let Ok(mut ws) = tokio_tungstenite::accept_async(stream).await else {
+        return;
+    };
+    let mut last_at = 0;
+
+    loop {
+        // Push notifications that were recorded before the next request
+        loop {
+            let notification = {
+                let mut state = state.lock().unwrap();
+                match state.entries.front() {
+                    Some(RecordedEntry::Notification { .. }) => state.entries.pop_front(),
+                    _ => None,
+                }
+            };
+            let Some(RecordedEntry::Notification { at_ms, message }) = notification else {
+                break;
+            };
+            if realtime && at_ms > last_at {
+                tokio::time::sleep(std::time::Duration::from_millis(at_ms - last_at)).await;
+            }
+            last_at = at_ms;
+            if ws.send(Message::Text(message.to_string())).await.is_err() {
+                return;
+            }
+        }
+
+        let text = match ws.next().await {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+            Some(Ok(_)) => continue,
+        };
+        let request: Value = serde_json::from_str(&text).unwrap_or(Value::Null);
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = request["method"].as_str().unwrap_or("").to_string();
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+        let reply = {
+            let mut state = state.lock().unwrap();
+            state.served += 1;
+            let n = state.served;
+            match state.entries.pop_front() {
+                Some(RecordedEntry::Request { at_ms, method: expected_method, params: expected_params, reply }) => {
+                    last_at = at_ms;
+                    let mut problems = Vec::new();
+                    if expected_method != method {
+                        problems.push(format!("method: expected {}, got {}", expected_method, method));
+                    }
+                    problems.extend(json_diff("params", &expected_params, &params));
+                    if problems.is_empty() {
+                        Ok(reply.unwrap_or_else(|| json!({"result": true})))
+                    } else {
+                        let mismatch = format!("request #{} ({}):\n  {}", n, expected_method, problems.join("\n  "));
+                        state.mismatches.push(mismatch.clone());
+                        Err(mismatch)
+                    }
+                }
+                _ => {
+                    let mismatch = format!("request #{} ({}): not in the fixture", n, method);
+                    state.mismatches.push(mismatch.clone());
+                    Err(mismatch)
+                }
+            }
+        };
+
+        let mut reply = match reply {
+            Ok(reply) => reply,
+            Err(mismatch) => error_reply(Value::Null, &method, error_code::INVALID_REQUEST, &mismatch),
+        };
+        reply["jsonrpc"] = "2.0".into();
+        reply["id"] = id;
+        if ws.send(Message::Text(reply.to_string())).await.is_err() {
+            return;
+        }
+    }
+}
+
+type RpcResult = std::result::Result<Value, (i64, String)>;
+
+struct Session {
+    state: Arc<Mutex<MockState>>,
+    events: broadcast::Sender<Value>,
+    rpc_user: String,
+    issuer: Option<String>,
+    sources: Option<Vec<String>>,
+}
+
+/// Refuses WebSocket handshakes without the expected `Authorization` header.
+struct CheckAuth<'a>(&'a str);
+
+impl Callback for CheckAuth<'_> {
+    fn on_request(self, request: &Request, response: Response) -> std::result::Result<Response, ErrorResponse> {
+        if request.headers().get("Authorization").is_some_and(|h| h.as_bytes() == self.0.as_bytes()) {
+            return Ok(response);
+        }
+        let mut denied = ErrorResponse::new(Some("Authentication required".to_string()));
+        *denied.status_mut() = http::StatusCode::UNAUTHORIZED;
+        Err(denied)
+    }
+}
+
+impl Session {
+    async fn run(
+        mut self,
+        stream: TcpStream,
+        auth_header: String,
+        mut events: broadcast::Receiver<Value>,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let Ok(mut ws) = tokio_tungstenite::accept_hdr_async(stream, CheckAuth(&auth_header)).await else {
+            return;
+        };
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                msg = ws.next() => {
+                    let reply = match msg {
+                        Some(Ok(Message::Text(text))) => self.handle(&text),
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => continue,
+                    };
+                    if self.drop_reply() {
+                        break;
+                    }
+                    if ws.send(Message::Text(reply.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+                event = events.recv() => {
+                    let Ok(event) = event else { continue };
+                    if self.sources.as_ref().is_some_and(|s| source_matches(s, &event)) {
+                        let notification = json!({"jsonrpc": "2.0", "method": "log.event", "result": event});
+                        if ws.send(Message::Text(notification.to_string())).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, text: &str) -> Value {
+        let request: Value = match serde_json::from_str(text) {
+            Ok(r) => r,
+            Err(e) => return error_reply(Value::Null, "", error_code::PARSE_ERROR, &e.to_string()),
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            return error_reply(id, "", error_code::INVALID_REQUEST, "Missing 'method'");
+        };
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+        self.state.lock().unwrap().requests.push(ReceivedRequest {
+            method: method.to_string(),
+            params: params.clone(),
+            issuer: self.issuer.clone(),
+        });
+
+        match self.dispatch(method, &params) {
+            Ok(result) => json!({"jsonrpc": "2.0", "method": method, "id": id, "result": result}),
+            Err((code, message)) => error_reply(id, method, code, &message),
+        }
+    }
+
+    fn drop_reply(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let drop = state.drop_replies > 0;
+        state.drop_replies = state.drop_replies.saturating_sub(1);
+        drop
+    }
+
+    fn set_by(&self, params: &Value) -> String {
+        opt_str(params, "set_by")
+            .map(str::to_string)
+            .or_else(|| self.issuer.clone())
+            .unwrap_or_else(|| format!("RPC:{}", self.rpc_user))
+    }
+
+    fn dispatch(&mut self, method: &str, params: &Value) -> RpcResult {
+        // Session-level methods, which don't touch the network state
+        match method {
+            "rpc.set_issuer" => {
+                self.issuer = Some(str_param(params, "name")?.to_string());
+                return Ok(json!(true));
+            }
+            "log.subscribe" => {
+                let sources = params
+                    .get("sources")
+                    .and_then(Value::as_array)
+                    .ok_or_else(|| missing("sources"))?;
+                self.sources = Some(sources.iter().filter_map(Value::as_str).map(str::to_string).collect());
+                return Ok(json!(true));
+            }
+            "log.unsubscribe" => {
+                self.sources = None;
+                return Ok(json!(true));
+            }
+            _ => {}
+        }
+
+        let set_by = self.set_by(params);
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        match method {
+            "rpc.info" => {
+                let methods: serde_json::Map<String, Value> = METHODS
+                    .iter()
+                    .map(|m| {
+                        let module = format!("rpc/{}", m.split('.').next().unwrap_or(""));
+                        (m.to_string(), json!({"name": m, "module": module, "version": "1.0.0"}))
+                    })
+                    .collect();
+                Ok(json!({"methods": methods}))
+            }
+            "rpc.add_timer" => {
+                let timer_id = str_param(params, "timer_id")?;
+                if state.timers.iter().any(|t| t == timer_id) {
+                    return Err((error_code::ALREADY_EXISTS, "Timer already exists with this name".to_string()));
+                }
+                state.timers.push(timer_id.to_string());
+                Ok(json!(true))
+            }
+            "rpc.del_timer" => {
+                let timer_id = str_param(params, "timer_id")?;
+                let before = state.timers.len();
+                state.timers.retain(|t| t != timer_id);
+                if state.timers.len() == before {
+                    return Err(not_found("Timer not found"));
+                }
+                Ok(json!(true))
+            }
+            "stats.get" => {
+                let count = |f: fn(&Tkl) -> bool| state.tkls.iter().filter(|t| f(t)).count();
+                let server_bans = count(Tkl::is_server_ban);
+                let spamfilters = count(Tkl::is_spamfilter);
+                let name_bans = count(Tkl::is_name_ban);
+                let exceptions = count(Tkl::is_exception);
+                let opers = state.users.iter().filter(|u| modes_of(u).contains('o')).count();
+                let ulined_users = state.users.iter().filter(|u| modes_of(u).contains('S')).count();
+                Ok(json!({
+                    "server": {"total": state.servers.len(), "ulined": 0},
+                    "user": {
+                        "total": state.users.len(),
+                        "ulined": ulined_users,
+                        "oper": opers,
+                        "record": state.users.len()
+                    },
+                    "channel": {"total": state.channels.len()},
+                    "server_ban": {
+                        "total": server_bans + spamfilters + name_bans + exceptions,
+                        "server_ban": server_bans,
+                        "spamfilter": spamfilters,
+                        "name_ban": name_bans,
+                        "server_ban_exception": exceptions
+                    }
+                }))
+            }
+            "server.list" => {
+                let mut servers = state.servers.clone();
+                for server in &mut servers {
+                    let users = state.users.iter().filter(|u| u["user"]["servername"] == server["name"]).count();
+                    server["server"]["num_users"] = users.into();
+                }
+                Ok(json!({"list": servers}))
+            }
+            "server.get" => {
+                let name = opt_str(params, "server").unwrap_or(&state.server_name).to_string();
+                let server = state
+                    .servers
+                    .iter()
+                    .find(|s| s["name"].as_str().is_some_and(|n| n.eq_ignore_ascii_case(&name)))
+                    .ok_or_else(|| not_found("Server not found"))?;
+                Ok(json!({"server": server}))
+            }
+            "server.rehash" => {
+                let name = opt_str(params, "server").unwrap_or(&state.server_name).to_string();
+                if !state.servers.iter().any(|s| s["name"].as_str().is_some_and(|n| n.eq_ignore_ascii_case(&name))) {
+                    return Err(not_found("Server not found"));
+                }
+                Ok(json!(true))
+            }
+            "user.list" => Ok(json!({"list": state.users})),
+            "user.get" => {
+                let user = state.user(str_param(params, "nick")?).ok_or_else(nick_not_found)?;
+                Ok(json!({"client": user}))
+            }
+            "user.set_nick" => {
+                let nick = str_param(params, "nick")?;
+                let newnick = str_param(params, "newnick")?;
+                let i = state.user_index(nick).ok_or_else(nick_not_found)?;
+                if state.user_index(newnick).is_some_and(|j| j != i) {
+                    return Err((error_code::ALREADY_EXISTS, "New nickname is already taken by another user".to_string()));
+                }
+                let old = state.users[i]["name"].as_str().unwrap_or("").to_string();
+                let before = state.users[i].clone();
+                let user = &mut state.users[i];
+                user["name"] = newnick.into();
+                user["details"] = format!(
+                    "{}!{}@{}",
+                    newnick,
+                    user["user"]["username"].as_str().unwrap_or(""),
+                    user["hostname"].as_str().unwrap_or("")
+                )
+                .into();
+                for channel in &mut state.channels {
+                    for member in members_mut(channel) {
+                        if member["name"].as_str() == Some(old.as_str()) {
+                            member["name"] = newnick.into();
+                        }
+                    }
+                }
+                let msg = format!("{} changed their nickname to {}", old, newnick);
+                emit(&self.events, state, "nick", "NICK_COMMAND", &msg, json!({"client": before, "new_nick": newnick}));
+                Ok(json!(true))
+            }
+            "user.set_username" => set_user_field(state, params, "username", "username"),
+            "user.set_realname" => set_user_field(state, params, "realname", "realname"),
+            "user.set_vhost" => set_user_field(state, params, "vhost", "vhost"),
+            "user.set_snomask" => set_user_field(state, params, "snomask", "snomask"),
+            "user.set_mode" => {
+                let i = state.user_index(str_param(params, "nick")?).ok_or_else(nick_not_found)?;
+                let modes = apply_simple_modes(&modes_of(&state.users[i]), str_param(params, "modes")?);
+                state.users[i]["user"]["modes"] = modes.into();
+                let client = state.users[i].clone();
+                let msg = format!("{} set user modes {}", client["name"].as_str().unwrap_or(""), str_param(params, "modes")?);
+                emit(&self.events, state, "mode", "USER_MODE", &msg, json!({"client": client}));
+                Ok(json!(true))
+            }
+            "user.set_oper" => {
+                let i = state.user_index(str_param(params, "nick")?).ok_or_else(nick_not_found)?;
+                let oper_account = str_param(params, "oper_account")?.to_string();
+                let oper_class = str_param(params, "oper_class")?.to_string();
+                let modes = apply_simple_modes(&modes_of(&state.users[i]), opt_str(params, "modes").unwrap_or("+o"));
+                let user = &mut state.users[i]["user"];
+                user["operlogin"] = oper_account.into();
+                user["operclass"] = oper_class.into();
+                user["modes"] = apply_simple_modes(&modes, "+o").into();
+                if let Some(vhost) = opt_str(params, "vhost") {
+                    user["vhost"] = vhost.into();
+                }
+                Ok(json!(true))
+            }
+            "user.join" => {
+                let nick = str_param(params, "nick")?;
+                let channel = str_param(params, "channel")?;
+                let i = state.user_index(nick).ok_or_else(nick_not_found)?;
+                let nick = state.users[i]["name"].clone();
+                let c = match state.channel_index(channel) {
+                    Some(c) => c,
+                    None => {
+                        state.add_channel(channel);
+                        state.channels.len() - 1
+                    }
+                };
+                if !members_mut(&mut state.channels[c]).iter().any(|m| m["name"] == nick) {
+                    let level = if state.channels[c]["num_users"] == 0 { "o" } else { "" };
+                    push_member(&mut state.channels[c], json!({"name": nick, "level": level}));
+                    let name = state.channels[c]["name"].clone();
+                    if let Some(channels) = state.users[i]["user"]["channels"].as_array_mut() {
+                        channels.push(json!({"name": name, "level": level}));
+                    }
+                    let client = state.users[i].clone();
+                    let channel = channel_summary(&state.channels[c]);
+                    let msg = format!("User {} joined {}", nick.as_str().unwrap_or(""), name.as_str().unwrap_or(""));
+                    emit(&self.events, state, "join", "LOCAL_CLIENT_JOIN", &msg, json!({"client": client, "channel": channel}));
+                }
+                Ok(json!(true))
+            }
+            "user.part" => {
+                let nick = str_param(params, "nick")?;
+                let channel = str_param(params, "channel")?;
+                let i = state.user_index(nick).ok_or_else(nick_not_found)?;
+                let c = state.channel_index(channel).ok_or_else(|| not_found("Channel not found"))?;
+                let nick = state.users[i]["name"].as_str().unwrap_or("").to_string();
+                if !remove_member(state, c, &nick) {
+                    return Err(not_in_channel());
+                }
+                let client = state.users[i].clone();
+                let channel = channel_summary(&state.channels[c]);
+                let msg = format!("User {} left {}", nick, channel["name"].as_str().unwrap_or(""));
+                emit(&self.events, state, "part", "LOCAL_CLIENT_PART", &msg, json!({"client": client, "channel": channel}));
+                Ok(json!(true))
+            }
+            "user.quit" | "user.kill" => {
+                let i = state.user_index(str_param(params, "nick")?).ok_or_else(nick_not_found)?;
+                let reason = str_param(params, "reason")?;
+                let nick = state.users[i]["name"].as_str().unwrap_or("").to_string();
+                for c in 0..state.channels.len() {
+                    remove_member(state, c, &nick);
+                }
+                let client = state.users.remove(i);
+                let msg = format!("Client exiting: {} ({})", nick, reason);
+                emit(&self.events, state, "connect", "LOCAL_CLIENT_DISCONNECT", &msg, json!({"client": client, "reason": reason}));
+                Ok(json!(true))
+            }
+            "channel.list" => Ok(json!({"list": state.channels})),
+            "channel.get" => {
+                let channel = state
+                    .channel(str_param(params, "channel")?)
+                    .ok_or_else(|| not_found("Channel not found"))?;
+                Ok(json!({"channel": channel}))
+            }
+            "channel.set_mode" => {
+                let c = state
+                    .channel_index(str_param(params, "channel")?)
+                    .ok_or_else(|| not_found("Channel not found"))?;
+                let modes = str_param(params, "modes")?;
+                let parameters = opt_str(params, "parameters").unwrap_or("");
+                apply_channel_modes(&mut state.channels[c], modes, parameters, &set_by);
+                let channel = channel_summary(&state.channels[c]);
+                let msg = format!("{} sets mode {} {} on {}", set_by, modes, parameters, channel["name"].as_str().unwrap_or(""));
+                let data = json!({"channel": channel, "modes": modes, "parameters": parameters});
+                emit(&self.events, state, "mode", "CHANNEL_MODE", &msg, data);
+                Ok(json!(true))
+            }
+            "channel.set_topic" => {
+                let c = state
+                    .channel_index(str_param(params, "channel")?)
+                    .ok_or_else(|| not_found("Channel not found"))?;
+                let channel = &mut state.channels[c];
+                channel["topic"] = str_param(params, "topic")?.into();
+                channel["topic_set_by"] = set_by.into();
+                channel["topic_set_at"] = opt_str(params, "set_at").map(str::to_string).unwrap_or_else(now).into();
+                Ok(json!(true))
+            }
+            "channel.kick" => {
+                let c = state
+                    .channel_index(str_param(params, "channel")?)
+                    .ok_or_else(|| not_found("Channel not found"))?;
+                let nick = str_param(params, "nick")?;
+                str_param(params, "reason")?;
+                let nick = state.user(nick).ok_or_else(nick_not_found)?["name"].as_str().unwrap_or("").to_string();
+                if !remove_member(state, c, &nick) {
+                    return Err(not_in_channel());
+                }
+                let victim = state.user(&nick).cloned().unwrap_or(Value::Null);
+                let channel = channel_summary(&state.channels[c]);
+                let reason = str_param(params, "reason")?;
+                let msg = format!("{} kicked {} from {} ({})", set_by, nick, channel["name"].as_str().unwrap_or(""), reason);
+                let data = json!({"victim": victim, "channel": channel, "reason": reason});
+                emit(&self.events, state, "kick", "LOCAL_CLIENT_KICK", &msg, data);
+                Ok(json!(true))
+            }
+            "server_ban.add" => {
+                let ban_type = str_param(params, "type")?;
+                if !matches!(ban_type, "kline" | "gline" | "zline" | "gzline" | "shun") {
+                    return Err(invalid_params("Invalid type"));
+                }
+                let name = str_param(params, "name")?;
+                BanMask::parse(name).map_err(|e| (error_code::INVALID_NAME, e.to_string()))?;
+                let tkl = Tkl {
+                    tkl_type: ban_type.to_string(),
+                    name: name.to_string(),
+                    reason: Some(str_param(params, "reason")?.to_string()),
+                    ..Default::default()
+                };
+                add_tkl(state, tkl, opt_str(params, "duration_string").unwrap_or("0"), set_by)
+            }
+            "server_ban_exception.add" => {
+                let tkl = Tkl {
+                    tkl_type: "exception".to_string(),
+                    name: str_param(params, "name")?.to_string(),
+                    exception_types: Some(str_param(params, "exception_types")?.to_string()),
+                    reason: Some(str_param(params, "reason")?.to_string()),
+                    ..Default::default()
+                };
+                add_tkl(state, tkl, opt_str(params, "duration_string").unwrap_or("0"), set_by)
+            }
+            "name_ban.add" => {
+                let tkl = Tkl {
+                    tkl_type: "qline".to_string(),
+                    name: str_param(params, "name")?.to_string(),
+                    reason: Some(str_param(params, "reason")?.to_string()),
+                    ..Default::default()
+                };
+                add_tkl(state, tkl, opt_str(params, "duration_string").unwrap_or("0"), set_by)
+            }
+            "spamfilter.add" => {
+                let tkl = Tkl {
+                    tkl_type: "spamfilter".to_string(),
+                    name: str_param(params, "name")?.to_string(),
+                    match_type: Some(str_param(params, "match_type")?.to_string()),
+                    spamfilter_targets: Some(str_param(params, "spamfilter_targets")?.to_string()),
+                    ban_action: Some(str_param(params, "ban_action")?.to_string()),
+                    ban_duration_string: Some(str_param(params, "ban_duration")?.to_string()),
+                    reason: Some(str_param(params, "reason")?.to_string()),
+                    hits: Some(0),
+                    hits_except: Some(0),
+                    ..Default::default()
+                };
+                add_tkl(state, tkl, "0", set_by)
+            }
+            "server_ban.list" => list_tkls(state, Tkl::is_server_ban),
+            "server_ban_exception.list" => list_tkls(state, Tkl::is_exception),
+            "name_ban.list" => list_tkls(state, Tkl::is_name_ban),
+            "spamfilter.list" => list_tkls(state, Tkl::is_spamfilter),
+            "server_ban.get" | "server_ban.del" | "server_ban_exception.get" | "server_ban_exception.del"
+            | "name_ban.get" | "name_ban.del" | "spamfilter.get" | "spamfilter.del" => {
+                let key = tkl_key(method, params)?;
+                let i = state
+                    .tkls
+                    .iter()
+                    .position(|t| t.key() == key)
+                    .ok_or_else(|| not_found("Ban not found"))?;
+                let tkl = if method.ends_with(".del") {
+                    state.tkls.remove(i)
+                } else {
+                    state.tkls[i].clone()
+                };
+                Ok(json!({"tkl": tkl}))
+            }
+            "log.list" => {
+                let log: Vec<&Value> = match params.get("sources").and_then(Value::as_array) {
+                    Some(sources) => {
+                        let sources: Vec<String> = sources.iter().filter_map(Value::as_str).map(str::to_string).collect();
+                        state.log.iter().filter(|e| source_matches(&sources, e)).collect()
+                    }
+                    None => state.log.iter().collect(),
+                };
+                Ok(json!({"list": log}))
+            }
+            _ => Err((error_code::METHOD_NOT_FOUND, "Unsupported method".to_string())),
+        }
+    }
+}
+
+fn error_reply(id: Value, method: &str, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "id": id,
+        "error": {"code": code, "message": message}
+    })
+}
+
+/// Log an event like the IRCd would, and send it to subscribed sessions.
+fn emit(events: &broadcast::Sender<Value>, state: &mut MockState, subsystem: &str, event_id: &str, msg: &str, data: Value) {
+    let mut event = json!({
+        "timestamp": now(),
+        "level": "info",
+        "subsystem": subsystem,
+        "event_id": event_id,
+        "log_source": state.server_name,
+        "msg": msg
+    });
+    if let (Some(event), Value::Object(data)) = (event.as_object_mut(), data) {
+        event.extend(data);
+    }
+    state.log.push(event.clone());
+    let _ = events.send(event);
+}
+
+/// A channel object as included in log events: without the member and list-mode lists.
+fn channel_summary(channel: &Value) -> Value {
+    let mut summary = channel.clone();
+    if let Some(obj) = summary.as_object_mut() {
+        for key in ["members", "bans", "ban_exemptions", "invite_exceptions"] {
+            obj.remove(key);
+        }
+    }
+    summary
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+fn missing(name: &str) -> (i64, String) {
+    invalid_params(&format!("Missing parameter: '{}'", name))
+}
+
+fn invalid_params(message: &str) -> (i64, String) {
+    (error_code::INVALID_PARAMS, message.to_string())
+}
+
+fn not_found(message: &str) -> (i64, String) {
+    (error_code::NOT_FOUND, message.to_string())
+}
+
+fn nick_not_found() -> (i64, String) {
+    not_found("Nickname not found")
+}
+
+fn not_in_channel() -> (i64, String) {
+    (error_code::USER_NOT_IN_CHANNEL, "User is not in that channel".to_string())
+}
+
+fn opt_str<'a>(params: &'a Value, name: &str) -> Option<&'a str> {
+    params.get(name).and_then(Value::as_str)
+}
+
+fn str_param<'a>(params: &'a Value, name: &str) -> std::result::Result<&'a str, (i64, String)> {
+    opt_str(params, name).ok_or_else(|| missing(name))
+}
+
+fn modes_of(user: &Value) -> String {
+    user["user"]["modes"].as_str().unwrap_or("").to_string()
+}
+
+fn set_user_field(state: &mut MockState, params: &Value, param: &str, field: &str) -> RpcResult {
+    let i = state.user_index(str_param(params, "nick")?).ok_or_else(nick_not_found)?;
+    state.users[i]["user"][field] = str_param(params, param)?.into();
+    Ok(json!(true))
+}
+
+/// Apply `+abc-d` style flag changes to a mode string.
+fn apply_simple_modes(current: &str, change: &str) -> String {
+    let mut modes: Vec<char> = current.trim_start_matches('+').chars().collect();
+    let mut adding = true;
+    for c in change.chars() {
+        match c {
+            '+' => adding = true,
+            '-' => adding = false,
+            c if adding && !modes.contains(&c) => modes.push(c),
+            c if !adding => modes.retain(|&m| m != c),
+            _ => {}
+        }
+    }
+    modes.into_iter().collect()
+}
+
+fn apply_channel_modes(channel: &mut Value, modes: &str, parameters: &str, set_by: &str) {
+    let mut params = parameters.split_whitespace();
+    let mut adding = true;
+    let mut flags = channel["modes"].as_str().unwrap_or("").to_string();
+    for c in modes.chars() {
+        match c {
+            '+' => adding = true,
+            '-' => adding = false,
+            'b' | 'e' | 'I' => {
+                let Some(mask) = params.next() else { continue };
+                let list = match c {
+                    'b' => "bans",
+                    'e' => "ban_exemptions",
+                    _ => "invite_exceptions",
+                };
+                if !channel[list].is_array() {
+                    channel[list] = json!([]);
+                }
+                let entries = channel[list].as_array_mut().unwrap();
+                entries.retain(|e| e["name"].as_str() != Some(mask));
+                if adding {
+                    entries.push(json!({"name": mask, "set_by": set_by, "set_at": now()}));
+                }
+            }
+            'q' | 'a' | 'o' | 'h' | 'v' => {
+                let Some(nick) = params.next() else { continue };
+                for member in members_mut(channel) {
+                    if member["name"].as_str().is_some_and(|n| n.eq_ignore_ascii_case(nick)) {
+                        let level = apply_simple_modes(member["level"].as_str().unwrap_or(""), &format!("{}{}", if adding { '+' } else { '-' }, c));
+                        member["level"] = level.into();
+                    }
+                }
+            }
+            'k' | 'l' | 'L' | 'f' | 'F' | 'H' => {
+                if adding || c == 'k' {
+                    params.next();
+                }
+                flags = apply_simple_modes(&flags, &format!("{}{}", if adding { '+' } else { '-' }, c));
+            }
+            c => flags = apply_simple_modes(&flags, &format!("{}{}", if adding { '+' } else { '-' }, c)),
+        }
+    }
+    channel["modes"] = flags.into();
+}
+
+fn members_mut(channel: &mut Value) -> Vec<&mut Value> {
+    match channel["members"].as_array_mut() {
+        Some(members) => members.iter_mut().collect(),
+        None => Vec::new(),
+    }
+}
+
+fn push_member(channel: &mut Value, member: Value) {
+    if !channel["members"].is_array() {
+        channel["members"] = json!([]);
+    }
+    channel["members"].as_array_mut().unwrap().push(member);
+    channel["num_users"] = channel["members"].as_array().unwrap().len().into();
+}
+
+/// Remove `nick` from channel `c` and the channel from the user. Returns whether it was a member.
+fn remove_member(state: &mut MockState, c: usize, nick: &str) -> bool {
+    let channel = &mut state.channels[c];
+    let name = channel["name"].clone();
+    let Some(members) = channel["members"].as_array_mut() else {
+        return false;
+    };
+    let before = members.len();
+    members.retain(|m| m["name"].as_str() != Some(nick));
+    if members.len() == before {
+        return false;
+    }
+    channel["num_users"] = members.len().into();
+    if let Some(i) = state.user_index(nick) {
+        if let Some(channels) = state.users[i]["user"]["channels"].as_array_mut() {
+            channels.retain(|ch| ch["name"] != name);
+        }
+    }
+    true
+}
+
+fn list_tkls(state: &MockState, filter: fn(&Tkl) -> bool) -> RpcResult {
+    let list: Vec<&Tkl> = state.tkls.iter().filter(|t| filter(t)).collect();
+    Ok(json!({"list": list}))
+}
+
+/// The [`Tkl::key`] of the TKL addressed by a `.get` or `.del` request.
+fn tkl_key(method: &str, params: &Value) -> std::result::Result<String, (i64, String)> {
+    let probe = match method.split('.').next().unwrap_or("") {
+        "server_ban" => Tkl {
+            tkl_type: str_param(params, "type")?.to_string(),
+            name: str_param(params, "name")?.to_string(),
+            ..Default::default()
+        },
+        "server_ban_exception" => Tkl {
+            tkl_type: "exception".to_string(),
+            name: str_param(params, "name")?.to_string(),
+            ..Default::default()
+        },
+        "name_ban" => Tkl {
+            tkl_type: "qline".to_string(),
+            name: str_param(params, "name")?.to_string(),
+            ..Default::default()
+        },
+        _ => Tkl {
+            tkl_type: "spamfilter".to_string(),
+            name: str_param(params, "name")?.to_string(),
+            match_type: Some(str_param(params, "match_type")?.to_string()),
+            spamfilter_targets: Some(str_param(params, "spamfilter_targets")?.to_string()),
+            ban_action: Some(str_param(params, "ban_action")?.to_string()),
+            ..Default::default()
+        },
+    };
+    Ok(probe.key())
+}
+
+fn add_tkl(state: &mut MockState, mut tkl: Tkl, duration: &str, set_by: String) -> RpcResult {
+    let seconds = parse_duration(duration).ok_or_else(|| invalid_params("Invalid duration"))?;
+    if state.tkls.iter().any(|t| t.key() == tkl.key()) {
+        return Err((error_code::ALREADY_EXISTS, "A ban with that name already exists".to_string()));
+    }
+
+    let set_at = chrono::Utc::now();
+    tkl.type_string = Some(type_string(&tkl.tkl_type).to_string());
+    tkl.set_by = Some(set_by);
+    tkl.set_at = Some(set_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
+    tkl.set_at_string = tkl.set_at.clone();
+    tkl.set_at_delta = Some(0);
+    tkl.set_in_config = Some(false);
+    if seconds > 0 {
+        let expire_at = set_at + chrono::Duration::seconds(seconds);
+        tkl.expire_at = Some(expire_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
+        tkl.expire_at_string = tkl.expire_at.clone();
+        tkl.duration_string = Some(duration.to_string());
+    } else {
+        tkl.expire_at_string = Some("Never".to_string());
+        tkl.duration_string = Some("permanent".to_string());
+    }
+    state.tkls.push(tkl.clone());
+    Ok(json!({"tkl": tkl}))
+}
+
+fn type_string(tkl_type: &str) -> &'static str {
+    match tkl_type {
+        "kline" => "K-Line",
+        "gline" => "G-Line",
+        "zline" => "Z-Line",
+        "gzline" => "Global Z-Line",
+        "shun" => "Shun",
+        "qline" => "Q-Line",
+        "exception" => "Exception",
+        "spamfilter" => "Spamfilter",
+        _ => "Unknown",
+    }
+}
+
+/// Parse an UnrealIRCd duration such as `1d2h` or `3600` into seconds.
+fn parse_duration(s: &str) -> Option<i64> {
+    let mut total = 0i64;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let n: i64 = number.parse().ok()?;
+        number.clear();
+        total += n * match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            'y' => 31536000,
+            _ => return None,
+        };
+    }
+    if !number.is_empty() {
+        total += number.parse::<i64>().ok()?;
+    }
+    Some(total)
+}
+
+/// Whether a log event matches a list of `log.subscribe` sources.
+///
+/// Sources are matched against the level, subsystem, `subsystem.event_id` and event_id;
+/// a leading `!` excludes, and `all` matches everything.
+fn source_matches(sources: &[String], event: &Value) -> bool {
+    let level = event["level"].as_str().unwrap_or("");
+    let subsystem = event["subsystem"].as_str().unwrap_or("");
+    let event_id = event["event_id"].as_str().unwrap_or("");
+    let full = format!("{}.{}", subsystem, event_id);
+    let hit = |source: &str| {
+        source == "all"
+            || source == level
+            || wildcard_match(source, subsystem)
+            || wildcard_match(source, event_id)
+            || wildcard_match(source, &full)
+    };
+    let excluded = sources.iter().filter_map(|s| s.strip_prefix('!')).any(hit);
+    !excluded && sources.iter().filter(|s| !s.starts_with('!')).any(|s| hit(s))
+}
@@ -0,0 +1,359 @@
+//! Client-side rate limiting and bulk operations.
+//!
+//! Mass actions such as removing thousands of bans or killing a botnet send requests as
+//! fast as the server answers, which can trip the server's flood protection and floods the
+//! network with the resulting messages. With [`RateLimits`] set on a connection (see
+//! [`Connection::set_rate_limits`](crate::Connection::set_rate_limits)), every request waits
+//! for a token from the bucket of its [`RateClass`] first.
+//!
+//! [`Bulk`] runs a list of calls one after the other, reporting [`Progress`] after each and
+//! checking its [`BulkControl`] in between, so another task can pause, resume or cancel it.
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! use unrealircd_rpc::throttle::{Bulk, Rate, RateLimits};
+//!
+//! # async fn example(conn: Connection, masks: Vec<String>) -> unrealircd_rpc::error::Result<()> {
+//! conn.set_rate_limits(Some(RateLimits { write: Some(Rate::new(10.0, 20)?), ..Default::default() }));
+//!
+//! let bulk = Bulk::new().on_progress(|p| eprintln!("{}/{} ({} failed)", p.done, p.total, p.failed));
+//! let control = bulk.control();
+//! tokio::spawn(async move {
+//!     // e.g. from a signal handler
+//!     control.pause();
+//! });
+//! let bans = conn.server_ban();
+//! let results = bulk.run(&masks, |mask| bans.delete(mask, "gline")).await;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::error::{Error, Result};
+use crate::method::MethodClass;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// The bucket a request is counted against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateClass {
+    /// Requests that only read state, and session requests.
+    Read,
+    /// Requests that change state, other than kills.
+    Write,
+    /// `user.kill` and `user.quit`, which each cause a quit to be broadcast.
+    Kill,
+}
+
+impl RateClass {
+    /// Classify a method name.
+    pub fn of(method: &str) -> Self {
+        match method {
+            "user.kill" | "user.quit" => RateClass::Kill,
+            _ if MethodClass::of(method) == MethodClass::Write => RateClass::Write,
+            _ => RateClass::Read,
+        }
+    }
+}
+
+/// A token bucket: `per_second` requests on average, and up to `burst` at once.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RateFields")]
+pub struct Rate {
+    per_second: f64,
+    burst: u32,
+}
+
+#[derive(Deserialize)]
+struct RateFields {
+    per_second: f64,
+    burst: u32,
+}
+
+impl TryFrom<RateFields> for Rate {
+    type Error = Error;
+
+    fn try_from(fields: RateFields) -> Result<Self> {
+        Self::new(fields.per_second, fields.burst)
+    }
+}
+
+impl Rate {
+    /// Create a rate. `per_second` must be positive and finite; a `burst` of 0 is treated as 1.
+    pub fn new(per_second: f64, burst: u32) -> Result<Self> {
+        if !per_second.is_finite() || per_second <= 0.0 {
+            return Err(Error::Parse(format!("invalid rate {}/s, expected a positive number", per_second)));
+        }
+        Ok(Self { per_second, burst })
+    }
+
+    pub fn per_second(&self) -> f64 {
+        self.per_second
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+}
+
+/// Rates per [`RateClass`]; classes without a rate are not limited.
+///
+/// Parses from a comma-separated list such as `write=10/20,kill=2`, where the number after
+/// the slash is the burst (default: 1).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimits {
+    #[serde(default)]
+    pub read: Option<Rate>,
+    #[serde(default)]
+    pub write: Option<Rate>,
+    #[serde(default)]
+    pub kill: Option<Rate>,
+}
+
+impl RateLimits {
+    /// The rate for a class.
+    pub fn rate(&self, class: RateClass) -> Option<Rate> {
+        match class {
+            RateClass::Read => self.read,
+            RateClass::Write => self.write,
+            RateClass::Kill => self.kill,
+        }
+    }
+}
+
+impl FromStr for RateLimits {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut limits = RateLimits::default();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let invalid = || Error::Parse(format!("invalid rate limit '{}', expected e.g. write=10/20", part));
+            let (class, rate) = part.split_once('=').ok_or_else(invalid)?;
+            let (per_second, burst) = match rate.split_once('/') {
+                Some((per_second, burst)) => (per_second, burst.parse().map_err(|_| invalid())?),
+                None => (rate, 1),
+            };
+            let per_second: f64 = per_second.parse().map_err(|_| invalid())?;
+            let rate = Some(Rate::new(per_second, burst).map_err(|_| invalid())?);
+            match class.trim() {
+                "read" => limits.read = rate,
+                "write" => limits.write = rate,
+                "kill" => limits.kill = rate,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(limits)
+    }
+}
+
+/// How often requests of one class had to wait.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ThrottleStats {
+    /// Requests that had to wait for a token.
+    pub throttled: u64,
+    /// Total time spent waiting.
+    pub wait_seconds: f64,
+}
+
+struct Bucket {
+    rate: Rate,
+    tokens: f64,
+    refilled: Instant,
+    stats: ThrottleStats,
+}
+
+impl Bucket {
+    fn new(rate: Rate) -> Self {
+        Self { rate, tokens: rate.burst.max(1) as f64, refilled: Instant::now(), stats: ThrottleStats::default() }
+    }
+
+    /// Take a token, or return how long until one is available.
+    fn take(&mut self, now: Instant) -> Option<Duration> {
+        let burst = self.rate.burst.max(1) as f64;
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_second).min(burst);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate.per_second))
+    }
+}
+
+/// Token buckets for the classes of a [`RateLimits`], shared by a connection and its clones.
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: [Option<Mutex<Bucket>>; 3],
+}
+
+fn index(class: RateClass) -> usize {
+    match class {
+        RateClass::Read => 0,
+        RateClass::Write => 1,
+        RateClass::Kill => 2,
+    }
+}
+
+impl RateLimiter {
+    /// Create a limiter with full buckets. Every [`Rate`] is checked by [`Rate::new`], also when
+    /// deserialized, so a bucket always refills.
+    pub fn new(limits: RateLimits) -> Self {
+        let bucket = |class| limits.rate(class).map(|rate| Mutex::new(Bucket::new(rate)));
+        Self {
+            limits,
+            buckets: [bucket(RateClass::Read), bucket(RateClass::Write), bucket(RateClass::Kill)],
+        }
+    }
+
+    /// The configured rates.
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Wait until a request of `method` may be sent.
+    pub async fn acquire(&self, method: &str) {
+        let class = RateClass::of(method);
+        let Some(bucket) = &self.buckets[index(class)] else {
+            return;
+        };
+        let started = Instant::now();
+        let mut waited = false;
+        loop {
+            let wait = bucket.lock().unwrap().take(Instant::now());
+            match wait {
+                Some(wait) => {
+                    waited = true;
+                    tokio::time::sleep(wait).await;
+                }
+                None => break,
+            }
+        }
+        if waited {
+            let mut bucket = bucket.lock().unwrap();
+            bucket.stats.throttled += 1;
+            bucket.stats.wait_seconds += started.elapsed().as_secs_f64();
+        }
+    }
+
+    /// How often requests of `class` had to wait so far.
+    pub fn stats(&self, class: RateClass) -> ThrottleStats {
+        match &self.buckets[index(class)] {
+            Some(bucket) => bucket.lock().unwrap().stats,
+            None => ThrottleStats::default(),
+        }
+    }
+}
+
+/// Pauses, resumes or cancels a [`Bulk`] run from another task.
+#[derive(Clone)]
+pub struct BulkControl {
+    paused: Arc<watch::Sender<bool>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Default for BulkControl {
+    fn default() -> Self {
+        Self { paused: Arc::new(watch::channel(false).0), cancelled: Arc::new(AtomicBool::new(false)) }
+    }
+}
+
+impl BulkControl {
+    /// Stop before the next call until [`resume`](Self::resume) is called.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    /// Skip the remaining calls. Also ends a pause.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Wait while paused. Returns `false` once cancelled.
+    async fn proceed(&self) -> bool {
+        let mut paused = self.paused.subscribe();
+        // The sender lives in self, so this can't fail
+        let _ = paused.wait_for(|paused| !paused).await;
+        !self.is_cancelled()
+    }
+}
+
+/// How far a [`Bulk`] run has come.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Calls made so far, including failed ones.
+    pub done: usize,
+    pub total: usize,
+    pub failed: usize,
+}
+
+type ProgressCallback = Box<dyn Fn(&Progress) + Send + Sync>;
+
+/// Runs calls one after the other with progress reporting and pause/resume.
+///
+/// Requests are still subject to the rate limits of the connection they are made on.
+#[derive(Default)]
+pub struct Bulk {
+    control: BulkControl,
+    on_progress: Option<ProgressCallback>,
+}
+
+impl Bulk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `callback` after every call.
+    pub fn on_progress(mut self, callback: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// A handle to pause, resume or cancel this run.
+    pub fn control(&self) -> BulkControl {
+        self.control.clone()
+    }
+
+    /// Run `call` for every item, in order. Failures do not stop the remaining items; after
+    /// a cancel the results end at the last call that was made.
+    pub async fn run<'a, T, V, F, Fut>(&self, items: &'a [T], mut call: F) -> Vec<Result<V>>
+    where
+        F: FnMut(&'a T) -> Fut,
+        Fut: Future<Output = Result<V>>,
+    {
+        let mut progress = Progress { total: items.len(), ..Default::default() };
+        let mut results = Vec::with_capacity(items.len());
+        for item in items {
+            if !self.control.proceed().await {
+                break;
+            }
+            let result = call(item).await;
+            progress.done += 1;
+            if result.is_err() {
+                progress.failed += 1;
+            }
+            results.push(result);
+            if let Some(callback) = &self.on_progress {
+                callback(&progress);
+            }
+        }
+        results
+    }
+}
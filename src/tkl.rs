@@ -0,0 +1,257 @@
+//! Typed TKL objects as returned by the ban, exception and spamfilter handlers.
+
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// A TKL (server ban, ban exception, name ban or spamfilter) object.
+///
+/// Only `type` and `name` are always present; the other fields depend on the kind of TKL.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tkl {
+    #[serde(rename = "type")]
+    pub tkl_type: String,
+    #[serde(default)]
+    pub type_string: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub set_by: Option<String>,
+    #[serde(default)]
+    pub set_at: Option<String>,
+    #[serde(default)]
+    pub expire_at: Option<String>,
+    #[serde(default)]
+    pub set_at_string: Option<String>,
+    #[serde(default)]
+    pub expire_at_string: Option<String>,
+    #[serde(default)]
+    pub duration_string: Option<String>,
+    #[serde(default)]
+    pub set_at_delta: Option<i64>,
+    #[serde(default)]
+    pub set_in_config: Option<bool>,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub exception_types: Option<String>,
+    #[serde(default)]
+    pub match_type: Option<String>,
+    #[serde(default)]
+    pub ban_action: Option<String>,
+    #[serde(default)]
+    pub ban_duration_string: Option<String>,
+    #[serde(default)]
+    pub spamfilter_targets: Option<String>,
+    #[serde(default)]
+    pub hits: Option<i64>,
+    #[serde(default)]
+    pub hits_except: Option<i64>,
+}
+
+/// The four families of TKLs, each managed by its own handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TklKind {
+    ServerBan,
+    Exception,
+    NameBan,
+    Spamfilter,
+}
+
+impl TklKind {
+    /// The JSON-RPC method prefix of the handler, e.g. `server_ban`.
+    pub fn method_prefix(&self) -> &'static str {
+        match self {
+            TklKind::ServerBan => "server_ban",
+            TklKind::Exception => "server_ban_exception",
+            TklKind::NameBan => "name_ban",
+            TklKind::Spamfilter => "spamfilter",
+        }
+    }
+}
+
+impl Tkl {
+    /// The family this TKL belongs to, or `None` for unknown types.
+    pub fn kind(&self) -> Option<TklKind> {
+        if self.is_server_ban() {
+            Some(TklKind::ServerBan)
+        } else if self.is_exception() {
+            Some(TklKind::Exception)
+        } else if self.is_name_ban() {
+            Some(TklKind::NameBan)
+        } else if self.is_spamfilter() {
+            Some(TklKind::Spamfilter)
+        } else {
+            None
+        }
+    }
+
+    /// Whether this is a server ban (kline, gline, zline, gzline or shun).
+    pub fn is_server_ban(&self) -> bool {
+        matches!(self.tkl_type.as_str(), "kline" | "gline" | "zline" | "gzline" | "shun")
+    }
+
+    /// Whether this is a server ban exception.
+    pub fn is_exception(&self) -> bool {
+        self.tkl_type == "exception"
+    }
+
+    /// Whether this is a name ban (QLine).
+    pub fn is_name_ban(&self) -> bool {
+        self.tkl_type == "qline"
+    }
+
+    /// Whether this is a spamfilter.
+    pub fn is_spamfilter(&self) -> bool {
+        self.tkl_type == "spamfilter"
+    }
+
+    /// Build the JSON-RPC request that adds this TKL.
+    ///
+    /// `duration_string` is used as the duration (`0` for permanent), and `set_by` is passed
+    /// along when present. Returns `None` for unknown TKL types.
+    pub fn add_request(&self) -> Option<(String, serde_json::Value)> {
+        let kind = self.kind()?;
+        let duration = self.duration_string.as_deref().unwrap_or("0");
+        let reason = self.reason.as_deref().unwrap_or("");
+        let mut params = match kind {
+            TklKind::ServerBan => serde_json::json!({
+                "name": self.name,
+                "type": self.tkl_type,
+                "reason": reason,
+                "duration_string": duration
+            }),
+            TklKind::Exception => serde_json::json!({
+                "name": self.name,
+                "exception_types": self.exception_types.as_deref().unwrap_or(""),
+                "reason": reason,
+                "duration_string": duration
+            }),
+            TklKind::NameBan => serde_json::json!({
+                "name": self.name,
+                "reason": reason,
+                "duration_string": duration
+            }),
+            TklKind::Spamfilter => serde_json::json!({
+                "name": self.name,
+                "match_type": self.match_type.as_deref().unwrap_or("simple"),
+                "spamfilter_targets": self.spamfilter_targets.as_deref().unwrap_or(""),
+                "ban_action": self.ban_action.as_deref().unwrap_or(""),
+                "ban_duration": self.ban_duration_string.as_deref().unwrap_or("0"),
+                "reason": reason
+            }),
+        };
+        if let Some(sb) = &self.set_by {
+            params["set_by"] = sb.as_str().into();
+        }
+        Some((format!("{}.add", kind.method_prefix()), params))
+    }
+
+    /// Build the JSON-RPC request that deletes this TKL. Returns `None` for unknown TKL types.
+    pub fn del_request(&self) -> Option<(String, serde_json::Value)> {
+        let kind = self.kind()?;
+        let params = match kind {
+            TklKind::ServerBan => serde_json::json!({"name": self.name, "type": self.tkl_type}),
+            TklKind::Exception | TklKind::NameBan => serde_json::json!({"name": self.name}),
+            TklKind::Spamfilter => serde_json::json!({
+                "name": self.name,
+                "match_type": self.match_type.as_deref().unwrap_or("simple"),
+                "spamfilter_targets": self.spamfilter_targets.as_deref().unwrap_or(""),
+                "ban_action": self.ban_action.as_deref().unwrap_or("")
+            }),
+        };
+        Some((format!("{}.del", kind.method_prefix()), params))
+    }
+
+    /// The fields that identify this TKL on the server, e.g. `gline *@192.0.2.1`.
+    pub fn key(&self) -> String {
+        match self.kind() {
+            Some(TklKind::Spamfilter) => format!(
+                "spamfilter {} {} {} {}",
+                self.match_type.as_deref().unwrap_or(""),
+                self.spamfilter_targets.as_deref().unwrap_or(""),
+                self.ban_action.as_deref().unwrap_or(""),
+                self.name
+            ),
+            _ => format!("{} {}", self.tkl_type, self.name),
+        }
+    }
+
+    /// Whether this exception covers bans of type `ban_type` (e.g. `gline`).
+    pub fn excepts(&self, ban_type: &str) -> bool {
+        match (self.exception_types.as_deref(), exception_type_char(ban_type)) {
+            (Some(types), Some(c)) => types.contains(c),
+            _ => false,
+        }
+    }
+}
+
+/// The `exception_types` letter for a ban type, e.g. `G` for `gline`.
+pub fn exception_type_char(ban_type: &str) -> Option<char> {
+    match ban_type {
+        "kline" => Some('k'),
+        "gline" => Some('G'),
+        "zline" => Some('z'),
+        "gzline" => Some('Z'),
+        "shun" => Some('s'),
+        "qline" => Some('q'),
+        "spamfilter" => Some('F'),
+        _ => None,
+    }
+}
+
+/// Parse a duration such as `90`, `30m` or `1d12h` into seconds. `Some(None)` for a
+/// permanent one (`0` or `permanent`).
+pub(crate) fn parse_duration(s: &str) -> Option<Option<u64>> {
+    let s = s.trim();
+    if s == "0" || s.eq_ignore_ascii_case("permanent") {
+        return Some(None);
+    }
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let n: u64 = number.parse().ok()?;
+        number.clear();
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            'y' => 31536000,
+            _ => return None,
+        };
+        total = total.checked_add(n.checked_mul(unit)?)?;
+    }
+    if !number.is_empty() {
+        total = total.checked_add(number.parse().ok()?)?;
+    }
+    Some(if total == 0 { None } else { Some(total) })
+}
+
+/// The `duration_string` that re-adds a TKL with the time it has left, such as `3600s`,
+/// given its `expire_at`. `0` if it never expires, `None` if it has expired by now.
+pub fn remaining_duration(expire_at: Option<&str>) -> Result<Option<String>> {
+    let expire_at = match expire_at.map(str::trim) {
+        None | Some("" | "0") => return Ok(Some("0".to_string())),
+        Some(never) if never.eq_ignore_ascii_case("never") => return Ok(Some("0".to_string())),
+        Some(expire_at) => expire_at,
+    };
+    let expire_at = chrono::DateTime::parse_from_rfc3339(expire_at)
+        .map_err(|e| Error::Parse(format!("invalid expire_at '{}': {}", expire_at, e)))?;
+    let seconds = (expire_at.with_timezone(&chrono::Utc) - chrono::Utc::now()).num_seconds();
+    Ok((seconds > 0).then(|| format!("{}s", seconds)))
+}
+
+/// Fetch all server bans, exceptions, name bans and spamfilters.
+pub async fn fetch_all(connection: &Connection) -> Result<Vec<Tkl>> {
+    let mut tkls: Vec<Tkl> = serde_json::from_value(connection.server_ban().get_all().await?)?;
+    tkls.extend(serde_json::from_value::<Vec<Tkl>>(connection.server_ban_exception().get_all().await?)?);
+    tkls.extend(serde_json::from_value::<Vec<Tkl>>(connection.name_ban().get_all().await?)?);
+    tkls.extend(serde_json::from_value::<Vec<Tkl>>(connection.spamfilter().get_all().await?)?);
+    Ok(tkls)
+}
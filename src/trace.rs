@@ -0,0 +1,147 @@
+//! Instrumentation of RPC calls with [`tracing`](https://docs.rs/tracing).
+//!
+//! With the `tracing` feature, every [`Connection::query`](crate::Connection::query) runs in
+//! an `rpc.query` span at info level with these fields:
+//!
+//! - `method`
+//! - `request_id`: the JSON-RPC id of the request, or of the last attempt when it was retried
+//! - `duration_ms`
+//! - `outcome`: `ok`, `error` or `dry_run`
+//!
+//! Inside the span, the params and the result or error are logged as debug events. Params
+//! and results pass through [`redact`] first, and endpoints through [`redact_uri`]; the
+//! `Authorization` header is never logged. Connecting, failing over, reconnecting and
+//! closing are logged as info or warn events with the `endpoint` they concern.
+//!
+//! Without the feature, none of this is compiled in.
+
+use crate::error::Result;
+use crate::undo::{ALWAYS_PARAM, SET_PARAM};
+use serde_json::Value;
+use std::future::Future;
+
+/// Placeholder for redacted values.
+pub const REDACTED: &str = "[redacted]";
+
+/// Whether a parameter name looks like it holds a secret, such as `password`, `secret`,
+/// `token` or a channel `key`.
+pub fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name == "key" || ["pass", "secret", "token", "auth"].iter().any(|s| name.contains(s))
+}
+
+/// `parameters` of a `channel.set_mode` with the channel key replaced by [`REDACTED`].
+fn redact_key(modes: &str, parameters: &str) -> String {
+    let mut words = parameters.split_whitespace();
+    let mut redacted = Vec::new();
+    let mut adding = true;
+    for c in modes.chars() {
+        match c {
+            '+' => adding = true,
+            '-' => adding = false,
+            c if ALWAYS_PARAM.contains(c) || (adding && SET_PARAM.contains(c)) => match words.next() {
+                Some(_) if c == 'k' => redacted.push(REDACTED),
+                Some(word) => redacted.push(word),
+                None => break,
+            },
+            _ => {}
+        }
+    }
+    redacted.extend(words);
+    redacted.join(" ")
+}
+
+/// A copy of `value` with the values of secret-looking keys (see [`is_secret`]) and channel
+/// keys in `modes`/`parameters` pairs replaced by [`REDACTED`], at any depth.
+pub fn redact(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| {
+                    let v = match (map.get("modes").and_then(Value::as_str), v.as_str()) {
+                        _ if is_secret(k) && !v.is_null() => Value::from(REDACTED),
+                        (Some(modes), Some(parameters)) if k == "parameters" => Value::from(redact_key(modes, parameters)),
+                        _ => redact(v),
+                    };
+                    (k.clone(), v)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.iter().map(redact).collect()),
+        other => other.clone(),
+    }
+}
+
+/// A URI without the password of its user info, if any.
+pub fn redact_uri(uri: &str) -> String {
+    match url::Url::parse(uri) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some(REDACTED));
+            url.to_string()
+        }
+        _ => uri.to_string(),
+    }
+}
+
+/// Emit a `tracing` event when the `tracing` feature is enabled; expands to nothing otherwise.
+#[cfg(feature = "tracing")]
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        tracing::$level!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {};
+}
+
+pub(crate) use event;
+
+/// Run `call` with `params` inside an `rpc.query` span.
+#[cfg(feature = "tracing")]
+pub(crate) async fn traced<F, Fut>(method: &str, params: Value, dry_run: bool, call: F) -> Result<Value>
+where
+    F: FnOnce(Value) -> Fut,
+    Fut: Future<Output = Result<Value>>,
+{
+    use tracing::field::Empty;
+    use tracing::Instrument;
+
+    let span = tracing::info_span!("rpc.query", method, request_id = Empty, duration_ms = Empty, outcome = Empty);
+    let started = std::time::Instant::now();
+    async move {
+        tracing::debug!(params = %redact(&params), "request");
+        let result = call(params).await;
+        let span = tracing::Span::current();
+        span.record("duration_ms", started.elapsed().as_millis() as u64);
+        match &result {
+            Ok(reply) => {
+                span.record("outcome", if dry_run { "dry_run" } else { "ok" });
+                tracing::debug!(result = %redact(reply), "reply");
+            }
+            Err(e) => {
+                span.record("outcome", "error");
+                tracing::debug!(error = %e, "failed");
+            }
+        }
+        result
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) async fn traced<F, Fut>(_method: &str, params: Value, _dry_run: bool, call: F) -> Result<Value>
+where
+    F: FnOnce(Value) -> Fut,
+    Fut: Future<Output = Result<Value>>,
+{
+    call(params).await
+}
+
+/// Record the JSON-RPC id of the request being sent on the current span.
+pub(crate) fn record_request_id(_id: i64) {
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("request_id", _id);
+}
@@ -0,0 +1,362 @@
+//! Export and import of TKLs in several formats.
+//!
+//! Supported formats are JSON lines, CSV, and UnrealIRCd configuration blocks
+//! (`ban user {}`, `ban ip {}`, `ban nick {}`, `except ban {}` and `spamfilter {}`).
+//!
+//! JSON lines and CSV keep every field, including `set_by` and `expire_at`, so
+//! [`restore`] can re-add entries with their original setter and remaining time.
+//! Configuration blocks cannot carry those: they are imported as permanent entries,
+//! `ban user` becomes a `gline` and `ban ip` a `gzline`. Shuns have no configuration
+//! block and are exported as comments.
+
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+use crate::reconcile::{apply_change, Action, Change, ChangeResult};
+use crate::throttle::Bulk;
+use crate::tkl::{remaining_duration, Tkl};
+
+/// The format of exported TKLs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TklFormat {
+    JsonLines,
+    Csv,
+    Config,
+}
+
+/// Export TKLs in the given format.
+pub fn export(tkls: &[Tkl], format: TklFormat) -> Result<String> {
+    match format {
+        TklFormat::JsonLines => {
+            let mut out = String::new();
+            for tkl in tkls {
+                out.push_str(&serde_json::to_string(tkl)?);
+                out.push('\n');
+            }
+            Ok(out)
+        }
+        TklFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for tkl in tkls {
+                writer.serialize(tkl).map_err(|e| Error::Other(e.to_string()))?;
+            }
+            let bytes = writer.into_inner().map_err(|e| Error::Other(e.to_string()))?;
+            String::from_utf8(bytes).map_err(|e| Error::Other(e.to_string()))
+        }
+        TklFormat::Config => Ok(tkls.iter().map(config_block).collect()),
+    }
+}
+
+/// Import TKLs from the given format.
+pub fn import(input: &str, format: TklFormat) -> Result<Vec<Tkl>> {
+    match format {
+        TklFormat::JsonLines => input
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| Ok(serde_json::from_str(l)?))
+            .collect(),
+        TklFormat::Csv => csv::Reader::from_reader(input.as_bytes())
+            .deserialize()
+            .map(|r| r.map_err(|e| Error::Parse(e.to_string())))
+            .collect(),
+        TklFormat::Config => {
+            let blocks = config::parse(input)?;
+            Ok(blocks.iter().filter_map(block_to_tkl).collect())
+        }
+    }
+}
+
+/// Add TKLs to a server, keeping their `set_by` and remaining time.
+///
+/// Entries whose `expire_at` lies in the past are skipped, and entries whose `expire_at`
+/// can't be read fail without being sent. Failures do not stop the remaining entries.
+pub async fn restore(connection: &Connection, tkls: &[Tkl]) -> Vec<ChangeResult> {
+    restore_with(connection, tkls, &Bulk::new()).await
+}
+
+/// Like [`restore`], with progress reporting and pause/resume through `bulk`. The progress
+/// total does not include expired entries.
+pub async fn restore_with(connection: &Connection, tkls: &[Tkl], bulk: &Bulk) -> Vec<ChangeResult> {
+    let mut entries = Vec::new();
+    for tkl in tkls {
+        let mut tkl = tkl.clone();
+        let invalid = match remaining_duration(tkl.expire_at.as_deref()) {
+            Ok(None) => continue,
+            Ok(Some(duration)) => {
+                tkl.duration_string = Some(duration);
+                None
+            }
+            Err(Error::Parse(message)) => Some(message),
+            Err(e) => Some(e.to_string()),
+        };
+        entries.push((Change { action: Action::Add, tkl }, invalid));
+    }
+    let results = bulk
+        .run(&entries, |(change, invalid)| async move {
+            match invalid {
+                Some(e) => Err(Error::Parse(e.clone())),
+                None => apply_change(connection, change).await,
+            }
+        })
+        .await;
+    entries.into_iter().zip(results).map(|((change, _), result)| ChangeResult { change, result }).collect()
+}
+
+const EXCEPTION_TYPES: &[(char, &str)] = &[
+    ('k', "kline"),
+    ('G', "gline"),
+    ('z', "zline"),
+    ('Z', "gzline"),
+    ('s', "shun"),
+    ('q', "qline"),
+    ('F', "spamfilter"),
+    ('b', "blacklist"),
+    ('c', "connect-flood"),
+    ('d', "handshake-data-flood"),
+    ('m', "maxperip"),
+    ('r', "antirandom"),
+    ('8', "antimixedutf8"),
+    ('v', "ban-version"),
+];
+
+const SPAMFILTER_TARGETS: &[(char, &str)] = &[
+    ('c', "channel"),
+    ('p', "private"),
+    ('n', "private-notice"),
+    ('N', "channel-notice"),
+    ('P', "part"),
+    ('q', "quit"),
+    ('d', "dcc"),
+    ('a', "away"),
+    ('t', "topic"),
+    ('T', "message-tag"),
+    ('u', "user"),
+];
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn letters_to_names(letters: &str, table: &[(char, &'static str)]) -> Vec<&'static str> {
+    letters
+        .chars()
+        .filter_map(|c| table.iter().find(|(l, _)| *l == c).map(|(_, n)| *n))
+        .collect()
+}
+
+fn names_to_letters(names: &[String], table: &[(char, &'static str)]) -> String {
+    names
+        .iter()
+        .filter_map(|n| table.iter().find(|(_, name)| name == n).map(|(l, _)| *l))
+        .collect()
+}
+
+fn config_block(tkl: &Tkl) -> String {
+    let reason = quote(tkl.reason.as_deref().unwrap_or(""));
+    match tkl.tkl_type.as_str() {
+        "kline" | "gline" => format!("ban user {{\n\tmask {};\n\treason {};\n}}\n\n", quote(&tkl.name), reason),
+        "zline" | "gzline" => format!("ban ip {{\n\tmask {};\n\treason {};\n}}\n\n", quote(&tkl.name), reason),
+        "qline" => format!("ban nick {{\n\tmask {};\n\treason {};\n}}\n\n", quote(&tkl.name), reason),
+        "exception" => {
+            let types = letters_to_names(tkl.exception_types.as_deref().unwrap_or(""), EXCEPTION_TYPES);
+            format!(
+                "except ban {{\n\tmask {};\n\ttype {{ {} }};\n}}\n\n",
+                quote(&tkl.name),
+                types.iter().map(|t| format!("{};", t)).collect::<Vec<_>>().join(" ")
+            )
+        }
+        "spamfilter" => {
+            let targets = letters_to_names(tkl.spamfilter_targets.as_deref().unwrap_or(""), SPAMFILTER_TARGETS);
+            let mut block = format!(
+                "spamfilter {{\n\tmatch-type {};\n\tmatch {};\n\ttarget {{ {} }};\n\taction {};\n\treason {};\n",
+                tkl.match_type.as_deref().unwrap_or("simple"),
+                quote(&tkl.name),
+                targets.iter().map(|t| format!("{};", t)).collect::<Vec<_>>().join(" "),
+                tkl.ban_action.as_deref().unwrap_or("block"),
+                reason
+            );
+            if let Some(d) = tkl.ban_duration_string.as_deref().filter(|d| *d != "0") {
+                block.push_str(&format!("\tban-time {};\n", d));
+            }
+            block.push_str("}\n\n");
+            block
+        }
+        other => format!("/* {} {} cannot be expressed as a configuration block */\n\n", other, tkl.name),
+    }
+}
+
+fn block_to_tkl(block: &config::Entry) -> Option<Tkl> {
+    let mask = || block.child_value("mask").map(str::to_string);
+    let reason = block.child_value("reason").map(str::to_string);
+    let mut tkl = match (block.name.as_str(), block.value.as_deref()) {
+        ("ban", Some("user")) => Tkl { tkl_type: "gline".to_string(), name: mask()?, ..Default::default() },
+        ("ban", Some("ip")) => Tkl { tkl_type: "gzline".to_string(), name: mask()?, ..Default::default() },
+        ("ban", Some("nick")) => Tkl { tkl_type: "qline".to_string(), name: mask()?, ..Default::default() },
+        ("ban", Some("realname")) => Tkl {
+            tkl_type: "gline".to_string(),
+            name: format!("~realname:{}", mask()?),
+            ..Default::default()
+        },
+        ("except", Some("ban")) => Tkl {
+            tkl_type: "exception".to_string(),
+            name: mask()?,
+            exception_types: Some(match block.child("type") {
+                Some(types) => names_to_letters(&types.child_names(), EXCEPTION_TYPES),
+                // Without a type block, UnrealIRCd excepts from K/G/Z-lines and shuns
+                None => "kGzZs".to_string(),
+            }),
+            ..Default::default()
+        },
+        ("spamfilter", _) => Tkl {
+            tkl_type: "spamfilter".to_string(),
+            name: block.child_value("match")?.to_string(),
+            match_type: Some(block.child_value("match-type").unwrap_or("simple").to_string()),
+            spamfilter_targets: Some(match block.child("target") {
+                Some(t) if t.children.is_empty() => names_to_letters(&[t.value.clone()?], SPAMFILTER_TARGETS),
+                Some(t) => names_to_letters(&t.child_names(), SPAMFILTER_TARGETS),
+                None => String::new(),
+            }),
+            ban_action: Some(block.child_value("action")?.to_string()),
+            ban_duration_string: block.child_value("ban-time").map(str::to_string),
+            ..Default::default()
+        },
+        _ => return None,
+    };
+    tkl.reason = reason;
+    Some(tkl)
+}
+
+/// A minimal parser for the UnrealIRCd configuration file syntax.
+mod config {
+    use crate::error::{Error, Result};
+
+    /// A `name [value] [{ children }];` entry.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Entry {
+        pub name: String,
+        pub value: Option<String>,
+        pub children: Vec<Entry>,
+    }
+
+    impl Entry {
+        pub fn child(&self, name: &str) -> Option<&Entry> {
+            self.children.iter().find(|c| c.name == name)
+        }
+
+        pub fn child_value(&self, name: &str) -> Option<&str> {
+            self.child(name).and_then(|c| c.value.as_deref())
+        }
+
+        pub fn child_names(&self) -> Vec<String> {
+            self.children.iter().map(|c| c.name.clone()).collect()
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Token {
+        Word(String),
+        Open,
+        Close,
+        Semicolon,
+    }
+
+    fn tokenize(input: &str) -> Result<Vec<Token>> {
+        let mut tokens = Vec::new();
+        let mut chars = input.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            match c {
+                c if c.is_whitespace() => {
+                    chars.next();
+                }
+                '#' => {
+                    chars.by_ref().take_while(|&c| c != '\n').for_each(drop);
+                }
+                '/' => {
+                    chars.next();
+                    match chars.next() {
+                        Some('/') => chars.by_ref().take_while(|&c| c != '\n').for_each(drop),
+                        Some('*') => {
+                            let mut prev = ' ';
+                            for c in chars.by_ref() {
+                                if prev == '*' && c == '/' {
+                                    break;
+                                }
+                                prev = c;
+                            }
+                        }
+                        _ => return Err(Error::Parse("unexpected '/' in configuration".to_string())),
+                    }
+                }
+                '{' | '}' | ';' => {
+                    chars.next();
+                    tokens.push(match c {
+                        '{' => Token::Open,
+                        '}' => Token::Close,
+                        _ => Token::Semicolon,
+                    });
+                }
+                '"' => {
+                    chars.next();
+                    let mut word = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('\\') => word.extend(chars.next()),
+                            Some('"') => break,
+                            Some(c) => word.push(c),
+                            None => return Err(Error::Parse("unterminated string in configuration".to_string())),
+                        }
+                    }
+                    tokens.push(Token::Word(word));
+                }
+                _ => {
+                    let mut word = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if c.is_whitespace() || matches!(c, '{' | '}' | ';' | '"') {
+                            break;
+                        }
+                        word.push(c);
+                        chars.next();
+                    }
+                    tokens.push(Token::Word(word));
+                }
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn parse_entries(tokens: &[Token], pos: &mut usize, nested: bool) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        while *pos < tokens.len() {
+            match &tokens[*pos] {
+                Token::Close if nested => {
+                    *pos += 1;
+                    return Ok(entries);
+                }
+                Token::Semicolon => *pos += 1,
+                Token::Word(name) => {
+                    *pos += 1;
+                    let mut entry = Entry { name: name.clone(), value: None, children: Vec::new() };
+                    if let Some(Token::Word(value)) = tokens.get(*pos) {
+                        entry.value = Some(value.clone());
+                        *pos += 1;
+                    }
+                    if tokens.get(*pos) == Some(&Token::Open) {
+                        *pos += 1;
+                        entry.children = parse_entries(tokens, pos, true)?;
+                    }
+                    entries.push(entry);
+                }
+                token => return Err(Error::Parse(format!("unexpected {:?} in configuration", token))),
+            }
+        }
+        if nested {
+            return Err(Error::Parse("missing '}' in configuration".to_string()));
+        }
+        Ok(entries)
+    }
+
+    /// Parse a configuration file into its top-level entries.
+    pub fn parse(input: &str) -> Result<Vec<Entry>> {
+        let tokens = tokenize(input)?;
+        parse_entries(&tokens, &mut 0, false)
+    }
+}
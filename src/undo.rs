@@ -0,0 +1,422 @@
+//! Undoing changes made through a connection.
+//!
+//! With an [`UndoLog`] set on a connection (see
+//! [`Connection::set_undo_log`](crate::Connection::set_undo_log)), every successful call of a
+//! reversible method is recorded as an [`Operation`] together with the call that reverses
+//! it. Where the inverse depends on the state before the call, such as the previous vhost
+//! of a user or the previous topic of a channel, that state is fetched first.
+//!
+//! | Method | Inverse |
+//! |--------|---------|
+//! | `server_ban.add`, `server_ban_exception.add`, `name_ban.add`, `spamfilter.add` | The matching `.del` |
+//! | `server_ban.del`, `server_ban_exception.del`, `name_ban.del`, `spamfilter.del` | `.add` with the removed entry, for the time it had left |
+//! | `channel.set_mode` | The modes that were changed, set back to what they were |
+//! | `channel.set_topic` | The previous topic, with its setter and time |
+//! | `user.set_vhost` | The previous vhost, or `-t` if there was none |
+//! | `rpc.add_timer` | `rpc.del_timer`, which stops it from running again |
+//!
+//! Other methods are not recorded. Undoing goes through the same connection, in dry-run
+//! mode as well, but is not itself recorded.
+//!
+//! ```rust,no_run
+//! # use unrealircd_rpc::Connection;
+//! use std::sync::Arc;
+//! use unrealircd_rpc::undo::UndoLog;
+//!
+//! # async fn example(conn: Connection) -> unrealircd_rpc::error::Result<()> {
+//! let undo = Arc::new(UndoLog::new());
+//! conn.set_undo_log(Some(undo.clone()));
+//!
+//! for ip in ["203.0.113.7", "203.0.113.8", "203.0.113.9"] {
+//!     conn.server_ban().add(&format!("*@{}", ip), "gline", "1d", "Botnet").await?;
+//! }
+//!
+//! // That was the wrong range
+//! for (id, result) in undo.undo_last(&conn, 3).await {
+//!     if let Err(e) = result {
+//!         eprintln!("could not undo operation {}: {}", id, e);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// Number of operations an [`UndoLog`] keeps by default.
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+/// A JSON-RPC call.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Call {
+    pub method: String,
+    pub params: Value,
+}
+
+impl Call {
+    fn new(method: &str, params: Value) -> Self {
+        Self { method: method.to_string(), params }
+    }
+}
+
+/// A recorded call.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Operation {
+    /// Identifier for [`UndoLog::undo`], increasing from 1.
+    pub id: u64,
+    /// When the reply arrived, in RFC 3339 format.
+    pub timestamp: String,
+    pub call: Call,
+    /// The call that reverses it, or `None` if the call changed nothing that can be restored
+    /// (for instance a mode that was already set).
+    pub inverse: Option<Call>,
+    pub undone: bool,
+}
+
+/// Whether `method` is recorded by an [`UndoLog`].
+pub fn is_reversible(method: &str) -> bool {
+    matches!(
+        method,
+        "server_ban.add"
+            | "server_ban.del"
+            | "server_ban_exception.add"
+            | "server_ban_exception.del"
+            | "name_ban.add"
+            | "name_ban.del"
+            | "spamfilter.add"
+            | "spamfilter.del"
+            | "channel.set_mode"
+            | "channel.set_topic"
+            | "user.set_vhost"
+            | "rpc.add_timer"
+    )
+}
+
+/// The request that fetches the state a call of `method` overwrites, if the inverse needs it.
+pub(crate) fn prior_request(method: &str, params: &Value) -> Option<(&'static str, Value)> {
+    match method {
+        "channel.set_mode" | "channel.set_topic" => {
+            let channel = params.get("channel")?;
+            Some(("channel.get", json!({"channel": channel, "object_detail_level": 3})))
+        }
+        "user.set_vhost" => {
+            let nick = params.get("nick")?;
+            Some(("user.get", json!({"nick": nick, "object_detail_level": 2})))
+        }
+        _ => None,
+    }
+}
+
+/// Copy the named parameters that are present.
+fn pick(params: &Value, names: &[&str]) -> Value {
+    let mut out = Map::new();
+    for name in names {
+        if let Some(value) = params.get(*name) {
+            out.insert(name.to_string(), value.clone());
+        }
+    }
+    Value::Object(out)
+}
+
+/// The `.add` call that restores the TKL in the reply of a `.del`.
+fn readd(prefix: &str, reply: &Value) -> Option<Call> {
+    let tkl = reply.get("tkl")?;
+    let mut params = match prefix {
+        "server_ban" => pick(tkl, &["name", "type", "reason", "set_by"]),
+        "server_ban_exception" => pick(tkl, &["name", "exception_types", "reason", "set_by"]),
+        "name_ban" => pick(tkl, &["name", "reason", "set_by"]),
+        _ => {
+            let mut params = pick(tkl, &["name", "match_type", "spamfilter_targets", "ban_action", "reason"]);
+            params["ban_duration"] = tkl.get("ban_duration_string").cloned().unwrap_or_else(|| "0".into());
+            return Some(Call::new("spamfilter.add", params));
+        }
+    };
+    let expire_at = tkl.get("expire_at").and_then(Value::as_str);
+    params["duration_string"] = crate::tkl::remaining_duration(expire_at).ok()??.into();
+    Some(Call::new(&format!("{}.add", prefix), params))
+}
+
+/// Modes that take a parameter when set and when unset.
+pub(crate) const ALWAYS_PARAM: &str = "beIqaohvk";
+/// Modes that take a parameter only when set.
+pub(crate) const SET_PARAM: &str = "lLfFHj";
+
+/// The flags and parameters of a channel's `modes` string, e.g. `ntk secret`.
+fn channel_flags(channel: &Value) -> Vec<(char, Option<String>)> {
+    let modes = channel.get("modes").and_then(Value::as_str).unwrap_or("");
+    let mut words = modes.split_whitespace();
+    let flags = words.next().unwrap_or("").trim_start_matches('+');
+    flags
+        .chars()
+        .map(|c| {
+            let param = (c == 'k' || SET_PARAM.contains(c)).then(|| words.next().map(str::to_string)).flatten();
+            (c, param)
+        })
+        .collect()
+}
+
+/// Whether `mask` is on a list mode of `channel`.
+fn on_list(channel: &Value, mode: char, mask: &str) -> bool {
+    let list = match mode {
+        'b' => "bans",
+        'e' => "ban_exemptions",
+        _ => "invite_exceptions",
+    };
+    let entries = channel.get(list).and_then(Value::as_array);
+    entries.is_some_and(|entries| entries.iter().any(|e| e["name"].as_str() == Some(mask)))
+}
+
+/// Whether `nick` has member mode `mode` on `channel`.
+fn has_level(channel: &Value, mode: char, nick: &str) -> Option<bool> {
+    let members = channel.get("members")?.as_array()?;
+    let member = members.iter().find(|m| m["name"].as_str().is_some_and(|n| n.eq_ignore_ascii_case(nick)))?;
+    Some(member["level"].as_str().unwrap_or("").contains(mode))
+}
+
+/// The `channel.set_mode` call that reverts `modes`/`parameters`, given the channel as it was
+/// before. Without it, every change is simply flipped.
+fn invert_channel_modes(params: &Value, prior: Option<&Value>) -> Option<Call> {
+    let modes = params.get("modes").and_then(Value::as_str)?;
+    let parameters = params.get("parameters").and_then(Value::as_str).unwrap_or("");
+    let prior = prior.and_then(|p| p.get("channel"));
+    let flags = prior.map(channel_flags);
+    let had = |c: char| flags.as_ref().map(|flags| flags.iter().find(|(f, _)| *f == c).map(|(_, p)| p.clone()));
+
+    let mut words = parameters.split_whitespace();
+    let mut changes: Vec<(bool, char, Option<String>)> = Vec::new();
+    let mut adding = true;
+    for c in modes.chars() {
+        match c {
+            '+' => adding = true,
+            '-' => adding = false,
+            'b' | 'e' | 'I' => {
+                let mask = words.next()?.to_string();
+                if prior.is_none_or(|channel| on_list(channel, c, &mask) != adding) {
+                    changes.push((!adding, c, Some(mask)));
+                }
+            }
+            'q' | 'a' | 'o' | 'h' | 'v' => {
+                let nick = words.next()?.to_string();
+                if prior.and_then(|channel| has_level(channel, c, &nick)) != Some(adding) {
+                    changes.push((!adding, c, Some(nick)));
+                }
+            }
+            c if c == 'k' || SET_PARAM.contains(c) => {
+                let param = if adding || ALWAYS_PARAM.contains(c) { words.next().map(str::to_string) } else { None };
+                match had(c) {
+                    // Set before: put the old value back
+                    Some(Some(old)) => changes.push((true, c, old.or(param))),
+                    // Not set before: remove it again
+                    Some(None) if adding => changes.push((false, c, param.filter(|_| c == 'k'))),
+                    Some(None) => {}
+                    None => changes.push((!adding, c, param)),
+                }
+            }
+            c => {
+                if had(c).is_none_or(|before| before.is_some() != adding) {
+                    changes.push((!adding, c, None));
+                }
+            }
+        }
+    }
+    if changes.is_empty() {
+        return None;
+    }
+
+    let mut modes = String::new();
+    let mut sign = None;
+    let mut parameters = Vec::new();
+    for (set, c, param) in changes {
+        if sign != Some(set) {
+            modes.push(if set { '+' } else { '-' });
+            sign = Some(set);
+        }
+        modes.push(c);
+        parameters.extend(param);
+    }
+    let mut inverse = json!({"channel": params["channel"], "modes": modes});
+    if !parameters.is_empty() {
+        inverse["parameters"] = parameters.join(" ").into();
+    }
+    Some(Call::new("channel.set_mode", inverse))
+}
+
+/// The call that reverses `method`/`params`, given its reply and the state fetched with
+/// [`prior_request`].
+fn inverse(method: &str, params: &Value, prior: Option<&Value>, reply: &Value) -> Option<Call> {
+    match method {
+        "server_ban.add" => Some(Call::new("server_ban.del", pick(params, &["name", "type"]))),
+        "server_ban_exception.add" => Some(Call::new("server_ban_exception.del", pick(params, &["name"]))),
+        "name_ban.add" => Some(Call::new("name_ban.del", pick(params, &["name"]))),
+        "spamfilter.add" => Some(Call::new(
+            "spamfilter.del",
+            pick(params, &["name", "match_type", "spamfilter_targets", "ban_action"]),
+        )),
+        "rpc.add_timer" => Some(Call::new("rpc.del_timer", pick(params, &["timer_id"]))),
+        "server_ban.del" | "server_ban_exception.del" | "name_ban.del" | "spamfilter.del" => {
+            readd(method.trim_end_matches(".del"), reply)
+        }
+        "channel.set_mode" => invert_channel_modes(params, prior),
+        "channel.set_topic" => {
+            let channel = prior?.get("channel")?;
+            let mut restore = json!({
+                "channel": params["channel"],
+                "topic": channel.get("topic").and_then(Value::as_str).unwrap_or(""),
+            });
+            if let Some(set_by) = channel.get("topic_set_by") {
+                restore["set_by"] = set_by.clone();
+            }
+            if let Some(set_at) = channel.get("topic_set_at") {
+                restore["set_at"] = set_at.clone();
+            }
+            Some(Call::new("channel.set_topic", restore))
+        }
+        "user.set_vhost" => {
+            let client = prior?.get("client")?;
+            match client["user"].get("vhost").and_then(Value::as_str) {
+                Some(vhost) => Some(Call::new("user.set_vhost", json!({"nick": params["nick"], "vhost": vhost}))),
+                None => Some(Call::new(
+                    "user.set_mode",
+                    json!({"nick": params["nick"], "modes": "-t", "hidden": false}),
+                )),
+            }
+        }
+        _ => None,
+    }
+}
+
+struct Entries {
+    next_id: u64,
+    operations: VecDeque<Operation>,
+    /// Operations whose inverse is being sent.
+    undoing: HashSet<u64>,
+}
+
+/// Clears the in-progress mark of an operation when its undo ends, however it ends.
+struct Undoing<'a> {
+    log: &'a UndoLog,
+    id: u64,
+}
+
+impl Drop for Undoing<'_> {
+    fn drop(&mut self) {
+        self.log.entries.lock().unwrap().undoing.remove(&self.id);
+    }
+}
+
+/// The operations made through a connection, newest last, with their inverses.
+pub struct UndoLog {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl Default for UndoLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UndoLog {
+    /// Create a log keeping the last [`DEFAULT_CAPACITY`] operations.
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Create a log keeping the last `capacity` operations.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(Entries { next_id: 1, operations: VecDeque::new(), undoing: HashSet::new() }),
+        }
+    }
+
+    pub(crate) fn record(&self, method: &str, params: Value, prior: Option<&Value>, reply: &Value) -> u64 {
+        let inverse = inverse(method, &params, prior, reply);
+        let mut entries = self.entries.lock().unwrap();
+        let id = entries.next_id;
+        entries.next_id += 1;
+        entries.operations.push_back(Operation {
+            id,
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            call: Call::new(method, params),
+            inverse,
+            undone: false,
+        });
+        while entries.operations.len() > self.capacity {
+            entries.operations.pop_front();
+        }
+        id
+    }
+
+    /// All operations still in the log, oldest first.
+    pub fn operations(&self) -> Vec<Operation> {
+        self.entries.lock().unwrap().operations.iter().cloned().collect()
+    }
+
+    /// An operation by id.
+    pub fn get(&self, id: u64) -> Option<Operation> {
+        self.entries.lock().unwrap().operations.iter().find(|op| op.id == id).cloned()
+    }
+
+    /// The id of the most recent operation.
+    pub fn last_id(&self) -> Option<u64> {
+        self.entries.lock().unwrap().operations.back().map(|op| op.id)
+    }
+
+    /// Send the inverse of an operation on `connection`. Returns the reply, or `Null` if
+    /// there was nothing to revert. An operation can only be undone once.
+    pub async fn undo(&self, connection: &Connection, id: u64) -> Result<Value> {
+        let inverse = {
+            let mut entries = self.entries.lock().unwrap();
+            let op = entries
+                .operations
+                .iter()
+                .find(|op| op.id == id)
+                .ok_or_else(|| Error::Other(format!("No operation {} in the undo log", id)))?;
+            if op.undone {
+                return Err(Error::Other(format!("Operation {} was already undone", id)));
+            }
+            let inverse = op.inverse.clone();
+            if !entries.undoing.insert(id) {
+                return Err(Error::Other(format!("Operation {} is being undone", id)));
+            }
+            inverse
+        };
+        let _undoing = Undoing { log: self, id };
+        let reply = match inverse {
+            Some(call) => connection.query_unrecorded(&call.method, call.params).await?,
+            None => Value::Null,
+        };
+        if !connection.context().is_dry_run() {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(op) = entries.operations.iter_mut().find(|op| op.id == id) {
+                op.undone = true;
+            }
+        }
+        Ok(reply)
+    }
+
+    /// Undo the last `n` operations that were not undone yet, newest first. Stops at the
+    /// first failure, which is included in the results.
+    pub async fn undo_last(&self, connection: &Connection, n: usize) -> Vec<(u64, Result<Value>)> {
+        let ids: Vec<u64> = {
+            let entries = self.entries.lock().unwrap();
+            entries.operations.iter().rev().filter(|op| !op.undone && !entries.undoing.contains(&op.id)).take(n).map(|op| op.id).collect()
+        };
+        let mut results = Vec::new();
+        for id in ids {
+            let result = self.undo(connection, id).await;
+            let failed = result.is_err();
+            results.push((id, result));
+            if failed {
+                break;
+            }
+        }
+        results
+    }
+}
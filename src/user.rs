@@ -1,5 +1,6 @@
 //! User operations module.
 
+use crate::client::Client;
 use crate::connection::Connection;
 use crate::error::Result;
 use serde_json;
@@ -29,6 +30,12 @@ impl User {
         }
     }
 
+    /// Get a list of all users as typed client objects.
+    pub async fn get_all_clients(&self, object_detail_level: i32) -> Result<Vec<Client>> {
+        let list = self.get_all(object_detail_level).await?;
+        Ok(serde_json::from_value(list)?)
+    }
+
     /// Get a user object.
     pub async fn get(&self, nick: &str, object_detail_level: i32) -> Result<Option<serde_json::Value>> {
         let result = self.connection.query("user.get", serde_json::json!({